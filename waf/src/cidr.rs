@@ -0,0 +1,57 @@
+#![allow(non_snake_case)]
+
+use std::net::IpAddr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpCidr {
+    pub Network: IpAddr,
+    pub PrefixLen: u8,
+}
+
+impl IpCidr {
+    // Accepts "10.0.0.0/8", "2001:db8::/32" or a bare address, which covers just that host
+    pub fn parse(Value: &str) -> Option<Self> {
+        let Value = Value.trim();
+        let (Address, PrefixLen) = match Value.split_once('/') {
+            Some((Address, PrefixLen)) => (Address, Some(PrefixLen.parse::<u8>().ok()?)),
+            None => (Value, None),
+        };
+
+        let Network: IpAddr = Address.trim_start_matches('[').trim_end_matches(']').parse().ok()?;
+        let MaxLen = if Network.is_ipv4() { 32 } else { 128 };
+        let PrefixLen = PrefixLen.unwrap_or(MaxLen);
+
+        if PrefixLen > MaxLen {
+            return None;
+        }
+
+        Some(Self { Network, PrefixLen })
+    }
+
+    pub fn contains(&self, Address: IpAddr) -> bool {
+        match (self.Network, Address.to_canonical()) {
+            (IpAddr::V4(Network), IpAddr::V4(Address)) => {
+                prefix_matches(u32::from(Network) as u128, u32::from(Address) as u128, self.PrefixLen, 32)
+            }
+            (IpAddr::V6(Network), IpAddr::V6(Address)) => {
+                prefix_matches(u128::from(Network), u128::from(Address), self.PrefixLen, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(Network: u128, Address: u128, PrefixLen: u8, Bits: u8) -> bool {
+    if PrefixLen == 0 {
+        return true;
+    }
+
+    let Shift = (Bits - PrefixLen) as u32;
+    (Network >> Shift) == (Address >> Shift)
+}
+
+pub fn parse_cidr_list(Values: &[String]) -> Result<Vec<IpCidr>, String> {
+    Values.iter()
+        .map(|Value| IpCidr::parse(Value).ok_or_else(|| format!("invalid address range '{}'", Value)))
+        .collect()
+}
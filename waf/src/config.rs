@@ -0,0 +1,57 @@
+#![allow(non_snake_case)]
+
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::sync::{PoisonError, RwLock};
+use once_cell::sync::Lazy;
+
+// Values set at runtime (admin API) shadow the process environment for everything read afterwards
+static _OVERRIDES: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn env_value(Key: &str) -> Option<String> {
+    let Overrides = _OVERRIDES.read().unwrap_or_else(PoisonError::into_inner);
+    match Overrides.get(Key) {
+        Some(Value) => Some(Value.clone()),
+        None => env::var(Key).ok(),
+    }
+}
+
+// Returns the previous override so a failed reconfiguration can put it back
+pub fn set_override(Key: &str, Value: Option<String>) -> Option<String> {
+    let mut Overrides = _OVERRIDES.write().unwrap_or_else(PoisonError::into_inner);
+    match Value {
+        Some(Value) => Overrides.insert(Key.to_string(), Value),
+        None => Overrides.remove(Key),
+    }
+}
+
+pub fn env_or<T: FromStr>(Key: &str, Default: T) -> T {
+    env_value(Key)
+        .and_then(|Value| Value.trim().parse().ok())
+        .unwrap_or(Default)
+}
+
+pub fn env_flag(Key: &str, Default: bool) -> bool {
+    match env_value(Key) {
+        Some(Value) => matches!(Value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+        None => Default,
+    }
+}
+
+pub fn env_list(Key: &str) -> Vec<String> {
+    env_value(Key)
+        .map(|Value| {
+            Value.split(',')
+                .map(|Item| Item.trim().to_string())
+                .filter(|Item| !Item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn env_opt(Key: &str) -> Option<String> {
+    env_value(Key)
+        .map(|Value| Value.trim().to_string())
+        .filter(|Value| !Value.is_empty())
+}
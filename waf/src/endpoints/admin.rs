@@ -0,0 +1,182 @@
+#![allow(non_snake_case)]
+
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::config::env_opt;
+use crate::module::{get_module_status, is_module_registered, move_module, reload_module, set_module_enabled, set_module_priority};
+use crate::modules::redaction::Keyring;
+use crate::proxy::body::{full_body, ResponseBody};
+use crate::shutdown;
+
+const MAX_ADMIN_BODY: usize = 16 * 1024;
+
+pub struct AdminSettings {
+    pub Address: SocketAddr,
+    Token: Option<String>,
+}
+
+impl AdminSettings {
+    // ADMIN_LISTEN=127.0.0.1:2027 turns the admin API on, ADMIN_TOKEN makes it require
+    // "Authorization: Bearer <token>". A non-loopback address without a token is refused.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Address = match env_opt("ADMIN_LISTEN") {
+            Some(Address) => Address,
+            None => return Ok(None),
+        };
+        let Address: SocketAddr = Address.parse()
+            .map_err(|_| format!("ADMIN_LISTEN '{}' is not an address:port", Address))?;
+
+        let Token = env_opt("ADMIN_TOKEN");
+        if Token.is_none() && !Address.ip().is_loopback() {
+            return Err(format!("ADMIN_LISTEN {} is reachable from other hosts, set ADMIN_TOKEN", Address));
+        }
+
+        Ok(Some(Self { Address, Token }))
+    }
+
+    fn authorized<B>(&self, Request: &Request<B>) -> bool {
+        let Token = match self.Token.as_ref() {
+            Some(Token) => Token,
+            None => return true,
+        };
+
+        Request.headers().get(AUTHORIZATION)
+            .and_then(|Value| Value.to_str().ok())
+            .and_then(|Value| Value.strip_prefix("Bearer "))
+            .map(|Given| constant_time_eq(Given.trim().as_bytes(), Token.as_bytes()))
+            .unwrap_or(false)
+    }
+}
+
+fn constant_time_eq(Left: &[u8], Right: &[u8]) -> bool {
+    Left.len() == Right.len() && Left.iter().zip(Right.iter()).fold(0u8, |Acc, (A, B)| Acc | (A ^ B)) == 0
+}
+
+pub async fn serve(Listener: TcpListener, Settings: Arc<AdminSettings>) {
+    loop {
+        let (Stream, _) = tokio::select! {
+            Accepted = Listener.accept() => match Accepted {
+                Ok(Connection) => Connection,
+                Err(_e) => continue,
+            },
+            _ = shutdown::shutdown_requested() => return,
+        };
+
+        let Settings = Settings.clone();
+        tokio::spawn(async move {
+            let Service = service_fn(move |Request| {
+                let Settings = Settings.clone();
+                async move { Ok::<_, Infallible>(handle(Request, &Settings).await) }
+            });
+            let _ = http1::Builder::new().serve_connection(TokioIo::new(Stream), Service).await;
+        });
+    }
+}
+
+fn reply(Status: StatusCode, Message: String) -> Response<ResponseBody> {
+    let mut _Response = Response::new(full_body(Message));
+    *_Response.status_mut() = Status;
+    _Response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    _Response
+}
+
+fn list_modules() -> String {
+    get_module_status().iter()
+        .map(|Status| format!(
+            "{} {} priority={} {} on_failure={}\n",
+            Status.Name,
+            Status.Version,
+            Status.Priority,
+            if Status.Enabled { "enabled" } else { "disabled" },
+            Status.Policy,
+        ))
+        .collect()
+}
+
+// What the keyed redaction strategies turn an address into under each key the detectors hold, signing key
+// first, so a pseudonym from before a key rotation can still be traced to today's
+fn lookup_pseudonyms(Address: &str) -> Response<ResponseBody> {
+    let Address: IpAddr = match Address.parse() {
+        Ok(Address) => Address,
+        Err(_) => return reply(StatusCode::BAD_REQUEST, format!("'{}' is not an IP address\n", Address)),
+    };
+    let Keyring = Keyring::shared();
+    if Keyring.keys().is_empty() {
+        return reply(StatusCode::NOT_FOUND, String::from("no redaction keys in use, the detectors mask or truncate\n"));
+    }
+
+    let Lines: String = Keyring.keys().iter()
+        .map(|Key| format!("{} {} {}\n", Key.Id, Key.pseudonym(Address), Key.fake_address(Address)))
+        .collect();
+    reply(StatusCode::OK, Lines)
+}
+
+// KEY=VALUE per line, blank lines and # comments are skipped
+fn parse_settings(Body: &str) -> Result<Vec<(String, String)>, String> {
+    Body.lines()
+        .map(|Line| Line.trim())
+        .filter(|Line| !Line.is_empty() && !Line.starts_with('#'))
+        .map(|Line| match Line.split_once('=') {
+            Some((Key, Value)) if !Key.trim().is_empty() => Ok((Key.trim().to_string(), Value.trim().to_string())),
+            _ => Err(format!("expected KEY=VALUE, got '{}'", Line)),
+        })
+        .collect()
+}
+
+// GET  /modules                          one line per module in execution order
+// POST /modules/<name>/enable|disable
+// POST /modules/<name>/earlier|later     swap places with the neighbour
+// PUT  /modules/<name>/priority          body: the new priority
+// POST /modules/<name>/reload            body: optional KEY=VALUE settings of the module applied before rebuilding
+// GET  /redaction/pseudonyms/<address>  "<key id> <pseudonym> <fake address>" per redaction key
+// Changes answer with the resulting module list; requests already in flight keep their pipeline.
+async fn handle(Request: Request<Incoming>, Settings: &AdminSettings) -> Response<ResponseBody> {
+    if !Settings.authorized(&Request) {
+        return reply(StatusCode::UNAUTHORIZED, String::from("missing or wrong admin token\n"));
+    }
+
+    let Method = Request.method().clone();
+    let Path = Request.uri().path().trim_matches('/').to_string();
+    let Segments: Vec<&str> = Path.split('/').collect();
+
+    let Body = match Limited::new(Request.into_body(), MAX_ADMIN_BODY).collect().await {
+        Ok(Collected) => String::from_utf8_lossy(&Collected.to_bytes()).into_owned(),
+        Err(e) => return reply(StatusCode::BAD_REQUEST, format!("request body error: {}\n", e)),
+    };
+
+    if let ["modules", Name, ..] = Segments.as_slice() {
+        if !is_module_registered(Name) {
+            return reply(StatusCode::NOT_FOUND, format!("unknown module '{}'\n", Name));
+        }
+    }
+
+    let Outcome = match (&Method, Segments.as_slice()) {
+        (&Method::GET, ["modules"]) => return reply(StatusCode::OK, list_modules()),
+        (&Method::GET, ["redaction", "pseudonyms", Address]) => return lookup_pseudonyms(Address),
+        (&Method::POST, ["modules", Name, "enable"]) => set_module_enabled(Name, true),
+        (&Method::POST, ["modules", Name, "disable"]) => set_module_enabled(Name, false),
+        (&Method::POST, ["modules", Name, "earlier"]) => move_module(Name, true),
+        (&Method::POST, ["modules", Name, "later"]) => move_module(Name, false),
+        (&Method::PUT, ["modules", Name, "priority"]) => Body.trim().parse::<i32>()
+            .map_err(|_| format!("priority must be an integer, got '{}'", Body.trim()))
+            .and_then(|Priority| set_module_priority(Name, Priority)),
+        (&Method::POST, ["modules", Name, "reload"]) => parse_settings(&Body)
+            .and_then(|Settings| reload_module(Name, &Settings)),
+        _ => return reply(StatusCode::NOT_FOUND, String::from("not found\n")),
+    };
+
+    match Outcome {
+        Ok(()) => reply(StatusCode::OK, list_modules()),
+        Err(e) => reply(StatusCode::BAD_REQUEST, format!("{}\n", e)),
+    }
+}
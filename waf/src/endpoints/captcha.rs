@@ -2,17 +2,11 @@
 #![allow(non_snake_case)]
 
 use hyper::{Request, Response, StatusCode};
-use hyper::body::Incoming;
 use hyper::header::{SET_COOKIE, COOKIE};
-use http_body_util::Full;
-use http_body_util::combinators::BoxBody;
-use hyper::body::Bytes;
-use std::convert::Infallible;
 
 use crate::modules::cookie_manager::{generate_cookie, validate_cookie, store_cookie, is_valid_format};
 use crate::modules::brotli_compressor::{compress_bytes_with_type, update_headers_for_brotli};
-
-type ResponseBody = BoxBody<Bytes, Infallible>;
+use crate::proxy::body::{full_body, empty_body, ResponseBody};
 
 #[derive(Clone)]
 pub struct CaptchaEndpoint;
@@ -22,7 +16,7 @@ impl CaptchaEndpoint {
         Self
     }
     
-    pub fn extract_cookie(&self, Request: &Request<Incoming>) -> Option<String> {
+    pub fn extract_cookie<B>(&self, Request: &Request<B>) -> Option<String> {
         let _Headers = Request.headers();
         _Headers.get(COOKIE).and_then(|cookie_value| {
             let _CookieStr = cookie_value.to_str().ok()?;
@@ -34,18 +28,26 @@ impl CaptchaEndpoint {
     }
     
     pub fn create_captcha_response(&self) -> Response<ResponseBody> {
-        let _NewCookie = generate_cookie();
-        store_cookie(_NewCookie.clone());
-        
         let _ContentType = "text/html; charset=utf-8";
         let _HtmlContent = "<html><body><h1>captcha 192.168.0.1</h1></body></html>";
-        let _CompressedBytes = compress_bytes_with_type(_HtmlContent.as_bytes(), _ContentType);
+        let _CompressedBytes = match compress_bytes_with_type(_HtmlContent.as_bytes(), _ContentType) {
+            Ok(Bytes) => Bytes,
+            Err(Fault) => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(full_body(Fault.to_string()))
+                    .unwrap();
+            }
+        };
+        
+        let _NewCookie = generate_cookie();
+        store_cookie(_NewCookie.clone());
         
         let mut _Response = Response::builder()
             .status(StatusCode::OK)
             .header(SET_COOKIE, format!("access={}; Max-Age=600; Path=/; HttpOnly", _NewCookie))
             .header(hyper::header::CONTENT_TYPE, _ContentType)
-            .body(BoxBody::new(Full::new(_CompressedBytes)))
+            .body(full_body(_CompressedBytes))
             .unwrap();
             
         update_headers_for_brotli(_Response.headers_mut());
@@ -56,11 +58,11 @@ impl CaptchaEndpoint {
         Response::builder()
             .status(StatusCode::FOUND)
             .header(hyper::header::LOCATION, "/captcha")
-            .body(BoxBody::new(Full::new(Bytes::from(""))))
+            .body(empty_body())
             .unwrap()
     }
     
-    pub fn handle_request(&self, Request: &Request<Incoming>) -> Option<Response<ResponseBody>> {
+    pub fn handle_request<B>(&self, Request: &Request<B>) -> Option<Response<ResponseBody>> {
         let _Path = Request.uri().path();
         
         if _Path == "/captcha" {
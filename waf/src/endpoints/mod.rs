@@ -1,4 +1,5 @@
 //waf/src/endpoints/mod.rs
 #![allow(non_snake_case)]
 
+pub mod admin;
 pub mod captcha;
\ No newline at end of file
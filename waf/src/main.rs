@@ -2,36 +2,67 @@
 #![allow(non_snake_case)]
 
 use dotenv::dotenv;
-use hyper::server::conn::http1;
-use hyper::{Request, Response};
+use hyper::{Request, Response, Version};
 use hyper_util::client::legacy::Client;
-use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::rt::TokioExecutor;
-use std::env;
-use std::net::SocketAddr;
+use hyper_util::server::conn::auto::Builder as ServerBuilder;
+use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
-use hyper::body::Bytes;
-use http_body_util::BodyExt;
-use http_body_util::Full;
-use hyper::body::Incoming;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes, Incoming};
 use hyper_util::client::legacy::Error as HyperError;
-use http_body_util::combinators::BoxBody;
-use std::convert::Infallible;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, Duration, Instant};
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+mod cidr;
+mod config;
 mod module;
+mod module_stats;
 mod modules;
 mod endpoints;
+mod proxy;
+mod shutdown;
 
-use module::{process_content, print_modules_performance_report, get_registered_module_count};
-use modules::brotli_compressor::{update_headers_for_brotli, compress_bytes_with_type, compress_content_with_type};
+use config::env_or;
+use module::{current_pipeline, shutdown_modules, RequestAction, RequestContext, get_module_performance, print_modules_performance_report, get_registered_module_count, validate_module_order};
+use modules::brotli_compressor::{self, apply_brotli_headers, is_compressible_content};
+use endpoints::admin::{self, AdminSettings};
 use endpoints::captcha::CaptchaEndpoint;
-use modules::dashboard::{increment_request_counter, increment_response_counter};
+use modules::dashboard::{increment_request_counter, increment_response_counter, update_module_performance};
+use module_stats::ModuleTiming;
+use proxy::breaker::Admission;
+use proxy::body::{empty_body, full_body, BodyError, RequestBody, ResponseBody};
+use proxy::connection::{ClientStream, ConnectionInfo};
+use proxy::connector::{backend_uri, UpstreamConnector};
+use proxy::headers::{strip_hop_by_hop, ForwardedSettings};
+use proxy::health::start_health_checks;
+use proxy::listener::{bind_listener, listeners_from_env, ListenerSpec};
+use proxy::policy::UpstreamPolicy;
+use proxy::proxy_protocol::ProxyProtocolSettings;
+use proxy::retry::is_idempotent;
+use proxy::router::RoutingTable;
+use proxy::server::ServerSettings;
+use proxy::timeout::{is_connect_timeout, within, DeadlineBody};
+use proxy::tls::{build_acceptor, TlsSettings};
+use proxy::tunnel::{is_upgrade_request, spawn_tunnel};
+use proxy::upstream::{TrackedBody, UpstreamRegistry};
+use proxy::stream::ContentStream;
+use shutdown::{DrainGuard, ShutdownSettings};
 
-type ResponseBody = BoxBody<Bytes, Infallible>;
+// Shared by every listener and connection
+struct ProxyState {
+    Client: Client<UpstreamConnector, RequestBody>,
+    Upstreams: Arc<UpstreamRegistry>,
+    Routes: RoutingTable,
+    Policy: Arc<UpstreamPolicy>,
+    Forwarded: Arc<ForwardedSettings>,
+    CaptchaEndpoint: CaptchaEndpoint,
+    RequestBodyLimit: usize,
+}
 
 static _REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
 static _RESPONSE_COUNTER: AtomicU64 = AtomicU64::new(0);
@@ -40,155 +71,539 @@ static _RESPONSE_COUNTER: AtomicU64 = AtomicU64::new(0);
 async fn main() {
     dotenv().ok();
     
-    modules::init_all();
+    if let Err(e) = modules::init_all().and_then(|_| validate_module_order()) {
+        eprintln!("Module configuration error: {}", e);
+        std::process::exit(1);
+    }
     
-    let _SourcePort: u16 = env::var("SOURCE_PORT")
-        .unwrap_or_else(|_| String::from("2025"))
-        .parse()
-        .unwrap();
+    let _Upstreams: Arc<UpstreamRegistry> = match UpstreamRegistry::from_env() {
+        Ok(Registry) => Arc::new(Registry),
+        Err(e) => {
+            eprintln!("Upstream configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
     
-    let _DestinationPort: u16 = env::var("DESTINATION_PORT")
-        .unwrap_or_else(|_| String::from("1337"))
-        .parse()
-        .unwrap();
-
-    let _ServerAddress: SocketAddr = SocketAddr::from(([127, 0, 0, 1], _SourcePort));
+    let _Routes = match RoutingTable::from_env(&_Upstreams) {
+        Ok(Routes) => Routes,
+        Err(e) => {
+            eprintln!("Routing configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let _TlsSettings = TlsSettings::from_env();
+    let _ListenerSpecs = match listeners_from_env(_TlsSettings.is_some()) {
+        Ok(Specs) => Specs,
+        Err(e) => {
+            eprintln!("Listener configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let mut _ServerListeners: Vec<(ListenerSpec, TcpListener)> = Vec::new();
+    for Spec in _ListenerSpecs.iter() {
+        match bind_listener(Spec).await {
+            Ok(Listener) => _ServerListeners.push((*Spec, Listener)),
+            Err(e) => {
+                eprintln!("Listener error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    let _AdminListener = match AdminSettings::from_env() {
+        Ok(Some(Settings)) => match TcpListener::bind(Settings.Address).await {
+            Ok(Listener) => Some((Listener, Arc::new(Settings))),
+            Err(e) => {
+                eprintln!("Admin API error: cannot bind {}: {}", Settings.Address, e);
+                std::process::exit(1);
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Admin API configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let _ProxyProtocol: Arc<ProxyProtocolSettings> = match ProxyProtocolSettings::from_env(_ServerListeners.iter().any(|(Spec, _)| Spec.ProxyProtocol)) {
+        Ok(Settings) => Arc::new(Settings),
+        Err(e) => {
+            eprintln!("PROXY protocol configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    start_health_checks(&_Upstreams);
+    
+    let _Forwarded: Arc<ForwardedSettings> = match ForwardedSettings::from_env() {
+        Ok(Settings) => Arc::new(Settings),
+        Err(e) => {
+            eprintln!("Forwarded header configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let _UpstreamPolicy: Arc<UpstreamPolicy> = Arc::new(UpstreamPolicy::from_env());
     
-    let _ServerListener: TcpListener = match TcpListener::bind(_ServerAddress).await {
-        Ok(listener) => listener,
-        Err(_e) => {
+    let _Connector = UpstreamConnector::new(_UpstreamPolicy.Timeouts.Connect);
+    let _HttpClient: Client<UpstreamConnector, RequestBody> = Client::builder(TokioExecutor::new()).build(_Connector);
+    let _ServerSettings = match ServerSettings::from_env() {
+        Ok(Settings) => Settings,
+        Err(e) => {
+            eprintln!("Server configuration error: {}", e);
             std::process::exit(1);
         }
     };
+    let _ServerBuilder = _ServerSettings.build();
+    
+    let _TlsAcceptor = match _TlsSettings {
+        Some(TlsConfig) => match build_acceptor(&TlsConfig, &_ServerSettings) {
+            Ok(Acceptor) => Some((Acceptor, TlsConfig.HandshakeTimeout)),
+            Err(e) => {
+                eprintln!("TLS setup failed: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
     
-    let _HttpClient: Client<HttpConnector, Incoming> = Client::builder(TokioExecutor::new()).build_http();
-    let _CaptchaEndpoint = CaptchaEndpoint::new();
+    let _ProxyState = Arc::new(ProxyState {
+        Client: _HttpClient,
+        Upstreams: _Upstreams,
+        Routes: _Routes,
+        Policy: _UpstreamPolicy,
+        Forwarded: _Forwarded,
+        CaptchaEndpoint: CaptchaEndpoint::new(),
+        // Largest request body buffered for modules that inspect it, bigger ones are refused with 413
+        RequestBodyLimit: env_or("REQUEST_BODY_BUFFER_LIMIT", 64 * 1024),
+    });
 
     let _ModuleCount = get_registered_module_count();
     
-    // Start performance monitoring task
-    tokio::spawn(async {
-        let mut _ReportInterval = interval(Duration::from_secs(60));
+    // Start performance monitoring task: per-module windows for the dashboard every second,
+    // the cumulative report every MODULE_REPORT_INTERVAL_SECS
+    let _ReportEvery = Duration::from_secs(env_or("MODULE_REPORT_INTERVAL_SECS", 60u64).max(1));
+    tokio::spawn(async move {
+        let mut _SampleInterval = interval(Duration::from_secs(1));
+        let mut _Previous: HashMap<String, ModuleTiming> = HashMap::new();
+        let mut _LastSample = Instant::now();
+        let mut _LastReport = Instant::now();
         loop {
-            _ReportInterval.tick().await;
+            _SampleInterval.tick().await;
             
-            let _TotalRequests = _REQUEST_COUNTER.load(Ordering::Relaxed);
-            let _TotalResponses = _RESPONSE_COUNTER.load(Ordering::Relaxed);
-                              
-            print_modules_performance_report();
+            let _Span = _LastSample.elapsed();
+            _LastSample = Instant::now();
+            for (Name, Timing) in get_module_performance() {
+                let _Window = Timing.since(&_Previous.get(&Name).copied().unwrap_or_default());
+                update_module_performance(&Name, &Timing, &_Window, _Span);
+                _Previous.insert(Name, Timing);
+            }
+            
+            if _LastReport.elapsed() >= _ReportEvery {
+                _LastReport = Instant::now();
+                let _TotalRequests = _REQUEST_COUNTER.load(Ordering::Relaxed);
+                let _TotalResponses = _RESPONSE_COUNTER.load(Ordering::Relaxed);
+                print_modules_performance_report(_TotalRequests, _TotalResponses);
+            }
         }
     });
+    
+    for (Spec, Listener) in _ServerListeners {
+        let TlsAcceptor = if Spec.Tls { _TlsAcceptor.clone() } else { None };
+        let ProxyProtocol = if Spec.ProxyProtocol { Some(_ProxyProtocol.clone()) } else { None };
+        tokio::spawn(run_listener(Listener, Spec, _ProxyState.clone(), _ServerBuilder.clone(), TlsAcceptor, ProxyProtocol));
+    }
 
+    if let Some((Listener, Settings)) = _AdminListener {
+        tokio::spawn(admin::serve(Listener, Settings));
+    }
+
+    let _ShutdownSettings = ShutdownSettings::from_env();
+    shutdown::wait_for_signal().await;
+    
+    // Stop accepting, let keep-alive connections finish their current request, then tear down
+    shutdown::trigger();
+    
+    let _Drained = tokio::select! {
+        Drained = tokio::time::timeout(_ShutdownSettings.Grace, shutdown::drained()) => Drained.is_ok(),
+        _ = shutdown::wait_for_signal() => false,
+    };
+    let _Abandoned = shutdown::active_connections();
+    
+    // Module shutdown hooks are synchronous and may wait on threads of their own
+    let _ShutdownErrors = tokio::task::spawn_blocking(shutdown_modules).await.unwrap_or_default();
+    
+    if _Drained {
+        eprintln!("Shutdown complete, all connections drained");
+    } else {
+        eprintln!("Shutdown deadline reached, dropping {} open connection(s)", _Abandoned);
+    }
+    for (ModuleName, e) in _ShutdownErrors {
+        eprintln!("{} shutdown error: {}", ModuleName, e);
+    }
+}
+
+async fn run_listener(
+    Listener: TcpListener,
+    Spec: ListenerSpec,
+    State: Arc<ProxyState>,
+    ServerBuilder: ServerBuilder<TokioExecutor>,
+    TlsAcceptor: Option<(TlsAcceptor, Duration)>,
+    ProxyProtocol: Option<Arc<ProxyProtocolSettings>>,
+) {
     loop {
-        let (Stream, _ClientAddr) = match _ServerListener.accept().await {
-            Ok(connection) => connection,
-            Err(_e) => {
-                continue;
-            }
+        let (mut Stream, PeerAddr) = tokio::select! {
+            Accepted = Listener.accept() => match Accepted {
+                Ok(connection) => connection,
+                Err(_e) => {
+                    continue;
+                }
+            },
+            _ = shutdown::shutdown_requested() => return,
         };
         
-        let ClientClone = _HttpClient.clone();
-        let DestPort = _DestinationPort;
-        let CaptchaEndpointClone = _CaptchaEndpoint.clone();
+        let State = State.clone();
+        let ServerBuilder = ServerBuilder.clone();
+        let TlsAcceptor = TlsAcceptor.clone();
+        let ProxyProtocol = ProxyProtocol.clone();
+        let Drain = DrainGuard::new();
 
         tokio::task::spawn(async move {
-            http1::Builder::new()
-                .serve_connection(
-                    TokioIo::new(Stream),
-                    service_fn(move |req| proxy_service(req, ClientClone.clone(), DestPort, CaptchaEndpointClone.clone())),
-                )
-                .await
-                .unwrap_or_else(|_| {});
+            let _Drain = Drain;
+            
+            // Behind an L4 balancer the peer is the balancer, the PROXY header names the real client
+            let ClientAddr = match ProxyProtocol {
+                Some(ProxyProtocol) => match ProxyProtocol.accept(&mut Stream, PeerAddr).await {
+                    Ok(ClientAddr) => ClientAddr,
+                    Err(_e) => return,
+                },
+                None => PeerAddr,
+            };
+            let Connection = ConnectionInfo {
+                ClientAddr,
+                IsTls: Spec.Tls,
+            };
+            let Service = service_fn(move |req| proxy_service(req, State.clone(), Connection));
+            
+            let ClientStream: ClientStream = match TlsAcceptor {
+                // A client that stalls mid-handshake would otherwise hold the connection open for good
+                Some((Acceptor, HandshakeTimeout)) => match tokio::time::timeout(HandshakeTimeout, Acceptor.accept(Stream)).await {
+                    Ok(Ok(TlsStream)) => Box::new(TlsStream),
+                    Ok(Err(_)) | Err(_) => return,
+                },
+                None => Box::new(Stream),
+            };
+            
+            // On shutdown the in-flight request is allowed to finish before the connection closes
+            let Connection = ServerBuilder.serve_connection_with_upgrades(TokioIo::new(ClientStream), Service);
+            tokio::pin!(Connection);
+            tokio::select! {
+                _ = Connection.as_mut() => {}
+                _ = shutdown::shutdown_requested() => {
+                    Connection.as_mut().graceful_shutdown();
+                    let _ = Connection.as_mut().await;
+                }
+            }
         });
     }
 }
 
 async fn proxy_service(
     Request: Request<Incoming>,
-    Client: Client<HttpConnector, Incoming>,
-    DestPort: u16,
-    CaptchaEndpoint: CaptchaEndpoint,
+    State: Arc<ProxyState>,
+    Connection: ConnectionInfo,
 ) -> Result<Response<ResponseBody>, HyperError> {
+    let ProxyState { Client, Upstreams, Routes, Policy, Forwarded, CaptchaEndpoint, RequestBodyLimit } = &*State;
+    
     _REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
     increment_request_counter();
     
-    if let Some(Response) = CaptchaEndpoint.handle_request(&Request) {
-        _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
-        increment_response_counter();
-        return Ok(Response);
-    }
-    
     let _RequestMethod = Request.method().clone();
     let _RequestUri = Request.uri().clone();
     
-    // Forward the request to the destination
-    let mut RequestToForward = Request;
-    let _DestinationUri: String = format!(
-        "http://127.0.0.1:{}{}",
-        DestPort,
-        RequestToForward.uri().path_and_query().map(|x| x.as_str()).unwrap_or("")
-    );
+    let _RequestHost: Option<String> = Request.headers().get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| Request.uri().authority().map(|a| a.as_str()))
+        .map(|v| v.to_string());
     
-    *RequestToForward.uri_mut() = _DestinationUri.parse().unwrap();
+    let Route = Routes.route(&_RequestMethod, _RequestHost.as_deref(), _RequestUri.path());
+    let Pipeline = current_pipeline();
     
-    let ServerResponse = match Client.request(RequestToForward).await {
-        Ok(response) => response,
-        Err(e) => {
-            let ErrorResponse = Response::builder()
-                .status(hyper::StatusCode::BAD_GATEWAY)
-                .body(BoxBody::new(Full::new(Bytes::from(format!("Proxy error: {}", e)))))
-                .unwrap();
+    // Request phase: modules may rewrite, block or challenge before anything reaches a backend
+    let (mut RequestParts, RequestBodyIn) = Request.into_parts();
+    // Chunked and unsized bodies are read too, a body that would skip inspection is refused instead
+    let BufferBody = Pipeline.wants_request_body(Route.Modules.as_deref());
+    
+    let mut BufferedBody: Option<Bytes> = None;
+    let RequestBodyIn: RequestBody = if BufferBody {
+        match within(Policy.Timeouts.Read, "request body read", Limited::new(RequestBodyIn, *RequestBodyLimit).collect()).await {
+            Ok(Ok(Collected)) => {
+                BufferedBody = Some(Collected.to_bytes());
+                empty_body()
+            }
+            Ok(Err(e)) if e.is::<LengthLimitError>() => return Ok(error_response(
+                hyper::StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Request body larger than {} bytes", RequestBodyLimit),
+            )),
+            Ok(Err(e)) => return Ok(error_response(hyper::StatusCode::BAD_REQUEST, format!("Request body error: {}", e))),
+            Err(_) => return Ok(error_response(hyper::StatusCode::REQUEST_TIMEOUT, String::from("Request body timed out"))),
+        }
+    } else {
+        RequestBodyIn.map_err(BodyError::from).boxed()
+    };
+    
+    let BufferedLength = BufferedBody.as_ref().map(|Body| Body.len());
+    let mut RequestContext = RequestContext {
+        Method: &RequestParts.method,
+        Uri: &mut RequestParts.uri,
+        Headers: &mut RequestParts.headers,
+        ClientAddr: Connection.ClientAddr,
+        Body: BufferedBody.as_mut(),
+    };
+    let ForceCaptcha = match Pipeline.process_request(&mut RequestContext, Route.Modules.as_deref()) {
+        Ok(RequestAction::Block(Response)) => {
             _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
             increment_response_counter();
-            return Ok(ErrorResponse);
+            return Ok(Response);
         }
+        Ok(RequestAction::Challenge) => true,
+        Ok(RequestAction::Allow | RequestAction::Modify) => false,
+        Err(Fault) => return Ok(error_response(hyper::StatusCode::INTERNAL_SERVER_ERROR, Fault.to_string())),
     };
     
-    let _ResponseStatus = ServerResponse.status();
+    // A hook that rewrote the buffered body leaves the announced length stale
+    if let Some(Body) = BufferedBody.as_ref() {
+        if BufferedLength != Some(Body.len()) {
+            RequestParts.headers.insert(hyper::header::CONTENT_LENGTH, hyper::header::HeaderValue::from(Body.len()));
+        }
+    }
     
-    let (ResponseParts, ResponseBody) = ServerResponse.into_parts();
-    let BodyBytes = match ResponseBody.collect().await {
-        Ok(collected) => collected.to_bytes(),
-        Err(_) => {
-            let ErrorResponse = Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(BoxBody::new(Full::new(Bytes::from("Error processing response"))))
-                .unwrap();
+    let Request = Request::from_parts(RequestParts, RequestBodyIn);
+    
+    // The challenge page itself stays reachable even on routes that skip the gate
+    if Route.Captcha || ForceCaptcha || Request.uri().path() == "/captcha" {
+        if let Some(Response) = CaptchaEndpoint.handle_request(&Request) {
             _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
             increment_response_counter();
-            return Ok(ErrorResponse);
+            return Ok(Response);
         }
+    }
+    
+    let Pool = match Route.Pool.as_ref() {
+        Some(Pool) => Pool.clone(),
+        None => Upstreams.pool_for_host(_RequestHost.as_deref()),
+    };
+    let RouteModules = Route.Modules.clone();
+    
+    let mut RequestToForward = Request;
+    let ClientUpgrade = if is_upgrade_request(&RequestToForward) {
+        Some(hyper::upgrade::on(&mut RequestToForward))
+    } else {
+        None
     };
     
-    let mut ProcessedResponseParts = ResponseParts;
-    let ContentBytes;
+    // h2 carries the host in the :authority pseudo-header, the backend only speaks HTTP/1.1
+    if RequestToForward.version() == Version::HTTP_2 {
+        if let Some(Authority) = RequestToForward.uri().authority().cloned() {
+            if let Ok(HostValue) = hyper::header::HeaderValue::from_str(Authority.as_str()) {
+                RequestToForward.headers_mut().entry(hyper::header::HOST).or_insert(HostValue);
+            }
+        }
+    }
+    
+    strip_hop_by_hop(RequestToForward.headers_mut(), ClientUpgrade.is_some());
+    Forwarded.apply(RequestToForward.headers_mut(), &Connection, _RequestHost.as_deref());
     
-    let ContentType = ProcessedResponseParts.headers.get(hyper::header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok());
+    // Only requests that can be replayed byte for byte are retried: no body, or one buffered for the hooks
+    let ReplayBody: Option<Bytes> = match BufferedBody {
+        Some(Body) => Some(Body),
+        None if RequestToForward.body().is_end_stream() => Some(Bytes::new()),
+        None => None,
+    };
+    let Retryable = ClientUpgrade.is_none()
+        && is_idempotent(&_RequestMethod)
+        && ReplayBody.is_some();
+    
+    let (RequestParts, RequestBodyIn) = RequestToForward.into_parts();
+    let mut PendingBody: Option<RequestBody> = Some(RequestBodyIn);
+    let PathAndQuery = RequestParts.uri.path_and_query().map(|x| x.as_str()).unwrap_or("/").to_string();
+    
+    let Deadline = Policy.Timeouts.deadline();
+    Policy.Budget.deposit();
+    
+    let Permit = match Pool.Breaker.try_acquire() {
+        Admission::Allowed(Permit) => Permit,
+        Admission::Rejected { RetryAfter } => {
+            _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
+            increment_response_counter();
+            return Ok(Pool.Breaker.fallback_response(RetryAfter));
+        }
+    };
+    let ForwardStarted = std::time::Instant::now();
+    
+    let mut Attempt: u32 = 0;
+    let ForwardOutcome = loop {
+        let BackendGuard = match Pool.select(Connection.ClientAddr.ip()) {
+            Some(Guard) => Guard,
+            None => {
+                break Err((
+                    hyper::StatusCode::BAD_GATEWAY,
+                    format!("Proxy error: no backend available in pool '{}'", Pool.Name),
+                ));
+            }
+        };
         
-    if let Some(TypeValue) = ContentType {
-        if let Ok(BodyText) = std::str::from_utf8(&BodyBytes) {
-            let mut Content: String = BodyText.to_string();
-            process_content(&mut Content);
-            ContentBytes = compress_content_with_type(&mut Content, TypeValue);
-        } else {
-            ContentBytes = compress_bytes_with_type(&BodyBytes, TypeValue);
-        }
-        update_headers_for_brotli(&mut ProcessedResponseParts.headers);
-    } else {
-        let mut Content: String = String::from_utf8_lossy(&BodyBytes).into_owned();
-        process_content(&mut Content);
-        ContentBytes = Bytes::from(Content);
+        // Forward the request to the selected backend
+        let _DestinationUri: String = backend_uri(&BackendGuard.Backend.Address, &PathAndQuery);
+        let AttemptBody = match (ReplayBody.as_ref(), PendingBody.take()) {
+            (Some(Body), _) => full_body(Body.clone()),
+            (None, Some(Body)) => Body,
+            (None, None) => empty_body(),
+        };
+        
+        let mut AttemptRequest = Request::new(AttemptBody);
+        *AttemptRequest.method_mut() = RequestParts.method.clone();
+        *AttemptRequest.uri_mut() = _DestinationUri.parse().unwrap();
+        *AttemptRequest.version_mut() = Version::HTTP_11;
+        *AttemptRequest.headers_mut() = RequestParts.headers.clone();
+        
+        let HeaderTimeout = Policy.Timeouts.header_timeout(Deadline);
+        let Outcome = within(HeaderTimeout, "response head", Client.request(AttemptRequest)).await;
+        
+        let CanRetry = Retryable
+            && Attempt < Policy.Retry.MaxRetries
+            && Deadline.map(|d| tokio::time::Instant::now() < d).unwrap_or(true);
+        
+        let Failure = match Outcome {
+            Ok(Ok(response)) => {
+                let RetryStatus = matches!(
+                    response.status(),
+                    hyper::StatusCode::BAD_GATEWAY
+                        | hyper::StatusCode::SERVICE_UNAVAILABLE
+                        | hyper::StatusCode::GATEWAY_TIMEOUT
+                );
+                
+                if !(RetryStatus && CanRetry && Policy.Budget.try_withdraw()) {
+                    break Ok((response, BackendGuard));
+                }
+                
+                BackendGuard.Backend.report_failure();
+                None
+            }
+            Ok(Err(e)) if e.is_connect() && is_connect_timeout(&e) => {
+                BackendGuard.Backend.report_failure();
+                Some((hyper::StatusCode::GATEWAY_TIMEOUT, String::from("Proxy error: upstream connect timed out")))
+            }
+            Ok(Err(e)) => {
+                BackendGuard.Backend.report_failure();
+                Some((hyper::StatusCode::BAD_GATEWAY, format!("Proxy error: {}", e)))
+            }
+            Err(_Timeout) => {
+                BackendGuard.Backend.report_failure();
+                Some((hyper::StatusCode::GATEWAY_TIMEOUT, String::from("Proxy error: upstream timed out")))
+            }
+        };
+        
+        if let Some(Failure) = Failure {
+            if !(CanRetry && Policy.Budget.try_withdraw()) {
+                break Err(Failure);
+            }
+        }
+        
+        tokio::time::sleep(Policy.Retry.backoff(Attempt)).await;
+        Attempt += 1;
+    };
+    
+    let (ServerResponse, BackendGuard) = match ForwardOutcome {
+        Ok(Forwarded) => {
+            Permit.record(Forwarded.0.status().as_u16() < 500, ForwardStarted.elapsed());
+            Forwarded
+        }
+        Err((Status, Message)) => {
+            Permit.record(false, ForwardStarted.elapsed());
+            return Ok(error_response(Status, Message));
+        }
+    };
+    
+    let _ResponseStatus = ServerResponse.status();
+    
+    if let Some(ClientUpgrade) = ClientUpgrade {
+        if _ResponseStatus == hyper::StatusCode::SWITCHING_PROTOCOLS {
+            BackendGuard.Backend.report_success();
+            
+            let mut ServerResponse = ServerResponse;
+            let BackendUpgrade = hyper::upgrade::on(&mut ServerResponse);
+            let Protocol = ServerResponse.headers().get(hyper::header::UPGRADE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_string();
+            
+            spawn_tunnel(ClientUpgrade, BackendUpgrade, BackendGuard, Connection.ClientAddr, Protocol, _RequestUri.path().to_string());
+            
+            let (mut ResponseParts, _) = ServerResponse.into_parts();
+            strip_hop_by_hop(&mut ResponseParts.headers, true);
+            _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
+            increment_response_counter();
+            return Ok(Response::from_parts(ResponseParts, empty_body()));
+        }
+    }
+    
+    match _ResponseStatus {
+        hyper::StatusCode::BAD_GATEWAY
+        | hyper::StatusCode::SERVICE_UNAVAILABLE
+        | hyper::StatusCode::GATEWAY_TIMEOUT => BackendGuard.Backend.report_failure(),
+        _ => BackendGuard.Backend.report_success(),
     }
     
-    if let Some(Header) = ProcessedResponseParts.headers.get_mut(hyper::header::CONTENT_LENGTH) {
-        *Header = hyper::header::HeaderValue::from_str(&ContentBytes.len().to_string()).unwrap();
+    let (mut ResponseParts, ResponseBody) = ServerResponse.into_parts();
+    strip_hop_by_hop(&mut ResponseParts.headers, false);
+    if let Err(Fault) = Pipeline.process_response_headers(_ResponseStatus, &mut ResponseParts.headers, RouteModules.as_deref()) {
+        return Ok(error_response(hyper::StatusCode::INTERNAL_SERVER_ERROR, Fault.to_string()));
     }
     
-    let NewResponseBody = BoxBody::new(Full::new(ContentBytes));
-    let FinalResponse = Response::from_parts(ProcessedResponseParts, NewResponseBody);
+    let ContentType: Option<String> = ResponseParts.headers.get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    
+    let AlreadyEncoded = ResponseParts.headers.get(hyper::header::CONTENT_ENCODING)
+        .map(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"))
+        .unwrap_or(false);
+    
+    let HasNoBody = _RequestMethod == hyper::Method::HEAD
+        || _ResponseStatus == hyper::StatusCode::NO_CONTENT
+        || _ResponseStatus == hyper::StatusCode::NOT_MODIFIED;
+    
+    let ProcessText = !AlreadyEncoded && !HasNoBody && Pipeline.has_modules_for(ContentType.as_deref(), RouteModules.as_deref());
+    let Compress = !AlreadyEncoded
+        && !HasNoBody
+        && Pipeline.includes(brotli_compressor::MODULE_NAME, RouteModules.as_deref())
+        && ContentType.as_deref().map(is_compressible_content).unwrap_or(false);
+    
+    let NewResponseBody = if ProcessText || Compress {
+        ResponseParts.headers.remove(hyper::header::CONTENT_LENGTH);
+        if Compress {
+            apply_brotli_headers(&mut ResponseParts.headers);
+        }
+        ContentStream::new(ResponseBody, Pipeline, ContentType, RouteModules, ProcessText, Compress).boxed()
+    } else {
+        ResponseBody.map_err(BodyError::from).boxed()
+    };
+    
+    let TimedResponseBody = DeadlineBody::new(NewResponseBody, Policy.Timeouts.BodyIdle, Deadline).boxed();
+    let FinalResponse = Response::from_parts(ResponseParts, TrackedBody::new(TimedResponseBody, BackendGuard).boxed());
     
     _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
     increment_response_counter();
     Ok(FinalResponse)
-}
\ No newline at end of file
+}
+
+fn error_response(Status: hyper::StatusCode, Message: String) -> Response<ResponseBody> {
+    _RESPONSE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    increment_response_counter();
+    Response::builder()
+        .status(Status)
+        .body(full_body(Message))
+        .unwrap()
+}
@@ -1,46 +1,1169 @@
 //waf/src/module.rs
 #![allow(non_snake_case)]
 
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Method, Response, StatusCode, Uri};
 use once_cell::sync::Lazy;
 
+use crate::config::{env_flag, env_opt, env_or, set_override};
+use crate::module_stats::{format_micros, ModuleStats, ModuleTiming, LATENCY_BUCKETS_US};
+use crate::proxy::body::ResponseBody;
+
+// A module is built from its configuration, checked with validate_config, then init runs once before
+// it joins the pipeline. Hooks take &self and may run on many connections at once; every hook has a
+// no-op default so a module only implements the phases it cares about.
+pub trait WafModule: Send + Sync {
+    fn name(&self) -> &str;
+    fn version(&self) -> &str;
+    // Lower runs earlier, MODULE_<NAME>_PRIORITY overrides it
+    fn priority(&self) -> i32;
+
+    // Names of modules this one must precede or follow, names that are not registered are ignored
+    fn before(&self) -> &[&str] {
+        &[]
+    }
+    fn after(&self) -> &[&str] {
+        &[]
+    }
+
+    // Content-Type prefixes on_body is interested in, empty skips response bodies altogether
+    fn content_types(&self) -> &[&str] {
+        &[]
+    }
+    // Begin and end markers of blocks that span lines, such as PEM armour. Streamed text from the line a
+    // block begins on is held back until the block ends, so on_body gets it in one piece.
+    fn held_blocks(&self) -> &[(&str, &str)] {
+        &[]
+    }
+    // Only modules that opt in are dispatched to (and timed for) the request and response header phases
+    fn handles_requests(&self) -> bool {
+        false
+    }
+    fn handles_response_headers(&self) -> bool {
+        false
+    }
+    fn wants_request_body(&self) -> bool {
+        false
+    }
+    // What happens to the request when a hook panics, MODULE_<NAME>_ON_FAILURE=open|closed overrides it
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Open
+    }
+
+    fn validate_config(&self) -> Result<(), String> {
+        Ok(())
+    }
+    fn init(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn on_request(&self, _Context: &mut RequestContext) -> RequestAction {
+        RequestAction::Allow
+    }
+    fn on_response_headers(&self, _Status: StatusCode, _Headers: &mut HeaderMap) {}
+    fn on_body(&self, _Content: &mut String) -> BodyAction {
+        BodyAction::Continue
+    }
+
+    // A fresh instance built from the current configuration, validated and initialised before it
+    // replaces this one. None means the module can only be configured at startup.
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        None
+    }
+    // Settings outside MODULE_<NAME>_* the module reads, the only other keys a reload may change
+    fn config_keys(&self) -> &[&str] {
+        &[]
+    }
+
+    fn shutdown(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    // Skip the failed module and carry on as if it was not selected
+    Open,
+    // Answer 500 rather than serve what the module should have inspected; mid-body the stream is cut.
+    // For detectors of data that must not leak, since whatever slips past a broken one cannot be taken back.
+    Closed,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(Value: &str) -> Result<Self, Self::Err> {
+        match Value.trim().to_lowercase().as_str() {
+            "open" => Ok(FailurePolicy::Open),
+            "closed" => Ok(FailurePolicy::Closed),
+            Other => Err(format!("unknown failure policy '{}', expected open or closed", Other)),
+        }
+    }
+}
+
+impl fmt::Display for FailurePolicy {
+    fn fmt(&self, Formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Formatter.write_str(match self {
+            FailurePolicy::Open => "open",
+            FailurePolicy::Closed => "closed",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ModuleFault {
+    pub Module: String,
+    pub Phase: &'static str,
+}
+
+impl fmt::Display for ModuleFault {
+    fn fmt(&self, Formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(Formatter, "module {} failed during the {} phase", self.Module, self.Phase)
+    }
+}
+
+impl std::error::Error for ModuleFault {}
+
+// Why a body ends early: a fail-closed module broke, or a module found something it must not pass on
+#[derive(Debug)]
+pub enum BodyRejection {
+    Fault(ModuleFault),
+    Blocked { Module: String, Reason: String },
+}
+
+impl fmt::Display for BodyRejection {
+    fn fmt(&self, Formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyRejection::Fault(Fault) => Fault.fmt(Formatter),
+            BodyRejection::Blocked { Module, Reason } => write!(Formatter, "module {} blocked the body: {}", Module, Reason),
+        }
+    }
+}
+
+impl std::error::Error for BodyRejection {}
+
+impl From<ModuleFault> for BodyRejection {
+    fn from(Fault: ModuleFault) -> Self {
+        BodyRejection::Fault(Fault)
+    }
+}
+
+// For hooks that detect their own failure (a plugin trap, malformed output): unwinds like a panic
+// without the panic message, so the module's failure policy applies exactly as it would for a crash
+pub fn raise_module_fault(Reason: String) -> ! {
+    std::panic::resume_unwind(Box::new(Reason))
+}
+
+pub const PRIORITY_FILTER: i32 = 100;
+// Redaction runs strictest shape first: a secret scanned after the address detectors would already be
+// half masked ("postgres://app:[REDACTED]"), loose digit groups are only taken once nothing else claimed them
+pub const PRIORITY_SECRETS: i32 = 480;
+pub const PRIORITY_REDACT: i32 = 500;
+pub const PRIORITY_REDACT_LOOSE: i32 = 520;
+pub const PRIORITY_ENCODE: i32 = 900;
+
+// What a request hook sees. Uri and Headers may be rewritten in place, Body is only present when a
+// module asked for it and holds the whole request body, chunked or not, within REQUEST_BODY_BUFFER_LIMIT.
+pub struct RequestContext<'a> {
+    pub Method: &'a Method,
+    pub Uri: &'a mut Uri,
+    pub Headers: &'a mut HeaderMap,
+    pub ClientAddr: SocketAddr,
+    pub Body: Option<&'a mut Bytes>,
+}
+
+pub enum RequestAction {
+    Allow,
+    // The hook rewrote the request and it should continue with the changes
+    Modify,
+    Block(Response<ResponseBody>),
+    // Require a solved captcha even on routes that skip the gate
+    Challenge,
+}
+
+pub enum BodyAction {
+    Continue,
+    // The headers are already on their way, so the body ends where the blocked content would have been
+    Block(String),
+}
+
+pub const TEXT_CONTENT_TYPES: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "application/x-www-form-urlencoded",
+];
+
+// A missing Content-Type is treated as text, which is how the proxy always handled it
+fn accepts_content_type(Module: &dyn WafModule, ContentType: Option<&str>) -> bool {
+    let ContentTypes = Module.content_types();
+    match ContentType {
+        Some(TypeValue) => {
+            let LowerType = TypeValue.trim().to_lowercase();
+            ContentTypes.iter().any(|Prefix| LowerType.starts_with(Prefix))
+        }
+        None => !ContentTypes.is_empty(),
+    }
+}
+
 #[derive(Clone)]
-pub struct ModuleInfo {
+struct ModuleEntry {
+    Module: Arc<dyn WafModule>,
+    Stats: Arc<ModuleStats>,
+    Priority: i32,
+    Policy: FailurePolicy,
+    Enabled: bool,
+    // A module registered switched off is only initialised the first time it is enabled
+    Initialised: bool,
+}
+
+// An immutable copy of the enabled modules in execution order. A request takes one when it arrives and
+// uses it for every phase, so toggling, reordering or reloading a module only affects later requests.
+pub struct Pipeline {
+    Stages: Vec<ModuleEntry>,
+}
+
+impl Pipeline {
+    fn stages<'a, F>(&'a self, Selection: Option<&'a [String]>, Filter: F) -> impl Iterator<Item = &'a ModuleEntry>
+    where
+        F: Fn(&dyn WafModule) -> bool + 'a,
+    {
+        self.Stages.iter().filter(move |Entry| is_module_selected(Entry.Module.name(), Selection) && Filter(Entry.Module.as_ref()))
+    }
+
+    pub fn includes(&self, Name: &str, Selection: Option<&[String]>) -> bool {
+        self.stages(Selection, |Module| Module.name().eq_ignore_ascii_case(Name)).next().is_some()
+    }
+
+    pub fn has_modules_for(&self, ContentType: Option<&str>, Selection: Option<&[String]>) -> bool {
+        self.stages(Selection, |Module| accepts_content_type(Module, ContentType)).next().is_some()
+    }
+
+    pub fn held_blocks(&self, ContentType: Option<&str>, Selection: Option<&[String]>) -> Vec<(String, String)> {
+        let mut Blocks: Vec<(String, String)> = Vec::new();
+        for Entry in self.stages(Selection, |Module| accepts_content_type(Module, ContentType)) {
+            for (Begin, End) in Entry.Module.held_blocks() {
+                if !Blocks.iter().any(|(Known, _)| Known == Begin) {
+                    Blocks.push((Begin.to_string(), End.to_string()));
+                }
+            }
+        }
+        Blocks
+    }
+
+    pub fn wants_request_body(&self, Selection: Option<&[String]>) -> bool {
+        self.stages(Selection, |Module| Module.handles_requests() && Module.wants_request_body()).next().is_some()
+    }
+
+    // Block ends the chain, a Challenge is remembered while the remaining hooks still get to block.
+    // A fail-closed module that faults rejects the request, a fail-open one is skipped.
+    pub fn process_request(&self, Context: &mut RequestContext, Selection: Option<&[String]>) -> Result<RequestAction, ModuleFault> {
+        let mut Outcome = RequestAction::Allow;
+
+        for Entry in self.stages(Selection, |Module| Module.handles_requests()) {
+            let BodyLength = Context.Body.as_ref().map(|Body| Body.len()).unwrap_or(0);
+            // A fail-open hook that faults halfway through a rewrite is skipped with its changes undone
+            let Original = match Entry.Policy {
+                FailurePolicy::Open => Some((Context.Uri.clone(), Context.Headers.clone())),
+                FailurePolicy::Closed => None,
+            };
+            let Action = match run_guarded(Entry, "request", BodyLength, || Entry.Module.on_request(Context)) {
+                Ok(Action) => Action,
+                Err(Fault) => match Original {
+                    Some((Uri, Headers)) => {
+                        *Context.Uri = Uri;
+                        *Context.Headers = Headers;
+                        continue;
+                    }
+                    None => return Err(Fault),
+                },
+            };
+
+            match Action {
+                RequestAction::Allow => {}
+                RequestAction::Modify => {
+                    if matches!(Outcome, RequestAction::Allow) {
+                        Outcome = RequestAction::Modify;
+                    }
+                }
+                RequestAction::Challenge => Outcome = RequestAction::Challenge,
+                RequestAction::Block(Response) => return Ok(RequestAction::Block(Response)),
+            }
+        }
+
+        Ok(Outcome)
+    }
+
+    pub fn process_response_headers(&self, Status: StatusCode, Headers: &mut HeaderMap, Selection: Option<&[String]>) -> Result<(), ModuleFault> {
+        for Entry in self.stages(Selection, |Module| Module.handles_response_headers()) {
+            if let Err(Fault) = run_guarded(Entry, "response headers", 0, || Entry.Module.on_response_headers(Status, Headers)) {
+                if Entry.Policy == FailurePolicy::Closed {
+                    return Err(Fault);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn process_content(&self, Content: &mut String, ContentType: Option<&str>, Selection: Option<&[String]>) -> Result<(), BodyRejection> {
+        for Entry in self.stages(Selection, |Module| accepts_content_type(Module, ContentType)) {
+            run_body_hook(Entry, Content)?;
+        }
+        Ok(())
+    }
+}
+
+struct ModuleRegistry {
+    Modules: HashMap<String, ModuleEntry>,
+    Order: Vec<String>,
+    OrderError: Option<String>,
+    // Modules being initialised on first enable, out of Modules until init returns
+    Initialising: HashSet<String>,
+    Current: Arc<Pipeline>,
+}
+
+impl ModuleRegistry {
+    fn ordered(&self) -> impl Iterator<Item = &ModuleEntry> {
+        self.Order.iter().filter_map(|Name| self.Modules.get(Name))
+    }
+
+    // Registered name for a case-insensitive lookup
+    fn resolve(&self, Name: &str) -> Result<String, String> {
+        if self.Initialising.iter().any(|Pending| Pending.eq_ignore_ascii_case(Name)) {
+            return Err(format!("module '{}' is initialising, try again", Name));
+        }
+        self.Modules.keys()
+            .find(|Registered| Registered.eq_ignore_ascii_case(Name))
+            .cloned()
+            .ok_or_else(|| format!("unknown module '{}'", Name))
+    }
+
+    // Swapped in whole, requests holding the previous pipeline finish with it
+    fn publish(&mut self) {
+        let Stages = self.ordered().filter(|Entry| Entry.Enabled).cloned().collect();
+        self.Current = Arc::new(Pipeline { Stages });
+    }
+
+    // Kahn's algorithm over the Before/After edges, ties broken by priority and then name so the
+    // order never depends on registration or hashing. Before/After names ignore case like every lookup.
+    fn compute_order(&self) -> Result<Vec<String>, String> {
+        let mut Successors: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut Incoming: HashMap<&str, usize> = self.Modules.keys().map(|Name| (Name.as_str(), 0)).collect();
+        let Registered = |Other: &str| self.Modules.keys().find(|Name| Name.eq_ignore_ascii_case(Other)).map(|Name| Name.as_str());
+
+        for (Name, Entry) in self.Modules.iter() {
+            let Edges = Entry.Module.after().iter().filter_map(|Other| Registered(Other)).map(|Other| (Other, Name.as_str()))
+                .chain(Entry.Module.before().iter().filter_map(|Other| Registered(Other)).map(|Other| (Name.as_str(), Other)));
+
+            for (From, To) in Edges {
+                if From == To {
+                    continue;
+                }
+                Successors.entry(From).or_default().push(To);
+                *Incoming.entry(To).or_default() += 1;
+            }
+        }
+
+        let SortKey = |Name: &str| (self.Modules.get(Name).map(|Entry| Entry.Priority).unwrap_or(0), Name.to_string());
+        let mut Ready: BTreeSet<(i32, String)> = Incoming.iter()
+            .filter(|(_, Count)| **Count == 0)
+            .map(|(Name, _)| SortKey(Name))
+            .collect();
+
+        let mut Order = Vec::with_capacity(self.Modules.len());
+        while let Some(Next) = Ready.pop_first() {
+            let Name = Next.1;
+            if let Some(Followers) = Successors.get(Name.as_str()) {
+                for Follower in Followers.iter() {
+                    if let Some(Count) = Incoming.get_mut(Follower) {
+                        *Count -= 1;
+                        if *Count == 0 {
+                            Ready.insert(SortKey(Follower));
+                        }
+                    }
+                }
+            }
+            Order.push(Name);
+        }
+
+        if Order.len() == self.Modules.len() {
+            return Ok(Order);
+        }
+
+        let mut Stuck: Vec<&str> = self.Modules.keys()
+            .map(|Name| Name.as_str())
+            .filter(|Name| !Order.iter().any(|Placed| Placed == Name))
+            .collect();
+        Stuck.sort();
+        Err(format!("module ordering cycle between {}", Stuck.join(", ")))
+    }
+
+    // A cycle leaves the plain priority order in place and is reported by validate_module_order
+    fn rebuild_order(&mut self) {
+        match self.compute_order() {
+            Ok(Order) => {
+                self.Order = Order;
+                self.OrderError = None;
+            }
+            Err(Error) => {
+                let mut Fallback: Vec<(i32, String)> = self.Modules.iter().map(|(Name, Entry)| (Entry.Priority, Name.clone())).collect();
+                Fallback.sort();
+                self.Order = Fallback.into_iter().map(|(_, Name)| Name).collect();
+                self.OrderError = Some(Error);
+            }
+        }
+        self.publish();
+    }
+}
+
+static _MODULE_REGISTRY: Lazy<Mutex<ModuleRegistry>> = Lazy::new(|| 
+    Mutex::new(ModuleRegistry {
+        Modules: HashMap::with_capacity(16),
+        Order: Vec::new(),
+        OrderError: None,
+        Initialising: HashSet::new(),
+        Current: Arc::new(Pipeline { Stages: Vec::new() }),
+    })
+);
+
+// Hooks never run under the lock, so a poisoned registry only means a panic mid-registration and the
+// maps are still consistent; carrying on beats silently dropping every module from then on
+fn registry() -> MutexGuard<'static, ModuleRegistry> {
+    _MODULE_REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn module_env_key(Name: &str, Setting: &str) -> String {
+    format!("MODULE_{}_{}", Name.to_uppercase().replace('-', "_"), Setting)
+}
+
+pub fn failure_policy_for(Module: &dyn WafModule) -> Result<FailurePolicy, String> {
+    let Key = module_env_key(Module.name(), "ON_FAILURE");
+    match env_opt(&Key) {
+        Some(Value) => Value.parse().map_err(|e| format!("{}: {}", Key, e)),
+        None => Ok(Module.failure_policy()),
+    }
+}
+
+fn prepare_module(Module: &mut dyn WafModule, Init: bool) -> Result<FailurePolicy, String> {
+    let Name = Module.name().to_string();
+    Module.validate_config().map_err(|e| format!("{}: {}", Name, e))?;
+    let Policy = failure_policy_for(Module)?;
+    if Init {
+        init_module(Module)?;
+    }
+    Ok(Policy)
+}
+
+fn init_module(Module: &mut dyn WafModule) -> Result<(), String> {
+    let Name = Module.name().to_string();
+    Module.init().map_err(|e| format!("{} failed to initialise: {}", Name, e))
+}
+
+// Validates and initialises the module before it becomes visible to requests.
+// MODULE_<NAME>_ENABLED=false registers it switched off and leaves init until it is enabled.
+pub fn register_module(Module: Box<dyn WafModule>) -> Result<(), String> {
+    let _ModuleIdentifier = Module.name().to_string();
+    let _Enabled = env_flag(&module_env_key(&_ModuleIdentifier, "ENABLED"), true);
+    let _Priority = env_or(&module_env_key(&_ModuleIdentifier, "PRIORITY"), Module.priority());
+    add_module(Module, _Enabled, _Priority)
+}
+
+fn add_module(mut Module: Box<dyn WafModule>, Enabled: bool, Priority: i32) -> Result<(), String> {
+    let _ModuleIdentifier = Module.name().to_string();
+    if is_module_registered(&_ModuleIdentifier) {
+        return Err(format!("module '{}' is registered twice", _ModuleIdentifier));
+    }
+    
+    let _Policy = prepare_module(Module.as_mut(), Enabled)?;
+    
+    let mut Registry = registry();
+    Registry.Modules.insert(_ModuleIdentifier, ModuleEntry {
+        Module: Arc::from(Module),
+        Stats: Arc::default(),
+        Priority,
+        Policy: _Policy,
+        Enabled,
+        Initialised: Enabled,
+    });
+    Registry.rebuild_order();
+    Ok(())
+}
+
+// Registers with the given failure policy instead of one read from MODULE_<NAME>_ON_FAILURE
+#[cfg(test)]
+pub fn register_with_policy(Module: Box<dyn WafModule>, Policy: FailurePolicy) -> Result<(), String> {
+    let _ModuleIdentifier = Module.name().to_string();
+    add_module(Module, true, 0)?;
+    let mut Registry = registry();
+    if let Some(Entry) = Registry.Modules.get_mut(&_ModuleIdentifier) {
+        Entry.Policy = Policy;
+    }
+    Registry.rebuild_order();
+    Ok(())
+}
+
+// The resolved pipeline, or why it could not be resolved from the declared dependencies
+pub fn validate_module_order() -> Result<Vec<String>, String> {
+    let Registry = registry();
+    match Registry.OrderError.as_ref() {
+        Some(Error) => Err(Error.clone()),
+        None => Ok(Registry.Order.clone()),
+    }
+}
+
+pub struct ModuleStatus {
     pub Name: String,
     pub Version: String,
-    pub ProcessContent: fn(&mut String),
+    pub Priority: i32,
+    pub Enabled: bool,
+    pub Policy: FailurePolicy,
 }
 
-static _MODULE_REGISTRY: Lazy<Mutex<HashMap<String, ModuleInfo>>> = Lazy::new(|| 
-    Mutex::new(HashMap::with_capacity(16))
-);
+// Every registered module in execution order, switched off ones included
+pub fn get_module_status() -> Vec<ModuleStatus> {
+    registry().ordered()
+        .map(|Entry| ModuleStatus {
+            Name: Entry.Module.name().to_string(),
+            Version: Entry.Module.version().to_string(),
+            Priority: Entry.Priority,
+            Enabled: Entry.Enabled,
+            Policy: Entry.Policy,
+        })
+        .collect()
+}
+
+pub fn current_pipeline() -> Arc<Pipeline> {
+    registry().Current.clone()
+}
+
+pub fn set_module_enabled(Name: &str, Enabled: bool) -> Result<(), String> {
+    let (Name, mut Dormant) = {
+        let mut Registry = registry();
+        let Name = Registry.resolve(Name)?;
+        let FirstEnable = Enabled && Registry.Modules.get(&Name).map(|Entry| !Entry.Initialised).unwrap_or(false);
+        if !FirstEnable {
+            if let Some(Entry) = Registry.Modules.get_mut(&Name) {
+                Entry.Enabled = Enabled;
+            }
+            Registry.publish();
+            return Ok(());
+        }
+
+        // Taken out of the registry while init runs so requests never wait on it, the name stays reserved
+        let Dormant = Registry.Modules.remove(&Name).ok_or_else(|| format!("unknown module '{}'", Name))?;
+        Registry.Initialising.insert(Name.clone());
+        (Name, Dormant)
+    };
 
-pub fn register_module(Info: ModuleInfo) {
-    let _ModuleIdentifier = Info.Name.clone();
+    // Never published, so only a passing shutdown walk can hold another reference
+    let Initialised = match Arc::get_mut(&mut Dormant.Module) {
+        Some(Module) => init_module(Module),
+        None => Err(format!("module '{}' is busy, try again", Name)),
+    };
+    if Initialised.is_ok() {
+        Dormant.Initialised = true;
+        Dormant.Enabled = true;
+    }
+
+    let mut Registry = registry();
+    Registry.Initialising.remove(&Name);
+    Registry.Modules.insert(Name, Dormant);
+    Registry.rebuild_order();
+    Initialised
+}
+
+// Before/After constraints still win over the priority, and an order cycle cannot come from a priority
+pub fn set_module_priority(Name: &str, Priority: i32) -> Result<(), String> {
+    let mut Registry = registry();
+    let Name = Registry.resolve(Name)?;
+    if let Some(Entry) = Registry.Modules.get_mut(&Name) {
+        Entry.Priority = Priority;
+    }
+    Registry.rebuild_order();
+    Ok(())
+}
+
+// Swaps a module with its neighbour in the pipeline. Priorities from the swap onwards are raised just
+// enough for the new order to hold, so a run of equal priorities is renumbered instead of jumped.
+pub fn move_module(Name: &str, Earlier: bool) -> Result<(), String> {
+    let mut Registry = registry();
+    let Name = Registry.resolve(Name)?;
+    let Position = Registry.Order.iter().position(|Placed| *Placed == Name).unwrap_or(0);
+    let Neighbour = match if Earlier { Position.checked_sub(1) } else { Some(Position + 1) } {
+        Some(Neighbour) if Neighbour < Registry.Order.len() => Neighbour,
+        _ => return Ok(()),
+    };
+
+    let mut Order = Registry.Order.clone();
+    Order.swap(Position, Neighbour);
+    let Start = Position.min(Neighbour);
+
+    let mut Previous: Option<(i32, String)> = Start.checked_sub(1)
+        .and_then(|Index| Registry.Modules.get(&Order[Index]))
+        .map(|Entry| (Entry.Priority, Entry.Module.name().to_string()));
+    for (Index, Placed) in Order.iter().enumerate().skip(Start) {
+        let Entry = match Registry.Modules.get_mut(Placed) {
+            Some(Entry) => Entry,
+            None => continue,
+        };
+        if let Some((Priority, PreviousName)) = Previous.as_ref() {
+            if (Entry.Priority, Placed) <= (*Priority, PreviousName) {
+                // Ties go by name, so an equal priority is enough when the name already sorts later
+                Entry.Priority = if Placed > PreviousName { *Priority } else { Priority.saturating_add(1) };
+            } else if Index > Start + 1 {
+                break;
+            }
+        }
+        Previous = Some((Entry.Priority, Placed.clone()));
+    }
+
+    Registry.rebuild_order();
+    Ok(())
+}
+
+// Applies the setting overrides and rebuilds the module from configuration. The old instance keeps
+// serving until the new one passed validation and init and its Before/After fit the current order;
+// on failure the overrides are rolled back.
+pub fn reload_module(Name: &str, Settings: &[(String, String)]) -> Result<(), String> {
+    let (Name, Current) = {
+        let Registry = registry();
+        let Name = Registry.resolve(Name)?;
+        let Current = Registry.Modules.get(&Name).map(|Entry| (Entry.Module.clone(), Entry.Initialised));
+        (Name, Current)
+    };
+    let (Current, Initialised) = Current.ok_or_else(|| format!("unknown module '{}'", Name))?;
+    
+    let OwnPrefix = module_env_key(&Name, "");
+    if let Some((Key, _)) = Settings.iter().find(|(Key, _)| !Key.starts_with(&OwnPrefix) && !Current.config_keys().contains(&Key.as_str())) {
+        return Err(format!("{} is not a setting of module '{}'", Key, Name));
+    }
+    
+    let Previous: Vec<(String, Option<String>)> = Settings.iter()
+        .map(|(Key, Value)| (Key.clone(), set_override(Key, Some(Value.clone()))))
+        .collect();
+    let Rollback = || {
+        for (Key, Value) in Previous.iter().rev() {
+            set_override(Key, Value.clone());
+        }
+    };
+    
+    let mut Fresh = match Current.reload() {
+        Some(Fresh) => Fresh,
+        None => {
+            Rollback();
+            return Err(format!("module '{}' can only be configured at startup", Name));
+        }
+    };
+    
+    let Policy = match prepare_module(Fresh.as_mut(), Initialised) {
+        Ok(Policy) => Policy,
+        Err(e) => {
+            Rollback();
+            return Err(e);
+        }
+    };
+    
+    let mut Registry = registry();
+    let Replaced = match Registry.Modules.get_mut(&Name) {
+        Some(Entry) => std::mem::replace(Entry, ModuleEntry { Module: Arc::from(Fresh), Policy, Initialised, ..Entry.clone() }),
+        None => {
+            Rollback();
+            return Err(format!("unknown module '{}'", Name));
+        }
+    };
     
-    if let Ok(mut ModuleMap) = _MODULE_REGISTRY.lock() {
-        ModuleMap.insert(_ModuleIdentifier.clone(), Info);
+    match Registry.compute_order() {
+        Ok(Order) => {
+            Registry.Order = Order;
+            Registry.OrderError = None;
+            Registry.publish();
+            drop(Registry);
+            // Requests still holding the previous pipeline may finish on the old instance after this
+            if Replaced.Initialised {
+                if let Err(e) = Replaced.Module.shutdown() {
+                    eprintln!("Module {} did not shut down cleanly after reload: {}", Name, e);
+                }
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let Rejected = Registry.Modules.insert(Name, Replaced);
+            drop(Registry);
+            Rollback();
+            if let Some(Rejected) = Rejected.filter(|Rejected| Rejected.Initialised) {
+                let _ = Rejected.Module.shutdown();
+            }
+            Err(e)
+        }
     }
 }
 
-pub fn process_content(Content: &mut String) {
-    if let Ok(ModuleMap) = _MODULE_REGISTRY.lock() {
-        for (_ModuleKey, ModuleData) in ModuleMap.iter() {
-            (ModuleData.ProcessContent)(Content);
+// Every hook call is timed and a panic inside it is caught and counted as a fault of that module.
+// Bytes are the body size the hook was handed.
+fn run_guarded<R>(Entry: &ModuleEntry, Phase: &'static str, Bytes: usize, Hook: impl FnOnce() -> R) -> Result<R, ModuleFault> {
+    match Entry.Stats.time(Bytes, || catch_unwind(AssertUnwindSafe(Hook))) {
+        Ok(Result) => Ok(Result),
+        Err(_) => {
+            Entry.Stats.record_fault();
+            Err(ModuleFault { Module: Entry.Module.name().to_string(), Phase })
         }
     }
 }
 
-pub fn print_modules_performance_report() {
-    if let Ok(_ModuleMap) = _MODULE_REGISTRY.lock() {
-        // Performance report functionality without logging
+// Fail-open hands the next module the content exactly as this one received it. A block is not a
+// fault, it ends the body whatever the failure policy.
+fn run_body_hook(Entry: &ModuleEntry, Content: &mut String) -> Result<(), BodyRejection> {
+    let Original = match Entry.Policy {
+        FailurePolicy::Open => Some(Content.clone()),
+        FailurePolicy::Closed => None,
+    };
+
+    match run_guarded(Entry, "body", Content.len(), || Entry.Module.on_body(Content)) {
+        Ok(BodyAction::Continue) => Ok(()),
+        Ok(BodyAction::Block(Reason)) => Err(BodyRejection::Blocked { Module: Entry.Module.name().to_string(), Reason }),
+        Err(Fault) => match Original {
+            Some(Original) => {
+                *Content = Original;
+                Ok(())
+            }
+            None => Err(Fault.into()),
+        },
+    }
+}
+
+// Content generated by the proxy itself goes through every enabled module that handles bodies
+pub fn process_content(Content: &mut String) -> Result<(), BodyRejection> {
+    let Pipeline = current_pipeline();
+    for Entry in Pipeline.stages(None, |Module| !Module.content_types().is_empty()) {
+        run_body_hook(Entry, Content)?;
+    }
+    Ok(())
+}
+
+// None selects every registered module, a route can narrow that down to a list of module names
+pub fn is_module_selected(Name: &str, Selection: Option<&[String]>) -> bool {
+    match Selection {
+        Some(Names) => Names.iter().any(|Selected| Selected.eq_ignore_ascii_case(Name)),
+        None => true,
+    }
+}
+
+// Runs in reverse pipeline order, returns (module, error) for every module that failed to stop cleanly
+pub fn shutdown_modules() -> Vec<(String, String)> {
+    let mut Modules: Vec<Arc<dyn WafModule>> = registry().ordered()
+        .filter(|Entry| Entry.Initialised)
+        .map(|Entry| Entry.Module.clone())
+        .collect();
+    Modules.reverse();
+
+    Modules.iter()
+        .filter_map(|Module| match catch_unwind(AssertUnwindSafe(|| Module.shutdown())) {
+            Ok(Result) => Result.err().map(|e| (Module.name().to_string(), e)),
+            Err(_) => Some((Module.name().to_string(), String::from("panicked during shutdown"))),
+        })
+        .collect()
+}
+
+pub fn is_module_registered(Name: &str) -> bool {
+    let Registry = registry();
+    Registry.Modules.keys().chain(Registry.Initialising.iter()).any(|Registered| Registered.eq_ignore_ascii_case(Name))
+}
+
+// Cumulative figures per module in pipeline order
+pub fn get_module_performance() -> Vec<(String, ModuleTiming)> {
+    registry().ordered()
+        .map(|Entry| (Entry.Module.name().to_string(), Entry.Stats.snapshot()))
+        .collect()
+}
+
+// The dashboard owns the terminal, so the report goes to MODULE_REPORT_PATH and is skipped when unset
+pub fn print_modules_performance_report(TotalRequests: u64, TotalResponses: u64) {
+    let _Path = match env_opt("MODULE_REPORT_PATH") {
+        Some(Path) => Path,
+        None => return,
+    };
+    
+    let _Now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut _Report = format!("module performance at {}, {} requests, {} responses\n", _Now, TotalRequests, TotalResponses);
+    
+    for (Name, Timing) in get_module_performance() {
+        let Percentile = |Quantile| Timing.percentile_micros(Quantile).map(format_micros).unwrap_or_else(|| String::from("-"));
+        _Report.push_str(&format!(
+            "{:<20} calls {:>10} faults {:>6} bytes {:>12} total {:>10} avg {:>8} p50 {:>8} p90 {:>8} p99 {:>8}\n",
+            Name,
+            Timing.Invocations,
+            Timing.Faults,
+            Timing.Bytes,
+            format_micros(Timing.Nanos / 1000),
+            format_micros(Timing.average_micros()),
+            Percentile(0.5),
+            Percentile(0.9),
+            Percentile(0.99),
+        ));
+        
+        let Histogram: Vec<String> = LATENCY_BUCKETS_US.iter().zip(Timing.Buckets.iter())
+            .filter(|(_, Count)| **Count > 0)
+            .map(|(Bound, Count)| match *Bound {
+                u64::MAX => format!("{} {}", format_micros(*Bound), Count),
+                _ => format!("<={} {}", format_micros(*Bound), Count),
+            })
+            .collect();
+        if !Histogram.is_empty() {
+            _Report.push_str(&format!("{:<20} {}\n", "", Histogram.join(" | ")));
+        }
+    }
+    
+    // Replaced in one step so a reader never sees half a report
+    let _TempPath = format!("{}.tmp", _Path);
+    if fs::write(&_TempPath, _Report).is_ok() {
+        let _ = fs::rename(&_TempPath, &_Path);
     }
 }
 
 pub fn get_registered_module_count() -> usize {
-    if let Ok(ModuleMap) = _MODULE_REGISTRY.lock() {
-        return ModuleMap.len();
+    registry().Modules.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static LAZY_INITS: AtomicUsize = AtomicUsize::new(0);
+    static LAZY_INIT_SAW_REGISTRY: AtomicUsize = AtomicUsize::new(0);
+
+    struct LazyModule;
+
+    impl WafModule for LazyModule {
+        fn name(&self) -> &str {
+            "TestLazyModule"
+        }
+
+        fn version(&self) -> &str {
+            "0.0.0"
+        }
+
+        fn priority(&self) -> i32 {
+            0
+        }
+
+        // Takes the registry lock, so it would never return if init ran under it
+        fn init(&mut self) -> Result<(), String> {
+            LAZY_INITS.fetch_add(1, Ordering::SeqCst);
+            if is_module_registered(self.name()) && set_module_enabled(self.name(), true).is_err() {
+                LAZY_INIT_SAW_REGISTRY.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn disabled_modules_are_initialised_when_first_enabled() {
+        add_module(Box::new(LazyModule), false, 0).unwrap();
+        assert_eq!(LAZY_INITS.load(Ordering::SeqCst), 0);
+
+        set_module_enabled("testlazymodule", true).unwrap();
+        set_module_enabled("TestLazyModule", false).unwrap();
+        set_module_enabled("TestLazyModule", true).unwrap();
+        assert_eq!(LAZY_INITS.load(Ordering::SeqCst), 1);
+        // While init ran the name was reserved and a second enable was turned away
+        assert_eq!(LAZY_INIT_SAW_REGISTRY.load(Ordering::SeqCst), 1);
+        assert!(current_pipeline().includes("TestLazyModule", None));
+    }
+
+    static ORDERED_SHUTDOWNS: AtomicUsize = AtomicUsize::new(0);
+
+    struct OrderedModule {
+        _Name: &'static str,
+        _Priority: i32,
+        _Before: &'static [&'static str],
+        _After: &'static [&'static str],
+        _Reloaded: &'static [&'static str],
+    }
+
+    fn ordered(Name: &'static str, Priority: i32) -> Box<OrderedModule> {
+        Box::new(OrderedModule { _Name: Name, _Priority: Priority, _Before: &[], _After: &[], _Reloaded: &[] })
+    }
+
+    impl WafModule for OrderedModule {
+        fn name(&self) -> &str {
+            self._Name
+        }
+
+        fn version(&self) -> &str {
+            "0.0.0"
+        }
+
+        fn priority(&self) -> i32 {
+            self._Priority
+        }
+
+        fn before(&self) -> &[&str] {
+            self._Before
+        }
+
+        fn after(&self) -> &[&str] {
+            self._After
+        }
+
+        fn reload(&self) -> Option<Box<dyn WafModule>> {
+            Some(Box::new(OrderedModule { _After: self._Reloaded, ..*self }))
+        }
+
+        fn config_keys(&self) -> &[&str] {
+            &["TEST_ORDERED_SETTING"]
+        }
+
+        fn shutdown(&self) -> Result<(), String> {
+            if self._Name == "TestReloadShutdown" {
+                ORDERED_SHUTDOWNS.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    fn position(Order: &[String], Name: &str) -> usize {
+        Order.iter().position(|Placed| Placed == Name).unwrap()
+    }
+
+    fn entry(Module: Box<dyn WafModule>, Policy: FailurePolicy) -> ModuleEntry {
+        ModuleEntry { Priority: Module.priority(), Module: Arc::from(Module), Stats: Arc::default(), Policy, Enabled: true, Initialised: true }
+    }
+
+    // A registry of its own, so the modules other tests registered do not take part
+    fn registry_of(Modules: Vec<Box<dyn WafModule>>) -> ModuleRegistry {
+        let mut Registry = ModuleRegistry {
+            Modules: HashMap::new(),
+            Order: Vec::new(),
+            OrderError: None,
+            Initialising: HashSet::new(),
+            Current: Arc::new(Pipeline { Stages: Vec::new() }),
+        };
+        for Module in Modules {
+            let Entry = entry(Module, FailurePolicy::Open);
+            Registry.Modules.insert(Entry.Module.name().to_string(), Entry);
+        }
+        Registry.rebuild_order();
+        Registry
+    }
+
+    #[test]
+    fn priority_and_then_name_order_independent_modules() {
+        let Registry = registry_of(vec![ordered("B", 10), ordered("A", 10), ordered("C", 5), ordered("D", -1)]);
+        assert_eq!(Registry.Order, ["D", "C", "A", "B"]);
+        assert!(Registry.OrderError.is_none());
     }
-    0
-}
\ No newline at end of file
+
+    #[test]
+    fn dependencies_win_over_priorities() {
+        let Registry = registry_of(vec![
+            Box::new(OrderedModule { _After: &["y", "NotRegistered"], ..*ordered("X", 0) }),
+            ordered("Y", 100),
+            Box::new(OrderedModule { _Before: &["x"], ..*ordered("Z", 50) }),
+            ordered("W", 75),
+        ]);
+        assert_eq!(Registry.Order, ["Z", "W", "Y", "X"]);
+    }
+
+    #[test]
+    fn a_cycle_is_reported_and_leaves_the_priority_order() {
+        let Registry = registry_of(vec![
+            Box::new(OrderedModule { _After: &["Q"], ..*ordered("P", 1) }),
+            Box::new(OrderedModule { _After: &["P"], ..*ordered("Q", 2) }),
+            ordered("R", 3),
+        ]);
+        assert_eq!(Registry.OrderError.as_deref(), Some("module ordering cycle between P, Q"));
+        assert_eq!(Registry.Order, ["P", "Q", "R"]);
+        assert_eq!(Registry.Current.Stages.len(), 3);
+    }
+
+    #[test]
+    fn reload_creating_an_order_cycle_keeps_the_old_instance() {
+        add_module(Box::new(OrderedModule { _Reloaded: &["testordersecond"], ..*ordered("TestOrderFirst", 0) }), true, 0).unwrap();
+        add_module(Box::new(OrderedModule { _After: &["TESTORDERFIRST"], ..*ordered("TestOrderSecond", 0) }), true, 0).unwrap();
+        let Before = validate_module_order().unwrap();
+        assert!(position(&Before, "TestOrderFirst") < position(&Before, "TestOrderSecond"));
+
+        let Error = reload_module("TestOrderFirst", &[]).unwrap_err();
+        assert!(Error.contains("cycle"), "{}", Error);
+        assert_eq!(validate_module_order().unwrap(), Before);
+        assert!(registry().Modules["TestOrderFirst"].Module.after().is_empty());
+
+        reload_module("TestOrderSecond", &[]).unwrap();
+        reload_module("TestOrderFirst", &[]).unwrap();
+        let After = validate_module_order().unwrap();
+        assert!(position(&After, "TestOrderSecond") < position(&After, "TestOrderFirst"));
+    }
+
+    #[test]
+    fn moving_swaps_with_the_neighbour_inside_a_run_of_equal_priorities() {
+        for Name in ["TestMoveA", "TestMoveB", "TestMoveC"] {
+            add_module(ordered(Name, 7000), true, 7000).unwrap();
+        }
+        let Run = || {
+            let Order = validate_module_order().unwrap();
+            let Start = position(&Order, "TestMoveA").min(position(&Order, "TestMoveB")).min(position(&Order, "TestMoveC"));
+            Order[Start..Start + 3].to_vec()
+        };
+        assert_eq!(Run(), ["TestMoveA", "TestMoveB", "TestMoveC"]);
+
+        move_module("TestMoveC", true).unwrap();
+        assert_eq!(Run(), ["TestMoveA", "TestMoveC", "TestMoveB"]);
+        move_module("TestMoveA", false).unwrap();
+        assert_eq!(Run(), ["TestMoveC", "TestMoveA", "TestMoveB"]);
+        move_module("TestMoveB", true).unwrap();
+        assert_eq!(Run(), ["TestMoveC", "TestMoveB", "TestMoveA"]);
+    }
+
+    #[test]
+    fn reload_shuts_the_replaced_instance_down_and_only_takes_its_own_settings() {
+        add_module(ordered("TestReloadShutdown", 0), true, 0).unwrap();
+
+        let Error = reload_module("TestReloadShutdown", &[(String::from("REDACT_KEYS"), String::from("k:x"))]).unwrap_err();
+        assert!(Error.contains("REDACT_KEYS"), "{}", Error);
+        assert_eq!(ORDERED_SHUTDOWNS.load(Ordering::SeqCst), 0);
+
+        let Settings = [
+            (String::from("MODULE_TESTRELOADSHUTDOWN_LEVEL"), String::from("2")),
+            (String::from("TEST_ORDERED_SETTING"), String::from("on")),
+        ];
+        reload_module("TestReloadShutdown", &Settings).unwrap();
+        assert_eq!(ORDERED_SHUTDOWNS.load(Ordering::SeqCst), 1);
+    }
+
+    // Rewrites the request halfway and then panics
+    struct HalfRewrite;
+
+    impl WafModule for HalfRewrite {
+        fn name(&self) -> &str {
+            "TestHalfRewrite"
+        }
+
+        fn version(&self) -> &str {
+            "0.0.0"
+        }
+
+        fn priority(&self) -> i32 {
+            0
+        }
+
+        fn handles_requests(&self) -> bool {
+            true
+        }
+
+        fn on_request(&self, Context: &mut RequestContext) -> RequestAction {
+            *Context.Uri = Uri::from_static("/rewritten");
+            Context.Headers.remove("x-keep");
+            Context.Headers.insert("x-added", hyper::header::HeaderValue::from_static("1"));
+            panic!("rewrite failed halfway");
+        }
+    }
+
+    #[test]
+    fn a_fail_open_request_hook_that_faults_leaves_the_request_untouched() {
+        add_module(Box::new(HalfRewrite), true, 0).unwrap();
+        let mut _Uri = Uri::from_static("/original?q=1");
+        let mut Headers = HeaderMap::new();
+        Headers.insert("x-keep", hyper::header::HeaderValue::from_static("yes"));
+        let mut Context = RequestContext { Method: &Method::GET, Uri: &mut _Uri, Headers: &mut Headers, ClientAddr: "127.0.0.1:4000".parse().unwrap(), Body: None };
+
+        let Selection = [String::from("TestHalfRewrite")];
+        let Action = current_pipeline().process_request(&mut Context, Some(&Selection)).ok().unwrap();
+        assert!(matches!(Action, RequestAction::Allow));
+        assert_eq!(_Uri, "/original?q=1");
+        assert_eq!(Headers.get("x-keep").unwrap(), "yes");
+        assert!(!Headers.contains_key("x-added"));
+    }
+
+    // Appends its name to every body it sees, or rewrites it and panics when Faulty
+    struct TaggingModule {
+        _Name: &'static str,
+        _ContentTypes: &'static [&'static str],
+        _Faulty: bool,
+    }
+
+    fn tagging(Name: &'static str, ContentTypes: &'static [&'static str]) -> Box<dyn WafModule> {
+        Box::new(TaggingModule { _Name: Name, _ContentTypes: ContentTypes, _Faulty: false })
+    }
+
+    impl WafModule for TaggingModule {
+        fn name(&self) -> &str {
+            self._Name
+        }
+
+        fn version(&self) -> &str {
+            "0.0.0"
+        }
+
+        fn priority(&self) -> i32 {
+            0
+        }
+
+        fn content_types(&self) -> &[&str] {
+            self._ContentTypes
+        }
+
+        fn on_body(&self, Content: &mut String) -> BodyAction {
+            Content.push_str(&format!("[{}]", self._Name));
+            if self._Faulty {
+                panic!("body hook failed after rewriting");
+            }
+            BodyAction::Continue
+        }
+    }
+
+    #[test]
+    fn the_content_type_picks_the_modules_a_body_goes_through() {
+        let Pipeline = Pipeline { Stages: vec![
+            entry(tagging("Json", &["application/json"]), FailurePolicy::Open),
+            entry(tagging("Text", TEXT_CONTENT_TYPES), FailurePolicy::Open),
+            entry(tagging("Headers", &[]), FailurePolicy::Open),
+        ] };
+        let Tags = |ContentType: Option<&str>| {
+            let mut Content = String::new();
+            Pipeline.process_content(&mut Content, ContentType, None).ok().unwrap();
+            Content
+        };
+
+        assert_eq!(Tags(Some("Application/JSON; charset=utf-8")), "[Json][Text]");
+        assert_eq!(Tags(Some("text/html")), "[Text]");
+        assert_eq!(Tags(Some("image/png")), "");
+        assert_eq!(Tags(None), "[Json][Text]");
+        assert!(!Pipeline.has_modules_for(Some("image/png"), None));
+
+        let Selection = [String::from("json")];
+        let mut Content = String::new();
+        Pipeline.process_content(&mut Content, Some("application/json"), Some(&Selection)).ok().unwrap();
+        assert_eq!(Content, "[Json]");
+    }
+
+    #[test]
+    fn a_faulting_body_hook_is_undone_when_open_and_ends_the_body_when_closed() {
+        let Faulty = |Policy| entry(Box::new(TaggingModule { _Name: "Faulty", _ContentTypes: TEXT_CONTENT_TYPES, _Faulty: true }), Policy);
+
+        let Open = Pipeline { Stages: vec![Faulty(FailurePolicy::Open), entry(tagging("After", TEXT_CONTENT_TYPES), FailurePolicy::Open)] };
+        let mut Content = String::from("body");
+        Open.process_content(&mut Content, Some("text/plain"), None).ok().unwrap();
+        assert_eq!(Content, "body[After]");
+        assert_eq!(Open.Stages[0].Stats.snapshot().Faults, 1);
+
+        let Closed = Pipeline { Stages: vec![Faulty(FailurePolicy::Closed), entry(tagging("After", TEXT_CONTENT_TYPES), FailurePolicy::Open)] };
+        let mut Content = String::from("body");
+        match Closed.process_content(&mut Content, Some("text/plain"), None) {
+            Err(BodyRejection::Fault(Fault)) => assert_eq!((Fault.Module.as_str(), Fault.Phase), ("Faulty", "body")),
+            _ => panic!("the fault did not end the body"),
+        }
+        assert!(!Content.contains("[After]"));
+    }
+}
@@ -0,0 +1,158 @@
+#![allow(non_snake_case)]
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the latency buckets in microseconds, anything slower lands in the last one
+pub const LATENCY_BUCKETS_US: [u64; 12] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, u64::MAX];
+
+// Counters a module's hooks feed on every call. Plain atomics so timing never takes a lock.
+#[derive(Default)]
+pub struct ModuleStats {
+    Invocations: AtomicU64,
+    Nanos: AtomicU64,
+    Bytes: AtomicU64,
+    Faults: AtomicU64,
+    Buckets: [AtomicU64; LATENCY_BUCKETS_US.len()],
+}
+
+impl ModuleStats {
+    pub fn time<R>(&self, Bytes: usize, Hook: impl FnOnce() -> R) -> R {
+        let Started = Instant::now();
+        let Result = Hook();
+        self.record(Started.elapsed(), Bytes);
+        Result
+    }
+
+    fn record(&self, Elapsed: Duration, Bytes: usize) {
+        let Micros = Elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let Bucket = LATENCY_BUCKETS_US.iter().position(|Bound| Micros <= *Bound).unwrap_or(LATENCY_BUCKETS_US.len() - 1);
+
+        self.Invocations.fetch_add(1, Ordering::Relaxed);
+        self.Nanos.fetch_add(Elapsed.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+        self.Bytes.fetch_add(Bytes as u64, Ordering::Relaxed);
+        self.Buckets[Bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fault(&self) {
+        self.Faults.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ModuleTiming {
+        ModuleTiming {
+            Invocations: self.Invocations.load(Ordering::Relaxed),
+            Nanos: self.Nanos.load(Ordering::Relaxed),
+            Bytes: self.Bytes.load(Ordering::Relaxed),
+            Faults: self.Faults.load(Ordering::Relaxed),
+            Buckets: std::array::from_fn(|Index| self.Buckets[Index].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// A point-in-time copy of ModuleStats, or the difference between two of them
+#[derive(Clone, Copy, Default)]
+pub struct ModuleTiming {
+    pub Invocations: u64,
+    pub Nanos: u64,
+    pub Bytes: u64,
+    // Hook calls that panicked, they are still counted and timed above
+    pub Faults: u64,
+    pub Buckets: [u64; LATENCY_BUCKETS_US.len()],
+}
+
+impl ModuleTiming {
+    pub fn since(&self, Earlier: &ModuleTiming) -> ModuleTiming {
+        ModuleTiming {
+            Invocations: self.Invocations.saturating_sub(Earlier.Invocations),
+            Nanos: self.Nanos.saturating_sub(Earlier.Nanos),
+            Bytes: self.Bytes.saturating_sub(Earlier.Bytes),
+            Faults: self.Faults.saturating_sub(Earlier.Faults),
+            Buckets: std::array::from_fn(|Index| self.Buckets[Index].saturating_sub(Earlier.Buckets[Index])),
+        }
+    }
+
+    pub fn average_micros(&self) -> u64 {
+        if self.Invocations == 0 {
+            return 0;
+        }
+        self.Nanos / self.Invocations / 1000
+    }
+
+    // Upper bound of the bucket the quantile falls into, None when nothing was recorded
+    pub fn percentile_micros(&self, Quantile: f64) -> Option<u64> {
+        if self.Invocations == 0 {
+            return None;
+        }
+
+        let Target = ((self.Invocations as f64 * Quantile).ceil() as u64).max(1);
+        let mut Seen = 0;
+        for (Index, Count) in self.Buckets.iter().enumerate() {
+            Seen += Count;
+            if Seen >= Target {
+                return Some(LATENCY_BUCKETS_US[Index]);
+            }
+        }
+        LATENCY_BUCKETS_US.last().copied()
+    }
+}
+
+pub fn format_micros(Micros: u64) -> String {
+    if Micros == u64::MAX {
+        format!(">{}ms", LATENCY_BUCKETS_US[LATENCY_BUCKETS_US.len() - 2] / 1000)
+    } else if Micros >= 1000 {
+        format!("{:.1}ms", Micros as f64 / 1000.0)
+    } else {
+        format!("{}us", Micros)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_land_in_the_bucket_of_their_latency() {
+        let Stats = ModuleStats::default();
+        for Micros in [5, 10, 11, 400, 2_000, 60_000] {
+            Stats.record(Duration::from_micros(Micros), 100);
+        }
+        let Timing = Stats.snapshot();
+        assert_eq!(Timing.Invocations, 6);
+        assert_eq!(Timing.Bytes, 600);
+        assert_eq!(Timing.Buckets, [2, 1, 0, 0, 0, 1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(Timing.average_micros(), 62_426 / 6);
+    }
+
+    #[test]
+    fn percentiles_report_the_bucket_bound() {
+        let Timing = ModuleTiming { Invocations: 100, Buckets: [90, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, 1], ..Default::default() };
+        assert_eq!(Timing.percentile_micros(0.5), Some(10));
+        assert_eq!(Timing.percentile_micros(0.95), Some(100));
+        assert_eq!(Timing.percentile_micros(0.99), Some(100));
+        assert_eq!(Timing.percentile_micros(1.0), Some(u64::MAX));
+        assert_eq!(ModuleTiming::default().percentile_micros(0.5), None);
+    }
+
+    #[test]
+    fn a_window_is_the_difference_of_two_snapshots() {
+        let Stats = ModuleStats::default();
+        Stats.record(Duration::from_micros(20), 1);
+        let Earlier = Stats.snapshot();
+        Stats.record(Duration::from_micros(3_000), 2);
+        Stats.record(Duration::from_micros(3_000), 2);
+
+        let Window = Stats.snapshot().since(&Earlier);
+        assert_eq!((Window.Invocations, Window.Bytes, Window.average_micros()), (2, 4, 3_000));
+        assert_eq!(Window.Buckets[1], 0);
+        assert_eq!(Window.Buckets[8], 2);
+        // A reset counter never produces a negative window
+        assert_eq!(Earlier.since(&Stats.snapshot()).Invocations, 0);
+    }
+
+    #[test]
+    fn latencies_are_formatted_for_the_dashboard() {
+        assert_eq!(format_micros(250), "250us");
+        assert_eq!(format_micros(2_500), "2.5ms");
+        assert_eq!(format_micros(u64::MAX), ">50ms");
+    }
+}
@@ -5,15 +5,14 @@ use brotli::CompressorWriter;
 use std::io::Write;
 use bytes::Bytes;
 
-use crate::module::{ModuleInfo, register_module, process_content};
+use crate::module::{register_module, process_content, BodyRejection, WafModule, PRIORITY_ENCODE};
 use hyper::{HeaderMap, header};
 
 pub const MODULE_NAME: &str = "BrotliCompressor";
 pub const MODULE_VERSION: &str = "1.0.0";
 
 thread_local! {
-    static _COMPRESSED_CONTENT: std::cell::RefCell<Option<Vec<u8>>> = std::cell::RefCell::new(None);
-    static _ORIGINAL_CONTENT_TYPE: std::cell::RefCell<Option<String>> = std::cell::RefCell::new(None);
+    static _ORIGINAL_CONTENT_TYPE: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
 }
 
 pub struct BrotliCompressor {
@@ -46,11 +45,11 @@ impl BrotliCompressor {
                 22
             );
             
-            if let Err(_) = Compressor.write_all(OriginalBytes) {
+            if Compressor.write_all(OriginalBytes).is_err() {
                 return Vec::new();
             }
             
-            if let Err(_) = Compressor.flush() {
+            if Compressor.flush().is_err() {
                 return Vec::new();
             }
         }
@@ -76,9 +75,7 @@ impl BrotliCompressor {
                 22
             );
             
-            if Compressor.write_all(InputBytes).is_err() {
-                Success = false;
-            } else if Compressor.flush().is_err() {
+            if Compressor.write_all(InputBytes).is_err() || Compressor.flush().is_err() {
                 Success = false;
             }
         }
@@ -91,6 +88,31 @@ impl BrotliCompressor {
     }
 }
 
+pub struct BrotliStream {
+    _Compressor: CompressorWriter<Vec<u8>>,
+}
+
+impl BrotliStream {
+    pub fn new(CompressionLevel: u32) -> Self {
+        let Level = if CompressionLevel > 11 { 11 } else { CompressionLevel };
+        BrotliStream {
+            _Compressor: CompressorWriter::new(Vec::new(), 4096, Level, 22),
+        }
+    }
+
+    // Flushes after every chunk so streamed responses reach the client without waiting for the end
+    pub fn push(&mut self, InputBytes: &[u8]) -> Vec<u8> {
+        if self._Compressor.write_all(InputBytes).is_err() || self._Compressor.flush().is_err() {
+            return Vec::new();
+        }
+        std::mem::take(self._Compressor.get_mut())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self._Compressor.into_inner()
+    }
+}
+
 pub fn is_compressible_content(ContentType: &str) -> bool {
     let LowerType = ContentType.to_lowercase();
     
@@ -103,61 +125,49 @@ pub fn is_compressible_content(ContentType: &str) -> bool {
     LowerType.contains("application/x-www-form-urlencoded")
 }
 
-pub fn compress_content(Content: &mut String) -> Bytes {
-    let Compressor = BrotliCompressor::new(4);
-    let CompressedData = Compressor.compress(Content, None);
-    Bytes::from(CompressedData)
-}
-
-pub fn compress_content_with_type(Content: &mut String, ContentType: &str) -> Bytes {
-    let Compressor = BrotliCompressor::new(4);
-    let CompressedData = Compressor.compress(Content, Some(ContentType));
-    Bytes::from(CompressedData)
-}
-
-pub fn compress_bytes_with_type(Data: &[u8], ContentType: &str) -> Bytes {
+// Text runs through the module pipeline first, a fail-closed module fault or a block means nothing may be served
+pub fn compress_bytes_with_type(Data: &[u8], ContentType: &str) -> Result<Bytes, BodyRejection> {
     if let Ok(TextContent) = std::str::from_utf8(Data) {
         let mut ContentString = TextContent.to_string();
         
-        process_content(&mut ContentString);
+        process_content(&mut ContentString)?;
         
         if !is_compressible_content(ContentType) {
-            return Bytes::from(ContentString);
+            return Ok(Bytes::from(ContentString));
         }
         
         let Compressor = BrotliCompressor::new(4);
         let CompressedData = Compressor.compress(&mut ContentString, Some(ContentType));
-        return Bytes::from(CompressedData);
+        Ok(Bytes::from(CompressedData))
     } else {
         if !is_compressible_content(ContentType) {
-            return Bytes::from(Data.to_vec());
+            return Ok(Bytes::from(Data.to_vec()));
         }
         
         let Compressor = BrotliCompressor::new(4);
         let CompressedData = Compressor.compress_bytes(Data, Some(ContentType));
-        Bytes::from(CompressedData)
+        Ok(Bytes::from(CompressedData))
     }
 }
 
-pub fn register() {
-    register_module(ModuleInfo {
-        Name: MODULE_NAME.to_string(),
-        Version: MODULE_VERSION.to_string(),
-        ProcessContent: process_content_brotli,
-    });
-}
+// Has no body hook of its own: being in a pipeline is what switches compression on, and the response
+// stream encodes with BrotliStream after every content module has run
+impl WafModule for BrotliCompressor {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
 
-fn process_content_brotli(Content: &mut String) {
-    let CompressedBytes = compress_content(Content);
-    _COMPRESSED_CONTENT.with(|Cell| {
-        *Cell.borrow_mut() = Some(CompressedBytes.to_vec());
-    });
+    fn priority(&self) -> i32 {
+        PRIORITY_ENCODE
+    }
 }
 
-pub fn get_compressed_content() -> Option<Bytes> {
-    _COMPRESSED_CONTENT.with(|Cell| {
-        Cell.borrow_mut().take().map(Bytes::from)
-    })
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(BrotliCompressor::new(4)))
 }
 
 pub fn get_original_content_type() -> Option<String> {
@@ -170,15 +180,7 @@ pub fn update_headers_for_brotli(Headers: &mut HeaderMap) -> bool {
     let HasCompressedContent = _ORIGINAL_CONTENT_TYPE.with(|Cell| Cell.borrow().is_some());
     
     if HasCompressedContent {
-        Headers.insert(
-            header::CONTENT_ENCODING,
-            header::HeaderValue::from_static("br")
-        );
-        
-        Headers.insert(
-            header::VARY,
-            header::HeaderValue::from_static("Accept-Encoding")
-        );
+        apply_brotli_headers(Headers);
         
         if let Some(OriginalType) = get_original_content_type() {
             if let Ok(TypeValue) = header::HeaderValue::from_str(&OriginalType) {
@@ -189,3 +191,15 @@ pub fn update_headers_for_brotli(Headers: &mut HeaderMap) -> bool {
     
     HasCompressedContent
 }
+
+pub fn apply_brotli_headers(Headers: &mut HeaderMap) {
+    Headers.insert(
+        header::CONTENT_ENCODING,
+        header::HeaderValue::from_static("br")
+    );
+    
+    Headers.insert(
+        header::VARY,
+        header::HeaderValue::from_static("Accept-Encoding")
+    );
+}
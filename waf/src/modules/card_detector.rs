@@ -0,0 +1,202 @@
+#![allow(non_snake_case)]
+
+use regex::Regex;
+
+use crate::config::env_opt;
+use crate::module::{register_module, BodyAction, FailurePolicy, WafModule, PRIORITY_REDACT, TEXT_CONTENT_TYPES};
+use crate::modules::email_detector;
+use crate::modules::redaction::{is_whole_token, replace_spans, TokenBounds, DEFAULT_MASK};
+
+pub const MODULE_NAME: &str = "CardDetector";
+pub const MODULE_VERSION: &str = "1.0.0";
+
+pub struct CardDetector {
+    _Pattern: Regex,
+    _Token: String,
+}
+
+// Runs of digits, optionally grouped by single spaces or hyphens
+const CARD_PATTERN: &str = r"[0-9]+(?:[ \-][0-9]+)*";
+
+pub struct CardMatch {
+    pub Start: usize,
+    pub End: usize,
+}
+
+const CARD_BOUNDS: TokenBounds = TokenBounds { Leading: b".,", Trailing: b".,", Continues: |Byte| Byte.is_ascii_digit() };
+
+fn passes_luhn(Digits: &[u8]) -> bool {
+    let Sum: u32 = Digits.iter().rev().enumerate()
+        .map(|(Index, Digit)| {
+            let Value = (Digit - b'0') as u32;
+            match Index % 2 {
+                1 if Value * 2 > 9 => Value * 2 - 9,
+                1 => Value * 2,
+                _ => Value,
+            }
+        })
+        .sum();
+    Sum.is_multiple_of(10)
+}
+
+// Issuer prefix and length of the major schemes, so a Luhn-valid order number or random ID that no
+// network could have issued is left alone
+fn has_issuer_layout(Digits: &[u8]) -> bool {
+    let Prefix = |Count: usize| Digits[..Count].iter().fold(0u32, |Acc, Digit| Acc * 10 + (Digit - b'0') as u32);
+    let Length = Digits.len();
+    match Digits[0] {
+        b'4' => matches!(Length, 13 | 16 | 19),
+        b'2' => (2221..=2720).contains(&Prefix(4)) && Length == 16,
+        b'5' => match Prefix(2) {
+            51..=55 => Length == 16,
+            50 | 56..=59 => (13..=19).contains(&Length),
+            _ => false,
+        },
+        b'3' => match Prefix(2) {
+            34 | 37 => Length == 15,
+            35 => (3528..=3589).contains(&Prefix(4)) && (16..=19).contains(&Length),
+            30 | 36 | 38 | 39 => (14..=19).contains(&Length),
+            _ => false,
+        },
+        b'6' => (16..=19).contains(&Length),
+        _ => false,
+    }
+}
+
+fn is_card_number(Digits: &[u8]) -> bool {
+    (13..=19).contains(&Digits.len()) && has_issuer_layout(Digits) && passes_luhn(Digits)
+}
+
+// Byte ranges of the digit groups in a candidate like "4111 1111 1111 1111"
+fn digit_groups(Candidate: &str) -> Vec<(usize, usize)> {
+    let mut Groups = Vec::new();
+    let mut Start = None;
+    for (Index, Byte) in Candidate.bytes().enumerate() {
+        match (Byte.is_ascii_digit(), Start) {
+            (true, None) => Start = Some(Index),
+            (false, Some(Begin)) => {
+                Groups.push((Begin, Index));
+                Start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(Begin) = Start {
+        Groups.push((Begin, Candidate.len()));
+    }
+    Groups
+}
+
+// Longest runs of whole groups that are card numbers. Grouped numbers have to use one separator
+// throughout, which keeps "2024-01-01 4111..." style neighbours from being glued on.
+fn find_in_candidate(Content: &str, Offset: usize, Candidate: &str, Found: &mut Vec<CardMatch>) {
+    let Bytes = Candidate.as_bytes();
+    let Groups = digit_groups(Candidate);
+    let mut First = 0;
+    while First < Groups.len() {
+        let mut Longest = None;
+        let mut Digits: Vec<u8> = Vec::with_capacity(19);
+        for Last in First..Groups.len() {
+            let (Start, End) = (Groups[First].0, Groups[Last].1);
+            let MixedSeparators = Last > First && Bytes[Groups[Last].0 - 1] != Bytes[Groups[First].1];
+            Digits.extend_from_slice(&Bytes[Groups[Last].0..End]);
+            if Digits.len() > 19 || MixedSeparators {
+                break;
+            }
+            if is_card_number(&Digits) && is_whole_token(Content, Offset + Start, Offset + End, &CARD_BOUNDS) {
+                Longest = Some((Last, CardMatch { Start: Offset + Start, End: Offset + End }));
+            }
+        }
+
+        match Longest {
+            Some((Last, Match)) => {
+                Found.push(Match);
+                First = Last + 1;
+            }
+            None => First += 1,
+        }
+    }
+}
+
+impl CardDetector {
+    pub fn new() -> Self {
+        CardDetector {
+            _Pattern: Regex::new(CARD_PATTERN).unwrap(),
+            _Token: String::from(DEFAULT_MASK),
+        }
+    }
+
+    pub fn find_card_numbers(&self, Content: &str) -> Vec<CardMatch> {
+        let mut Found = Vec::new();
+        for Candidate in self._Pattern.find_iter(Content).filter(|Candidate| Candidate.len() >= 13) {
+            find_in_candidate(Content, Candidate.start(), Candidate.as_str(), &mut Found);
+        }
+        Found
+    }
+
+    pub fn redact_card_numbers(&self, Content: &mut String) {
+        let Found = self.find_card_numbers(Content);
+        replace_spans(Content, Found.iter().map(|Match| (Match.Start..Match.End, &self._Token)));
+    }
+}
+
+impl WafModule for CardDetector {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        PRIORITY_REDACT
+    }
+
+    // Digits in the local part of an address belong to the email detector
+    fn after(&self) -> &[&str] {
+        &[email_detector::MODULE_NAME]
+    }
+
+    fn content_types(&self) -> &[&str] {
+        TEXT_CONTENT_TYPES
+    }
+
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+
+    // CARD_REDACT_TOKEN replaces each card number, [REDACTED] unless set
+    fn init(&mut self) -> Result<(), String> {
+        self._Token = env_opt("CARD_REDACT_TOKEN").unwrap_or_else(|| String::from(DEFAULT_MASK));
+        Ok(())
+    }
+
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(CardDetector::new()))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        &["CARD_REDACT_TOKEN"]
+    }
+
+    fn on_body(&self, Content: &mut String) -> BodyAction {
+        self.redact_card_numbers(Content);
+        BodyAction::Continue
+    }
+}
+
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(CardDetector::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::corpus;
+
+    #[test]
+    fn card_corpus() {
+        corpus::check("card_detection.txt", &[&CardDetector::new()]);
+    }
+}
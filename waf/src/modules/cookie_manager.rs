@@ -2,12 +2,17 @@
 #![allow(non_snake_case)]
 
 use std::collections::HashMap;
+use std::fs;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use lazy_static::lazy_static;
 use sha2::{Sha256, Digest};
+use tokio::runtime::Handle;
 use uuid::Uuid;
 
+use crate::config::env_opt;
+use crate::module::{register_module, WafModule};
+
 lazy_static! {
     static ref COOKIE_STORAGE: Arc<Mutex<CookieStorage>> = Arc::new(Mutex::new(CookieStorage::new()));
 }
@@ -65,6 +70,78 @@ pub fn store_cookie(Cookie: String) {
     _Storage.add_cookie(Cookie);
 }
 
+// COOKIE_STORE_PATH keeps issued cookies across restarts, one "<cookie> <unix issue time>" per line
+fn cookie_store_path() -> Option<String> {
+    env_opt("COOKIE_STORE_PATH")
+}
+
+fn load_cookie_store() -> Result<usize, String> {
+    let _Path = match cookie_store_path() {
+        Some(Path) => Path,
+        None => return Ok(0),
+    };
+    
+    let _Contents = match fs::read_to_string(&_Path) {
+        Ok(Contents) => Contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("{}: {}", _Path, e)),
+    };
+    
+    let _Now = SystemTime::now();
+    let mut _Storage = COOKIE_STORAGE.lock().unwrap();
+    let mut _Loaded = 0;
+    
+    for _Line in _Contents.lines() {
+        let mut _Fields = _Line.split_whitespace();
+        let (Some(Cookie), Some(Issued)) = (_Fields.next(), _Fields.next()) else {
+            continue;
+        };
+        let Issued = match Issued.parse::<u64>() {
+            Ok(Issued) => UNIX_EPOCH + Duration::from_secs(Issued),
+            Err(_) => continue,
+        };
+        if !is_valid_format(Cookie) {
+            continue;
+        }
+        
+        // Instants cannot be persisted, so the remaining lifetime is carried over through the wall clock
+        let _Age = _Now.duration_since(Issued).unwrap_or_default();
+        if _Age >= _Storage.ExpirationTime {
+            continue;
+        }
+        if let Some(Created) = Instant::now().checked_sub(_Age) {
+            _Storage.Cookies.insert(Cookie.to_string(), Created);
+            _Loaded += 1;
+        }
+    }
+    
+    Ok(_Loaded)
+}
+
+pub fn flush_cookie_store() -> Result<usize, String> {
+    let _Path = match cookie_store_path() {
+        Some(Path) => Path,
+        None => return Ok(0),
+    };
+    
+    let _Now = SystemTime::now();
+    let mut _Storage = COOKIE_STORAGE.lock().unwrap();
+    _Storage.cleanup_expired();
+    
+    let mut _Contents = String::new();
+    for (Cookie, Created) in _Storage.Cookies.iter() {
+        let _Issued = (_Now - Created.elapsed()).duration_since(UNIX_EPOCH).unwrap_or_default();
+        _Contents.push_str(&format!("{} {}\n", Cookie, _Issued.as_secs()));
+    }
+    
+    // Written beside the target and renamed so a crash mid-write never leaves a truncated store
+    let _TempPath = format!("{}.tmp", _Path);
+    fs::write(&_TempPath, _Contents).map_err(|e| format!("{}: {}", _TempPath, e))?;
+    fs::rename(&_TempPath, &_Path).map_err(|e| format!("{}: {}", _Path, e))?;
+    
+    Ok(_Storage.Cookies.len())
+}
+
 pub fn is_valid_format(Cookie: &str) -> bool {
     Cookie.len() == 64 && Cookie.chars().all(|c| c.is_ascii_hexdigit())
 }
@@ -75,13 +152,51 @@ pub fn get_active_user_count() -> usize {
     _Storage.Cookies.len()
 }
 
-pub fn register() {
-    tokio::spawn(async {
-        let _CleanupInterval = Duration::from_secs(60);
-        loop {
-            tokio::time::sleep(_CleanupInterval).await;
-            let mut _Storage = COOKIE_STORAGE.lock().unwrap();
-            _Storage.cleanup_expired();
+pub const MODULE_NAME: &str = "CookieManager";
+pub const MODULE_VERSION: &str = "1.0.0";
+
+// Owns the captcha cookie store's lifecycle: loaded and swept while running, flushed on shutdown.
+// Enabling the module from the dashboard runs init on a plain thread, so the sweeper is spawned
+// through the runtime handle taken at registration rather than the caller's context.
+pub struct CookieManager {
+    Runtime: Handle,
+}
+
+impl WafModule for CookieManager {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        if let Err(e) = load_cookie_store() {
+            eprintln!("Cookie store load error: {}", e);
         }
-    });
-} 
\ No newline at end of file
+        
+        self.Runtime.spawn(async {
+            let _CleanupInterval = Duration::from_secs(60);
+            loop {
+                tokio::time::sleep(_CleanupInterval).await;
+                let mut _Storage = COOKIE_STORAGE.lock().unwrap();
+                _Storage.cleanup_expired();
+            }
+        });
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<(), String> {
+        flush_cookie_store().map(|_| ())
+    }
+}
+
+pub fn register() -> Result<(), String> {
+    let Runtime = Handle::try_current().map_err(|e| format!("{}: {}", MODULE_NAME, e))?;
+    register_module(Box::new(CookieManager { Runtime }))
+}
@@ -0,0 +1,34 @@
+#![allow(non_snake_case)]
+
+// The regression corpora under tests/corpus: each "<" line is a response body line and the ">" line
+// after it what the client must receive, "#" lines are comments
+
+use crate::module::WafModule;
+
+pub fn cases(Name: &str) -> Vec<(String, String)> {
+    let Path = format!("{}/tests/corpus/{}", env!("CARGO_MANIFEST_DIR"), Name);
+    let Content = std::fs::read_to_string(&Path).unwrap_or_else(|e| panic!("{}: {}", Path, e));
+    let Lines: Vec<&str> = Content.lines().filter(|Line| !Line.is_empty() && !Line.starts_with('#')).collect();
+    Lines.chunks(2)
+        .map(|Pair| match Pair {
+            [Input, Expected] if Input.starts_with("< ") && Expected.starts_with("> ") => (Input[2..].to_string(), Expected[2..].to_string()),
+            _ => panic!("{}: expected a '<' line followed by a '>' line, got {:?}", Name, Pair),
+        })
+        .collect()
+}
+
+// Runs every case through the modules' on_body in the given order and reports all mismatches at once
+pub fn check(Name: &str, Modules: &[&dyn WafModule]) {
+    let Cases = cases(Name);
+    assert!(!Cases.is_empty(), "{} has no cases", Name);
+    let Failures: Vec<String> = Cases.iter()
+        .filter_map(|(Input, Expected)| {
+            let mut Content = Input.clone();
+            for Module in Modules {
+                Module.on_body(&mut Content);
+            }
+            (Content != *Expected).then(|| format!("  < {}\n  expected {}\n  got      {}", Input, Expected, Content))
+        })
+        .collect();
+    assert!(Failures.is_empty(), "{}: {} of {} cases failed\n{}", Name, Failures.len(), Cases.len(), Failures.join("\n"));
+}
@@ -3,37 +3,52 @@
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::lazy_static;
-use sysinfo::{System, Cpu, CpuRefreshKind, RefreshKind, ProcessRefreshKind, ProcessesToUpdate};
+use sysinfo::{System, CpuRefreshKind, ProcessesToUpdate};
 use std::thread;
 use std::io::{stdout, Write};
 use crossterm::{
     execute,
-    terminal::{Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
     cursor::{Hide, Show, MoveTo},
-    style::{Color, SetForegroundColor, ResetColor, Print, Stylize, Attribute, SetAttribute},
-    event::{poll, read, Event, KeyCode},
+    style::{Color, SetForegroundColor, ResetColor, Print, Attribute, SetAttribute},
+    event::{poll, read, Event, KeyCode, KeyEventKind, KeyModifiers},
 };
-use std::ops::Add;
 
-use crate::module::{ModuleInfo, register_module};
+use crate::module::{get_module_status, move_module, register_module, reload_module, set_module_enabled, WafModule};
+use crate::module_stats::{format_micros, ModuleTiming};
 use crate::modules::cookie_manager::get_active_user_count;
+use crate::modules::secret_scanner::get_finding_counts;
+use crate::proxy::breaker::{get_breaker_transitions, BreakerState};
+use crate::proxy::health::get_upstream_status;
+use crate::proxy::tunnel::{get_active_tunnels, TUNNEL_BYTES_DOWN, TUNNEL_BYTES_UP, TUNNEL_TOTAL};
+use crate::shutdown::{active_connections, is_shutting_down, request_shutdown};
 
 lazy_static! {
     static ref DASHBOARD_DATA: Arc<Mutex<DashboardData>> = Arc::new(Mutex::new(DashboardData::new()));
     static ref SYSTEM_INFO: Arc<Mutex<System>> = Arc::new(Mutex::new(System::new_all()));
+    static ref DASHBOARD_THREAD: Mutex<Option<thread::JoinHandle<()>>> = Mutex::new(None);
 }
 
+static DASHBOARD_STOP: AtomicBool = AtomicBool::new(false);
+
 pub static REQUEST_RATE: AtomicU64 = AtomicU64::new(0);
 pub static RESPONSE_RATE: AtomicU64 = AtomicU64::new(0);
 
-#[derive(Clone)]
+// One sampling window of a module's hooks; Usage is the share of wall time spent inside them
+#[derive(Clone, Default)]
 struct ModulePerformance {
-    CpuUsage: f32,
-    LastUpdate: Instant,
+    Usage: f64,
+    CallsPerSecond: f64,
+    BytesPerSecond: f64,
+    AverageMicros: u64,
+    P99Micros: Option<u64>,
+    // Panicked hook calls since startup, not just in this window
+    Faults: u64,
 }
 
+#[allow(dead_code)]
 struct DashboardColors {
     Primary: Color,
     Secondary: Color,
@@ -57,6 +72,9 @@ struct DashboardData {
     LastResponseCount: u64,
     SelfPid: u32,
     ColorScheme: DashboardColors,
+    // Row picked in the module pane and the outcome of the last key action on it
+    SelectedModule: usize,
+    ModuleNotice: Option<(String, Instant)>,
 }
 
 impl DashboardData {
@@ -70,6 +88,8 @@ impl DashboardData {
             LastRequestCount: 0,
             LastResponseCount: 0,
             SelfPid: std::process::id(),
+            SelectedModule: 0,
+            ModuleNotice: None,
             ColorScheme: DashboardColors {
                 Primary: Color::Rgb { r: 73, g: 156, b: 228 },
                 Secondary: Color::Rgb { r: 145, g: 205, b: 247 },
@@ -98,18 +118,56 @@ impl DashboardData {
     }
 }
 
-pub fn register() {
-    let _ModuleInfo = ModuleInfo {
-        Name: String::from("Dashboard"),
-        Version: String::from("1.0"),
-        ProcessContent: |_| {},
-    };
+pub struct Dashboard;
+
+impl WafModule for Dashboard {
+    fn name(&self) -> &str {
+        "Dashboard"
+    }
     
-    register_module(_ModuleInfo);
+    fn version(&self) -> &str {
+        "1.0"
+    }
     
-    thread::spawn(|| {
-        start_dashboard();
-    });
+    fn priority(&self) -> i32 {
+        0
+    }
+    
+    fn init(&mut self) -> Result<(), String> {
+        let _Handle = thread::spawn(|| {
+            start_dashboard();
+        });
+        *DASHBOARD_THREAD.lock().unwrap() = Some(_Handle);
+        Ok(())
+    }
+    
+    fn shutdown(&self) -> Result<(), String> {
+        stop_dashboard();
+        Ok(())
+    }
+}
+
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(Dashboard))
+}
+
+// Waits for the dashboard to leave the alternate screen so the terminal is usable after exit.
+// A draw loop stuck in a terminal read is not waited on forever, the screen is restored from here instead.
+fn stop_dashboard() {
+    DASHBOARD_STOP.store(true, Ordering::Relaxed);
+    let _Handle = DASHBOARD_THREAD.lock().unwrap().take();
+    if let Some(_Handle) = _Handle {
+        let _Deadline = Instant::now() + Duration::from_secs(2);
+        while !_Handle.is_finished() && Instant::now() < _Deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        
+        if _Handle.is_finished() {
+            let _ = _Handle.join();
+        } else {
+            drop(TerminalGuard);
+        }
+    }
 }
 
 fn format_duration(Duration: Duration) -> String {
@@ -122,6 +180,23 @@ fn format_duration(Duration: Duration) -> String {
     format!("{}d {}h {}m {}s", _Days, _Hours, _Minutes, _Seconds)
 }
 
+fn format_bytes(Bytes: u64) -> String {
+    let _Units = ["B", "KB", "MB", "GB", "TB"];
+    let mut _Value = Bytes as f64;
+    let mut _Unit = 0;
+    
+    while _Value >= 1024.0 && _Unit < _Units.len() - 1 {
+        _Value /= 1024.0;
+        _Unit += 1;
+    }
+    
+    if _Unit == 0 {
+        format!("{} {}", Bytes, _Units[0])
+    } else {
+        format!("{:.1} {}", _Value, _Units[_Unit])
+    }
+}
+
 fn draw_border(X: u16, Y: u16, Width: u16, Height: u16, Title: &str, BorderColor: Color, TitleColor: Color) {
     let _XEnd = X + Width - 1;
     let _YEnd = Y + Height - 1;
@@ -164,6 +239,7 @@ fn draw_border(X: u16, Y: u16, Width: u16, Height: u16, Title: &str, BorderColor
     execute!(stdout(), ResetColor).unwrap();
 }
 
+#[allow(clippy::too_many_arguments)]
 fn draw_horizontal_gauge(X: u16, Y: u16, Width: u16, Value: f64, LowColor: Color, MedColor: Color, HighColor: Color, BackgroundChar: char, ForegroundChar: char) {
     let _FilledWidth = ((Width as f64 * Value.min(100.0)) / 100.0).round() as u16;
     let _Color = if Value > 80.0 { HighColor } else if Value > 50.0 { MedColor } else { LowColor };
@@ -242,8 +318,21 @@ fn get_current_timestamp() -> String {
     }
 }
 
+// Leaves raw mode and the alternate screen on every exit path, including a panic in the draw loop
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(), ResetColor, LeaveAlternateScreen, Show);
+    }
+}
+
 fn start_dashboard() {
+    // Raw mode delivers keys one at a time without echo, which the module pane bindings rely on
     execute!(stdout(), EnterAlternateScreen, Hide).unwrap();
+    let _TerminalGuard = TerminalGuard;
+    let _ = enable_raw_mode();
     
     let mut _System = SYSTEM_INFO.lock().unwrap();
     _System.refresh_all();
@@ -269,11 +358,22 @@ fn start_dashboard() {
     let _ColorScheme = &_DashboardData.ColorScheme;
     std::mem::drop(_DashboardData);
     
-    while !_Exit {
-        if poll(Duration::from_millis(100)).unwrap() {
+    while !_Exit && !DASHBOARD_STOP.load(Ordering::Relaxed) {
+        // Everything typed since the last frame is handled before drawing, not one key per frame
+        let mut _Wait = Duration::from_millis(100);
+        while !_Exit && poll(_Wait).unwrap() {
+            _Wait = Duration::ZERO;
             if let Event::Key(KeyEvent) = read().unwrap() {
-                if KeyEvent.code == KeyCode::Char('q') {
+                if KeyEvent.kind != KeyEventKind::Press {
+                    continue;
+                }
+                if KeyEvent.code == KeyCode::Char('c') && KeyEvent.modifiers.contains(KeyModifiers::CONTROL) {
+                    // Raw mode keeps the terminal from turning Ctrl+C into SIGINT
+                    request_shutdown();
+                } else if KeyEvent.code == KeyCode::Char('q') {
                     _Exit = true;
+                } else {
+                    handle_module_key(KeyEvent.code);
                 }
             }
         }
@@ -519,6 +619,50 @@ fn start_dashboard() {
             _ColorScheme.Accent
         );
         
+        let _ActiveTunnels = get_active_tunnels();
+        
+        draw_stats_label(
+            _RequestStatsX + 3, 
+            _RequestStatsY + 7,
+            "Active Tunnels: ", 
+            &format!("{}", _ActiveTunnels.len()),
+            _ColorScheme.Text,
+            _ColorScheme.Success
+        );
+        
+        draw_stats_label(
+            _RequestStatsX + 30, 
+            _RequestStatsY + 7,
+            "Total Tunnels: ", 
+            &format!("{}", TUNNEL_TOTAL.load(Ordering::Relaxed)),
+            _ColorScheme.Text,
+            _ColorScheme.Primary
+        );
+        
+        draw_stats_label(
+            _RequestStatsX + 3, 
+            _RequestStatsY + 8,
+            "Tunnel Bytes: ", 
+            &format!("up {} / down {}", format_bytes(TUNNEL_BYTES_UP.load(Ordering::Relaxed)), format_bytes(TUNNEL_BYTES_DOWN.load(Ordering::Relaxed))),
+            _ColorScheme.Text,
+            _ColorScheme.Info
+        );
+        
+        for (_Index, _Tunnel) in _ActiveTunnels.iter().take(2).enumerate() {
+            execute!(
+                stdout(),
+                MoveTo(_RequestStatsX + 3, _RequestStatsY + 9 + _Index as u16),
+                SetForegroundColor(_ColorScheme.Muted),
+                Print(format!("{} {} {} up {} / down {}",
+                    _Tunnel.ClientAddr,
+                    _Tunnel.Protocol,
+                    _Tunnel.Path,
+                    format_bytes(_Tunnel.BytesUp.load(Ordering::Relaxed)),
+                    format_bytes(_Tunnel.BytesDown.load(Ordering::Relaxed)))),
+                ResetColor
+            ).unwrap();
+        }
+        
         let _UserStatsY = _SysInfoY + _SysInfoHeight + 1;
         let _UserStatsWidth = _MainWidth;
         let _UserStatsHeight = 6;
@@ -587,7 +731,71 @@ fn start_dashboard() {
             '█'
         );
         
-        let _ModuleY = _UserStatsY + _UserStatsHeight + 1;
+        let _Upstreams = get_upstream_status();
+        let _UpstreamY = _UserStatsY + _UserStatsHeight + 1;
+        let _UpstreamRows = _Upstreams.len().clamp(1, 6) as u16;
+        let _UpstreamHeight = _UpstreamRows + 4;
+        
+        draw_border(
+            _MainStartX, 
+            _UpstreamY, 
+            _MainWidth, 
+            _UpstreamHeight, 
+            ".upstreams",
+            _ColorScheme.Border, 
+            _ColorScheme.Accent
+        );
+        
+        for (_Index, _Upstream) in _Upstreams.iter().take(_UpstreamRows as usize).enumerate() {
+            let _YPos = _UpstreamY + 2 + _Index as u16;
+            let (_StateLabel, _StateColor) = if _Upstream.Healthy {
+                ("UP  ", _ColorScheme.Success)
+            } else {
+                ("DOWN", _ColorScheme.Danger)
+            };
+            
+            execute!(
+                stdout(),
+                MoveTo(_MainStartX + 3, _YPos),
+                SetForegroundColor(_StateColor),
+                Print(_StateLabel),
+                SetForegroundColor(_ColorScheme.Text),
+                Print(format!(" {:<12} {:<28}", _Upstream.Pool, _Upstream.Address)),
+                SetForegroundColor(_ColorScheme.Info),
+                Print(format!(" conns: {:<6} fails: {:<4} {}", 
+                    _Upstream.ActiveConnections, 
+                    _Upstream.ConsecutiveFailures,
+                    if _Upstream.ActiveProbe { "probe: active " } else { "probe: passive" })),
+                SetForegroundColor(match _Upstream.Breaker {
+                    BreakerState::Closed => _ColorScheme.Success,
+                    BreakerState::HalfOpen => _ColorScheme.Warning,
+                    BreakerState::Open => _ColorScheme.Danger,
+                }),
+                Print(format!(" breaker: {}", _Upstream.Breaker.label())),
+                ResetColor
+            ).unwrap();
+        }
+        
+        let _LastTransition = match get_breaker_transitions().first() {
+            Some(_Transition) => format!(
+                "last breaker change: pool {} {} -> {} {}s ago",
+                _Transition.Pool,
+                _Transition.From.label(),
+                _Transition.To.label(),
+                _Transition.At.elapsed().map(|d| d.as_secs()).unwrap_or(0)
+            ),
+            None => String::from("last breaker change: none"),
+        };
+        
+        execute!(
+            stdout(),
+            MoveTo(_MainStartX + 3, _UpstreamY + 2 + _UpstreamRows),
+            SetForegroundColor(_ColorScheme.Info),
+            Print(_LastTransition),
+            ResetColor
+        ).unwrap();
+        
+        let _ModuleY = _UpstreamY + _UpstreamHeight + 1;
         
         let _RemainingHeight = if _TerminalHeight > _ModuleY + 4 {
             _TerminalHeight - _ModuleY - 4
@@ -607,30 +815,40 @@ fn start_dashboard() {
             _ColorScheme.Primary
         );
         
-        let _MaxModulesPerCol = if _ModuleHeight > 3 { (_ModuleHeight - 3) as usize } else { 1 };
+        let _MaxModulesPerCol = if _ModuleHeight > 5 { (_ModuleHeight - 5) as usize } else { 1 };
         let _ModuleColWidth = (_MainWidth - 6) / 3;
         
         let mut _Row = 0;
         let mut _Col = 0;
         
         let _ModulePerformance = _DashboardData.ModulePerformance.clone();
+        let _ModuleStatus = get_module_status();
+        let _Selected = _DashboardData.SelectedModule.min(_ModuleStatus.len().saturating_sub(1));
         
-        for (_ModuleName, _Performance) in _ModulePerformance.iter().take(_MaxModulesPerCol * 3) {
+        for (_Index, _Status) in _ModuleStatus.iter().enumerate().take(_MaxModulesPerCol * 3) {
+            let _ModuleName = &_Status.Name;
+            let _Performance = _ModulePerformance.get(_ModuleName).cloned().unwrap_or_default();
             let _XPos = _MainStartX + 3 + ((_Col * _ModuleColWidth as usize) as u16);
             let _YPos = _ModuleY + 2 + (_Row % _MaxModulesPerCol) as u16;
             
+            let mut _Label = _ModuleName.clone();
+            _Label.truncate(16);
+            
             execute!(
                 stdout(),
                 MoveTo(_XPos, _YPos),
-                SetForegroundColor(_ColorScheme.Text),
-                Print(format!("{}: ", _ModuleName))
+                SetForegroundColor(if !_Status.Enabled { _ColorScheme.Muted } else if _Performance.Faults > 0 { _ColorScheme.Danger } else { _ColorScheme.Text }),
+                SetAttribute(if _Index == _Selected { Attribute::Reverse } else { Attribute::NoReverse }),
+                Print(format!("{}:", _Label)),
+                SetAttribute(Attribute::NoReverse),
+                Print(" ".repeat(16 - _Label.chars().count()))
             ).unwrap();
             
             draw_horizontal_gauge(
-                _XPos + 15, 
+                _XPos + 17, 
                 _YPos, 
-                _ModuleColWidth - 25, 
-                _Performance.CpuUsage as f64,
+                10, 
+                _Performance.Usage,
                 _ColorScheme.Success,
                 _ColorScheme.Warning, 
                 _ColorScheme.Danger,
@@ -638,6 +856,25 @@ fn start_dashboard() {
                 '█'
             );
             
+            let mut _Figures = if !_Status.Enabled { String::from("disabled ") } else { String::new() };
+            _Figures.push_str(&format!(
+                "{:.0}/s avg {} p99 {}{} {}/s",
+                _Performance.CallsPerSecond,
+                format_micros(_Performance.AverageMicros),
+                _Performance.P99Micros.map(format_micros).unwrap_or_else(|| String::from("-")),
+                if _Performance.Faults > 0 { format!(" faults {}", _Performance.Faults) } else { String::new() },
+                format_bytes(_Performance.BytesPerSecond as u64)
+            ));
+            _Figures.truncate(_ModuleColWidth.saturating_sub(31) as usize);
+            
+            execute!(
+                stdout(),
+                MoveTo(_XPos + 30, _YPos),
+                SetForegroundColor(_ColorScheme.Muted),
+                Print(_Figures),
+                ResetColor
+            ).unwrap();
+            
             _Row += 1;
             if _Row % _MaxModulesPerCol == 0 {
                 _Col += 1;
@@ -647,6 +884,44 @@ fn start_dashboard() {
             }
         }
         
+        let _Findings: Vec<String> = get_finding_counts().iter()
+            .filter(|(_, _Count)| *_Count > 0)
+            .map(|(_Rule, _Count)| format!("{} {}", _Rule, _Count))
+            .collect();
+        let mut _FindingsLine = if _Findings.is_empty() {
+            String::from("secret findings: none")
+        } else {
+            format!("secret findings: {}", _Findings.join("  "))
+        };
+        _FindingsLine.truncate((_MainWidth - 6) as usize);
+        
+        execute!(
+            stdout(),
+            MoveTo(_MainStartX + 3, _ModuleY + _ModuleHeight - 3),
+            SetForegroundColor(if _Findings.is_empty() { _ColorScheme.Muted } else { _ColorScheme.Danger }),
+            Print(_FindingsLine),
+            ResetColor
+        ).unwrap();
+        
+        let _Pipeline: Vec<String> = _ModuleStatus.iter()
+            .filter(|_Status| _Status.Enabled)
+            .map(|_Status| format!("{}({})", _Status.Name, _Status.Priority))
+            .collect();
+        let mut _PipelineLine = match _DashboardData.ModuleNotice.as_ref() {
+            Some((_Notice, _At)) if _At.elapsed() < Duration::from_secs(5) => _Notice.clone(),
+            _ => format!("pipeline: {}", _Pipeline.join(" > ")),
+        };
+        _PipelineLine = format!("{:<1$}", _PipelineLine, (_MainWidth - 6) as usize);
+        _PipelineLine.truncate((_MainWidth - 6) as usize);
+        
+        execute!(
+            stdout(),
+            MoveTo(_MainStartX + 3, _ModuleY + _ModuleHeight - 2),
+            SetForegroundColor(_ColorScheme.Muted),
+            Print(_PipelineLine),
+            ResetColor
+        ).unwrap();
+        
         draw_border(
             _MainStartX, 
             _TerminalHeight - 3, 
@@ -661,22 +936,70 @@ fn start_dashboard() {
             stdout(),
             MoveTo(_MainStartX + 3, _TerminalHeight - 2),
             SetForegroundColor(_ColorScheme.Muted),
-            Print("Press 'q' to exit dashboard | Refresh Rate: 500ms | cebulka-waf security dashboard"),
+            Print(if is_shutting_down() {
+                format!("Shutting down, draining {} connection(s) | cebulka-waf security dashboard{:20}", active_connections(), "")
+            } else {
+                String::from("q close | ctrl+c shut down | up/down pick module, space toggle, [ ] move, r reload | Refresh Rate: 500ms | cebulka-waf security dashboard")
+            }),
             ResetColor
         ).unwrap();
         
         stdout().flush().unwrap();
         thread::sleep(Duration::from_millis(500));
     }
+}
+
+// Module pane controls; changes go through the registry so only requests arriving afterwards see them.
+// A reload re-initialises the module, so the dashboard lock is not held while the registry works.
+fn handle_module_key(Code: KeyCode) {
+    let _Modules = get_module_status();
+    if _Modules.is_empty() {
+        return;
+    }
     
-    execute!(stdout(), LeaveAlternateScreen, Show).unwrap();
+    let _Selected = DASHBOARD_DATA.lock().unwrap().SelectedModule.min(_Modules.len() - 1);
+    let _Module = &_Modules[_Selected];
+    let mut _NewSelection = _Selected;
+    
+    let _Outcome = match Code {
+        KeyCode::Up => {
+            DASHBOARD_DATA.lock().unwrap().SelectedModule = _Selected.saturating_sub(1);
+            return;
+        }
+        KeyCode::Down => {
+            DASHBOARD_DATA.lock().unwrap().SelectedModule = (_Selected + 1).min(_Modules.len() - 1);
+            return;
+        }
+        KeyCode::Char(' ') => set_module_enabled(&_Module.Name, !_Module.Enabled)
+            .map(|_| format!("{} {}", _Module.Name, if _Module.Enabled { "disabled" } else { "enabled" })),
+        KeyCode::Char('[') | KeyCode::Char(']') => {
+            let _Earlier = Code == KeyCode::Char('[');
+            move_module(&_Module.Name, _Earlier).map(|_| {
+                // Keep the cursor on the module that moved
+                _NewSelection = get_module_status().iter().position(|Status| Status.Name == _Module.Name).unwrap_or(_Selected);
+                format!("{} moved {}", _Module.Name, if _Earlier { "earlier" } else { "later" })
+            })
+        }
+        KeyCode::Char('r') => reload_module(&_Module.Name, &[]).map(|_| format!("{} reloaded", _Module.Name)),
+        _ => return,
+    };
+    
+    let mut _Data = DASHBOARD_DATA.lock().unwrap();
+    _Data.SelectedModule = _NewSelection;
+    _Data.ModuleNotice = Some((_Outcome.unwrap_or_else(|e| e), Instant::now()));
 }
 
-pub fn update_module_performance(ModuleName: &str, CpuUsage: f32) {
+// Window is what the module's hooks did over the last Span, Total is everything since startup
+pub fn update_module_performance(ModuleName: &str, Total: &ModuleTiming, Window: &ModuleTiming, Span: Duration) {
+    let _Seconds = Span.as_secs_f64().max(0.001);
     let mut _Data = DASHBOARD_DATA.lock().unwrap();
     _Data.ModulePerformance.insert(ModuleName.to_string(), ModulePerformance {
-        CpuUsage,
-        LastUpdate: Instant::now(),
+        Usage: Window.Nanos as f64 / 1e9 / _Seconds * 100.0,
+        CallsPerSecond: Window.Invocations as f64 / _Seconds,
+        BytesPerSecond: Window.Bytes as f64 / _Seconds,
+        AverageMicros: Window.average_micros(),
+        P99Micros: Window.percentile_micros(0.99),
+        Faults: Total.Faults,
     });
 }
 
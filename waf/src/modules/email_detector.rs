@@ -0,0 +1,120 @@
+#![allow(non_snake_case)]
+
+use regex::Regex;
+
+use crate::config::env_opt;
+use crate::module::{register_module, BodyAction, FailurePolicy, WafModule, PRIORITY_REDACT, TEXT_CONTENT_TYPES};
+use crate::modules::redaction::{continues_token, replace_spans, DEFAULT_MASK};
+
+pub const MODULE_NAME: &str = "EmailDetector";
+pub const MODULE_VERSION: &str = "1.0.0";
+
+pub struct EmailDetector {
+    _Pattern: Regex,
+    _Token: String,
+}
+
+const EMAIL_PATTERN: &str = r"(?i)[a-z0-9._%+\-]+@[a-z0-9](?:[a-z0-9\-]*[a-z0-9])?(?:\.[a-z0-9](?:[a-z0-9\-]*[a-z0-9])?)+";
+
+// "logo@2x.png" and friends are asset names, not addresses
+const FILE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp", "avif", "ico", "css", "js", "mjs", "map", "woff", "woff2"];
+
+pub struct EmailMatch {
+    pub Start: usize,
+    pub End: usize,
+}
+
+// RFC 5321 limits, no empty or dotted-off local part labels, and an alphabetic top-level domain
+fn is_plausible_address(Candidate: &str) -> bool {
+    let (Local, Domain) = match Candidate.rsplit_once('@') {
+        Some(Parts) => Parts,
+        None => return false,
+    };
+    if Local.len() > 64 || Local.starts_with('.') || Local.ends_with('.') || Local.contains("..") || Domain.len() > 253 {
+        return false;
+    }
+
+    let TopLevel = Domain.rsplit('.').next().unwrap_or("");
+    TopLevel.len() >= 2
+        && TopLevel.bytes().all(|Byte| Byte.is_ascii_alphabetic())
+        && !FILE_EXTENSIONS.iter().any(|Extension| TopLevel.eq_ignore_ascii_case(Extension))
+}
+
+impl EmailDetector {
+    pub fn new() -> Self {
+        EmailDetector {
+            _Pattern: Regex::new(EMAIL_PATTERN).unwrap(),
+            _Token: String::from(DEFAULT_MASK),
+        }
+    }
+
+    pub fn find_addresses(&self, Content: &str) -> Vec<EmailMatch> {
+        let Bytes = Content.as_bytes();
+        self._Pattern.find_iter(Content)
+            .filter(|Found| !Bytes.get(Found.end()).is_some_and(|Byte| continues_token(*Byte) || matches!(Byte, b'-' | b'@')))
+            .filter(|Found| is_plausible_address(Found.as_str()))
+            .map(|Found| EmailMatch { Start: Found.start(), End: Found.end() })
+            .collect()
+    }
+
+    pub fn redact_emails(&self, Content: &mut String) {
+        let Found = self.find_addresses(Content);
+        replace_spans(Content, Found.iter().map(|Match| (Match.Start..Match.End, &self._Token)));
+    }
+}
+
+impl WafModule for EmailDetector {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        PRIORITY_REDACT
+    }
+
+    fn content_types(&self) -> &[&str] {
+        TEXT_CONTENT_TYPES
+    }
+
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+
+    // EMAIL_REDACT_TOKEN replaces each address, [REDACTED] unless set
+    fn init(&mut self) -> Result<(), String> {
+        self._Token = env_opt("EMAIL_REDACT_TOKEN").unwrap_or_else(|| String::from(DEFAULT_MASK));
+        Ok(())
+    }
+
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(EmailDetector::new()))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        &["EMAIL_REDACT_TOKEN"]
+    }
+
+    fn on_body(&self, Content: &mut String) -> BodyAction {
+        self.redact_emails(Content);
+        BodyAction::Continue
+    }
+}
+
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(EmailDetector::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::corpus;
+
+    #[test]
+    fn email_corpus() {
+        corpus::check("email_detection.txt", &[&EmailDetector::new()]);
+    }
+}
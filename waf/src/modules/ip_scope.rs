@@ -0,0 +1,170 @@
+#![allow(non_snake_case)]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::cidr::{parse_cidr_list, IpCidr};
+use crate::config::{env_list, env_opt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressScope {
+    Loopback,
+    // RFC 1918, carrier-grade NAT (100.64.0.0/10) and unique local fc00::/7
+    Private,
+    LinkLocal,
+    // Unspecified, broadcast, multicast, documentation and other special-purpose ranges
+    Reserved,
+    Public,
+}
+
+const ALL_SCOPES: [AddressScope; 5] = [
+    AddressScope::Loopback,
+    AddressScope::Private,
+    AddressScope::LinkLocal,
+    AddressScope::Reserved,
+    AddressScope::Public,
+];
+
+impl FromStr for AddressScope {
+    type Err = String;
+
+    fn from_str(Value: &str) -> Result<Self, Self::Err> {
+        match Value.trim().to_lowercase().replace('_', "-").as_str() {
+            "loopback" => Ok(AddressScope::Loopback),
+            "private" => Ok(AddressScope::Private),
+            "link-local" => Ok(AddressScope::LinkLocal),
+            "reserved" => Ok(AddressScope::Reserved),
+            "public" => Ok(AddressScope::Public),
+            Other => Err(format!("unknown address scope '{}', expected loopback, private, link-local, reserved or public", Other)),
+        }
+    }
+}
+
+fn scope_v4(Address: Ipv4Addr) -> AddressScope {
+    let [First, Second, ..] = Address.octets();
+    if Address.is_loopback() {
+        AddressScope::Loopback
+    } else if Address.is_private() || (First == 100 && (64..128).contains(&Second)) {
+        AddressScope::Private
+    } else if Address.is_link_local() {
+        AddressScope::LinkLocal
+    } else if Address.is_unspecified() || Address.is_broadcast() || Address.is_multicast() || Address.is_documentation() || First == 0 || First >= 240 {
+        AddressScope::Reserved
+    } else {
+        AddressScope::Public
+    }
+}
+
+fn scope_v6(Address: Ipv6Addr) -> AddressScope {
+    let Segments = Address.segments();
+    if Address.is_loopback() {
+        AddressScope::Loopback
+    } else if Address.is_unique_local() {
+        AddressScope::Private
+    } else if Address.is_unicast_link_local() {
+        AddressScope::LinkLocal
+    } else if Address.is_unspecified() || Address.is_multicast() || (Segments[0] == 0x2001 && Segments[1] == 0x0db8) {
+        AddressScope::Reserved
+    } else {
+        AddressScope::Public
+    }
+}
+
+impl AddressScope {
+    // IPv4-mapped IPv6 addresses are judged by the IPv4 address they carry
+    pub fn of(Address: IpAddr) -> Self {
+        match Address.to_canonical() {
+            IpAddr::V4(Address) => scope_v4(Address),
+            IpAddr::V6(Address) => scope_v6(Address),
+        }
+    }
+}
+
+// Which detected addresses the IP detectors actually redact. IP_REDACT_SCOPES lists the scopes to
+// redact (all of them unless set), IP_REDACT_ALLOW holds CIDRs that are never redacted, e.g. our own
+// anycast ranges, and IP_REDACT_DENY CIDRs that always are. Deny wins when both match.
+pub struct IpRedactionScope {
+    Scopes: Vec<AddressScope>,
+    Allow: Vec<IpCidr>,
+    Deny: Vec<IpCidr>,
+}
+
+impl Default for IpRedactionScope {
+    fn default() -> Self {
+        Self { Scopes: ALL_SCOPES.to_vec(), Allow: Vec::new(), Deny: Vec::new() }
+    }
+}
+
+impl IpRedactionScope {
+    pub fn from_env() -> Result<Self, String> {
+        let Scopes = match env_opt("IP_REDACT_SCOPES") {
+            Some(_) => env_list("IP_REDACT_SCOPES").iter()
+                .map(|Scope| Scope.parse().map_err(|e| format!("IP_REDACT_SCOPES: {}", e)))
+                .collect::<Result<Vec<AddressScope>, String>>()?,
+            None => ALL_SCOPES.to_vec(),
+        };
+
+        Ok(Self {
+            Scopes,
+            Allow: parse_cidr_list(&env_list("IP_REDACT_ALLOW")).map_err(|e| format!("IP_REDACT_ALLOW: {}", e))?,
+            Deny: parse_cidr_list(&env_list("IP_REDACT_DENY")).map_err(|e| format!("IP_REDACT_DENY: {}", e))?,
+        })
+    }
+
+    pub fn should_redact(&self, Address: IpAddr) -> bool {
+        if self.Deny.iter().any(|Range| Range.contains(Address)) {
+            return true;
+        }
+        if self.Allow.iter().any(|Range| Range.contains(Address)) {
+            return false;
+        }
+        self.Scopes.contains(&AddressScope::of(Address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(Address: &str) -> AddressScope {
+        AddressScope::of(Address.parse().unwrap())
+    }
+
+    fn ranges(Values: &[&str]) -> Vec<IpCidr> {
+        Values.iter().map(|Value| IpCidr::parse(Value).unwrap()).collect()
+    }
+
+    #[test]
+    fn addresses_are_sorted_into_scopes() {
+        assert_eq!(scope("100.64.0.1"), AddressScope::Private);
+        assert_eq!(scope("100.127.255.254"), AddressScope::Private);
+        assert_eq!(scope("100.128.0.1"), AddressScope::Public);
+        assert_eq!(scope("::ffff:10.1.2.3"), AddressScope::Private);
+        assert_eq!(scope("::ffff:127.0.0.1"), AddressScope::Loopback);
+        assert_eq!(scope("::ffff:8.8.8.8"), AddressScope::Public);
+        assert_eq!(scope("2001:db8::1"), AddressScope::Reserved);
+        assert_eq!(scope("2001:db9::1"), AddressScope::Public);
+        assert_eq!(scope("0.0.0.0"), AddressScope::Reserved);
+        assert_eq!(scope("::"), AddressScope::Reserved);
+    }
+
+    #[test]
+    fn deny_wins_over_allow_and_both_win_over_scopes() {
+        let Scope = IpRedactionScope {
+            Scopes: vec![AddressScope::Public],
+            Allow: ranges(&["203.0.113.0/24", "8.8.0.0/16"]),
+            Deny: ranges(&["8.8.8.0/24", "10.0.0.0/8"]),
+        };
+        let Redacted = |Address: &str| Scope.should_redact(Address.parse().unwrap());
+
+        assert!(Redacted("1.1.1.1"));
+        assert!(!Redacted("192.168.1.1"));
+        // Allowed although public, denied although private
+        assert!(!Redacted("8.8.4.4"));
+        assert!(Redacted("10.1.2.3"));
+        // In both lists
+        assert!(Redacted("8.8.8.8"));
+        // Allowed in a scope that is not redacted anyway
+        assert!(!Redacted("203.0.113.7"));
+    }
+}
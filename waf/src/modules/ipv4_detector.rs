@@ -1,50 +1,128 @@
-//waf/src/modules/ipv4_detector.rs
 #![allow(non_snake_case)]
 
+use std::net::{IpAddr, Ipv4Addr};
 use regex::Regex;
-use std::sync::OnceLock;
 
-use crate::module::{ModuleInfo, register_module};
+use crate::module::{register_module, BodyAction, FailurePolicy, WafModule, PRIORITY_REDACT, TEXT_CONTENT_TYPES};
+use crate::modules::ip_scope::IpRedactionScope;
+use crate::modules::redaction::{continues_token, is_whole_token, replace_spans, IpRedactor, TokenBounds};
 
 pub const MODULE_NAME: &str = "IPv4Detector";
-pub const MODULE_VERSION: &str = "1.0.0";
+pub const MODULE_VERSION: &str = "1.3.0";
+// The redaction, keyring and scope settings the detector is built from
+const CONFIG_KEYS: &[&str] = &[
+    "IPV4_REDACT_STRATEGY", "IPV4_REDACT_TOKEN", "IPV4_REDACT_PREFIX", "IPV6_REDACT_PREFIX",
+    "REDACT_KEYS", "REDACT_KEYS_FILE", "IP_REDACT_SCOPES", "IP_REDACT_ALLOW", "IP_REDACT_DENY",
+];
 
 pub struct IPv4Detector {
     _Pattern: Regex,
+    _Scope: IpRedactionScope,
+    _Redactor: IpRedactor,
 }
 
-fn get_ipv4_regex() -> &'static Regex {
-    static _IPV4_PATTERN: OnceLock<Regex> = OnceLock::new();
-    _IPV4_PATTERN.get_or_init(|| {
-        Regex::new(r"(25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.(25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.(25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.(25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)").unwrap()
-    })
+// Candidates only, whether one really is an address is decided by its surroundings and Ipv4Addr
+const IPV4_PATTERN: &str = r"[0-9]{1,3}(?:\.[0-9]{1,3}){3}(?:/[0-9]+)?";
+
+pub struct Ipv4Match {
+    pub Start: usize,
+    pub End: usize,
+    pub Address: Ipv4Addr,
+    pub Prefix: Option<u8>,
 }
 
+// The candidate has to be a whole token: "asd192.168.0.1", "92348294192.168.0.123949" and the
+// "1.2.3.4" of version 1.2.3.4.5 are parts of something else. A trailing "." still ends a sentence.
+const IPV4_BOUNDS: TokenBounds = TokenBounds { Leading: b".", Trailing: b".", Continues: continues_token };
+
 impl IPv4Detector {
     pub fn new() -> Self {
         IPv4Detector {
-            _Pattern: get_ipv4_regex().clone(),
+            _Pattern: Regex::new(IPV4_PATTERN).unwrap(),
+            _Scope: IpRedactionScope::default(),
+            _Redactor: IpRedactor::default(),
         }
     }
 
+    // Addresses with leading zeros ("010.0.0.1") are left alone, Ipv4Addr rejects them as ambiguous.
+    // A CIDR suffix belongs to the match when it is a valid prefix length, otherwise only the address does.
+    pub fn find_addresses(&self, Content: &str) -> Vec<Ipv4Match> {
+        self._Pattern.find_iter(Content)
+            .filter_map(|Found| {
+                let (Text, Suffix) = match Found.as_str().split_once('/') {
+                    Some((Text, Suffix)) => (Text, Some(Suffix)),
+                    None => (Found.as_str(), None),
+                };
+                let Address: Ipv4Addr = Text.parse().ok()?;
+                let AddressEnd = Found.start() + Text.len();
+
+                match Suffix.and_then(|Suffix| Suffix.parse::<u8>().ok()).filter(|Prefix| *Prefix <= 32) {
+                    Some(Prefix) if is_whole_token(Content, Found.start(), Found.end(), &IPV4_BOUNDS) => {
+                        Some(Ipv4Match { Start: Found.start(), End: Found.end(), Address, Prefix: Some(Prefix) })
+                    }
+                    _ if is_whole_token(Content, Found.start(), AddressEnd, &IPV4_BOUNDS) => {
+                        Some(Ipv4Match { Start: Found.start(), End: AddressEnd, Address, Prefix: None })
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
     pub fn redact_ipv4(&self, Content: &mut String) {
-        *Content = self._Pattern.replace_all(Content, "[REDACTED]").to_string();
+        let Found: Vec<Ipv4Match> = self.find_addresses(Content).into_iter()
+            .filter(|Match| self._Scope.should_redact(IpAddr::V4(Match.Address)))
+            .collect();
+        replace_spans(Content, Found.iter().map(|Match| (Match.Start..Match.End, self._Redactor.replacement(IpAddr::V4(Match.Address), Match.Prefix))));
     }
 }
 
-pub fn redact_ipv4_in_content(Content: &mut String) {
-    let Detector = IPv4Detector::new();
-    Detector.redact_ipv4(Content);
-}
+impl WafModule for IPv4Detector {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        PRIORITY_REDACT
+    }
+
+    fn content_types(&self) -> &[&str] {
+        TEXT_CONTENT_TYPES
+    }
 
-pub fn register() {
-    register_module(ModuleInfo {
-        Name: MODULE_NAME.to_string(),
-        Version: MODULE_VERSION.to_string(),
-        ProcessContent: process_content,
-    });
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+
+    fn validate_config(&self) -> Result<(), String> {
+        IpRedactionScope::from_env()?;
+        IpRedactor::from_env("IPV4").map(|_| ())
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        self._Scope = IpRedactionScope::from_env()?;
+        self._Redactor = IpRedactor::from_env("IPV4")?.into_shared();
+        Ok(())
+    }
+
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(IPv4Detector::new()))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        CONFIG_KEYS
+    }
+
+    fn on_body(&self, Content: &mut String) -> BodyAction {
+        self.redact_ipv4(Content);
+        BodyAction::Continue
+    }
 }
 
-fn process_content(Content: &mut String) {
-    redact_ipv4_in_content(Content);
-} 
\ No newline at end of file
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(IPv4Detector::new()))
+}
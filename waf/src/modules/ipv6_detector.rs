@@ -1,50 +1,164 @@
 //waf/src/modules/ipv6_detector.rs
 #![allow(non_snake_case)]
 
+use std::net::{IpAddr, Ipv6Addr};
 use regex::Regex;
-use std::sync::OnceLock;
 
-use crate::module::{ModuleInfo, register_module};
+use crate::module::{register_module, BodyAction, FailurePolicy, WafModule, PRIORITY_REDACT, TEXT_CONTENT_TYPES};
+use crate::modules::ip_scope::IpRedactionScope;
+use crate::modules::ipv4_detector;
+use crate::modules::redaction::{continues_token, is_whole_token, replace_spans, IpRedactor, TokenBounds};
 
 pub const MODULE_NAME: &str = "IPv6Detector";
-pub const MODULE_VERSION: &str = "1.0.0";
+pub const MODULE_VERSION: &str = "1.3.0";
+// The redaction, keyring and scope settings the detector is built from
+const CONFIG_KEYS: &[&str] = &[
+    "IPV6_REDACT_STRATEGY", "IPV6_REDACT_TOKEN", "IPV4_REDACT_PREFIX", "IPV6_REDACT_PREFIX",
+    "REDACT_KEYS", "REDACT_KEYS_FILE", "IP_REDACT_SCOPES", "IP_REDACT_ALLOW", "IP_REDACT_DENY",
+];
 
 pub struct IPv6Detector {
     _Pattern: Regex,
+    _Scope: IpRedactionScope,
+    _Redactor: IpRedactor,
 }
 
-fn get_ipv6_regex() -> &'static Regex {
-    static _IPV6_PATTERN: OnceLock<Regex> = OnceLock::new();
-    _IPV6_PATTERN.get_or_init(|| {
-        Regex::new(r"(?i)(([0-9a-f]{1,4}:){7}[0-9a-f]{1,4}|::([0-9a-f]{1,4}:){0,6}[0-9a-f]{1,4}|([0-9a-f]{1,4}:){1,6}:[0-9a-f]{1,4}|([0-9a-f]{1,4}:){1,5}(:[0-9a-f]{1,4}){1,2}|([0-9a-f]{1,4}:){1,4}(:[0-9a-f]{1,4}){1,3}|([0-9a-f]{1,4}:){1,3}(:[0-9a-f]{1,4}){1,4}|([0-9a-f]{1,4}:){1,2}(:[0-9a-f]{1,4}){1,5}|[0-9a-f]{1,4}:((:[0-9a-f]{1,4}){1,6})|:((:[0-9a-f]{1,4}){1,7}|:))").unwrap()
-    })
+// Maximal runs of hex digits, colons and dots with at least two colons, an optional zone ID and
+// prefix length. Parsing decides what they are, "12:34:56" and hex dumps simply fail to parse.
+const IPV6_PATTERN: &str = r"(?i)[0-9a-f:]*:[0-9a-f]*:[0-9a-f:.]*(?:%[0-9a-z_.\-]+)?(?:/[0-9]+)?";
+
+pub struct Ipv6Match {
+    pub Start: usize,
+    pub End: usize,
+    pub Address: Ipv6Addr,
+    pub Prefix: Option<u8>,
+}
+
+// Like the IPv4 detector, an address glued to letters or digits is a fragment of a longer token
+const IPV6_BOUNDS: TokenBounds = TokenBounds { Leading: b".:", Trailing: b".", Continues: continues_token };
+
+// "fe80::1%eth0/64" is split into address, zone and prefix. A sentence's trailing "." or ":" is not
+// part of the address and a prefix length beyond 128 is left out of the match.
+fn parse_candidate(Content: &str, Start: usize, Candidate: &str) -> Option<Ipv6Match> {
+    let Candidate = Candidate.trim_end_matches('.');
+    let (Body, Suffix) = match Candidate.split_once('/') {
+        Some((Body, Suffix)) => (Body, Some(Suffix)),
+        None => (Candidate, None),
+    };
+    let (Text, Zone) = match Body.split_once('%') {
+        Some((Text, Zone)) => (Text, Some(Zone).filter(|Zone| !Zone.is_empty())),
+        None => match Body.strip_suffix(':').filter(|Text| !Text.ends_with(':')) {
+            Some(Text) if Suffix.is_none() => (Text, None),
+            _ => (Body, None),
+        },
+    };
+
+    // A bare "::" is more likely C++ or Rust than the unspecified address. Forms ending in a dotted
+    // quad ("::ffff:192.168.0.1") carry an IPv4 address, which is the IPv4 detector's to rewrite.
+    if !Text.bytes().any(|Byte| Byte.is_ascii_hexdigit()) || Text.contains('.') {
+        return None;
+    }
+    let Address: Ipv6Addr = Text.parse().ok()?;
+    let AddressEnd = Start + Text.len() + Zone.map(|Zone| Zone.len() + 1).unwrap_or(0);
+
+    match Suffix.and_then(|Suffix| Suffix.parse::<u8>().ok()).filter(|Prefix| *Prefix <= 128) {
+        Some(Prefix) if is_whole_token(Content, Start, Start + Candidate.len(), &IPV6_BOUNDS) => {
+            Some(Ipv6Match { Start, End: Start + Candidate.len(), Address, Prefix: Some(Prefix) })
+        }
+        _ if is_whole_token(Content, Start, AddressEnd, &IPV6_BOUNDS) => {
+            Some(Ipv6Match { Start, End: AddressEnd, Address, Prefix: None })
+        }
+        _ => None,
+    }
 }
 
 impl IPv6Detector {
     pub fn new() -> Self {
         IPv6Detector {
-            _Pattern: get_ipv6_regex().clone(),
+            _Pattern: Regex::new(IPV6_PATTERN).unwrap(),
+            _Scope: IpRedactionScope::default(),
+            _Redactor: IpRedactor::default(),
         }
     }
 
+    pub fn find_addresses(&self, Content: &str) -> Vec<Ipv6Match> {
+        self._Pattern.find_iter(Content)
+            .filter_map(|Found| parse_candidate(Content, Found.start(), Found.as_str()))
+            .collect()
+    }
+
     pub fn redact_ipv6(&self, Content: &mut String) {
-        *Content = self._Pattern.replace_all(Content, "[REDACTED]").to_string();
+        let Found: Vec<Ipv6Match> = self.find_addresses(Content).into_iter()
+            .filter(|Match| self._Scope.should_redact(IpAddr::V6(Match.Address)))
+            .collect();
+        replace_spans(Content, Found.iter().map(|Match| (Match.Start..Match.End, self._Redactor.replacement(IpAddr::V6(Match.Address), Match.Prefix))));
     }
 }
 
-pub fn redact_ipv6_in_content(Content: &mut String) {
-    let Detector = IPv6Detector::new();
-    Detector.redact_ipv6(Content);
+impl WafModule for IPv6Detector {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        PRIORITY_REDACT
+    }
+
+    // Has to see "::ffff:192.168.0.1" whole to leave it alone, after the IPv4 detector only "::ffff:" is left
+    fn before(&self) -> &[&str] {
+        &[ipv4_detector::MODULE_NAME]
+    }
+
+    fn content_types(&self) -> &[&str] {
+        TEXT_CONTENT_TYPES
+    }
+
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+
+    fn validate_config(&self) -> Result<(), String> {
+        IpRedactionScope::from_env()?;
+        IpRedactor::from_env("IPV6").map(|_| ())
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        self._Scope = IpRedactionScope::from_env()?;
+        self._Redactor = IpRedactor::from_env("IPV6")?.into_shared();
+        Ok(())
+    }
+
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(IPv6Detector::new()))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        CONFIG_KEYS
+    }
+
+    fn on_body(&self, Content: &mut String) -> BodyAction {
+        self.redact_ipv6(Content);
+        BodyAction::Continue
+    }
 }
 
-pub fn register() {
-    register_module(ModuleInfo {
-        Name: MODULE_NAME.to_string(),
-        Version: MODULE_VERSION.to_string(),
-        ProcessContent: process_content,
-    });
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(IPv6Detector::new()))
 }
 
-fn process_content(Content: &mut String) {
-    redact_ipv6_in_content(Content);
-} 
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::corpus;
+    use crate::modules::ipv4_detector::IPv4Detector;
+
+    // In pipeline order, IPv6 first so mapped addresses are seen whole
+    #[test]
+    fn ip_corpus() {
+        corpus::check("ip_detection.txt", &[&IPv6Detector::new(), &IPv4Detector::new()]);
+    }
+}
@@ -1,16 +1,33 @@
 //waf/src/modules/mod.rs
 #![allow(non_snake_case)]
 
+pub mod ip_scope;
 pub mod ipv4_detector;
 pub mod ipv6_detector;
+pub mod email_detector;
+pub mod card_detector;
+pub mod phone_detector;
+pub mod secret_scanner;
+pub mod redaction;
 pub mod brotli_compressor;
 pub mod cookie_manager;
+pub mod request_filter;
 pub mod dashboard;
+pub mod wasm_plugin;
+#[cfg(test)]
+pub mod corpus;
 
-pub fn init_all() {
-    ipv4_detector::register();
-    ipv6_detector::register();
-    brotli_compressor::register();
-    cookie_manager::register();
-    dashboard::register();
-} 
\ No newline at end of file
+pub fn init_all() -> Result<(), String> {
+    ipv4_detector::register()?;
+    ipv6_detector::register()?;
+    email_detector::register()?;
+    card_detector::register()?;
+    phone_detector::register()?;
+    secret_scanner::register()?;
+    brotli_compressor::register()?;
+    cookie_manager::register()?;
+    request_filter::register()?;
+    wasm_plugin::register_all()?;
+    dashboard::register()?;
+    Ok(())
+}
\ No newline at end of file
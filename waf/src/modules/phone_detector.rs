@@ -0,0 +1,157 @@
+#![allow(non_snake_case)]
+
+use regex::Regex;
+
+use crate::config::env_opt;
+use crate::module::{register_module, BodyAction, FailurePolicy, WafModule, PRIORITY_REDACT_LOOSE, TEXT_CONTENT_TYPES};
+use crate::modules::redaction::{drop_overlapping, is_whole_token, replace_spans, TokenBounds, DEFAULT_MASK};
+
+pub const MODULE_NAME: &str = "PhoneDetector";
+pub const MODULE_VERSION: &str = "1.0.0";
+
+#[derive(Clone, Copy)]
+enum PhoneFormat {
+    // E.164 with the leading +, grouping is free: +44 20 7946 0958, +1 (415) 555-2671, +14155552671
+    International,
+    // North American numbers: (415) 555-2671, 415-555-2671, 415.555.2671
+    Nanp,
+    // National numbers with a trunk prefix: 020 7946 0958, (030) 123456, 01 23 45 67 89
+    Trunk,
+}
+
+const PHONE_PATTERNS: [(PhoneFormat, &str); 3] = [
+    (PhoneFormat::International, r"\+[1-9][0-9]*(?:[ .\-]?(?:\([0-9]{1,4}\)|[0-9]+))*"),
+    (PhoneFormat::Nanp, r"(?:\([2-9][0-9]{2}\) ?|[2-9][0-9]{2}[ .\-])[2-9][0-9]{2}[ .\-][0-9]{4}"),
+    (PhoneFormat::Trunk, r"(?:\(0[0-9]{1,4}\) ?|0[0-9]{1,4}[ .\-])[0-9]{2,8}(?:[ .\-][0-9]{2,8}){0,3}"),
+];
+
+pub struct PhoneDetector {
+    _Patterns: Vec<(PhoneFormat, Regex)>,
+    _Token: String,
+}
+
+pub struct PhoneMatch {
+    pub Start: usize,
+    pub End: usize,
+}
+
+// Dates, versions, decimals and ranges glue digits together with the same separators, so a number
+// that runs on into more digits ("2024-01-01", "020 7946 0958.5", "12:30") is not taken apart
+const PHONE_BOUNDS: TokenBounds = TokenBounds { Leading: b"+.-/", Trailing: b".-/:,", Continues: |Byte| Byte.is_ascii_digit() };
+
+// Digit count per format, and for national numbers one separator throughout. The space after an
+// area code in parentheses does not count, "(415) 555-2671" is written that way.
+fn is_plausible_number(Format: PhoneFormat, Text: &str) -> bool {
+    let Bytes = Text.as_bytes();
+    let Digits = Bytes.iter().filter(|Byte| Byte.is_ascii_digit()).count();
+    let mut Separators = Bytes.iter().enumerate()
+        .filter(|(Index, Byte)| matches!(Byte, b' ' | b'.' | b'-') && !(*Index > 0 && Bytes[Index - 1] == b')'))
+        .map(|(_, Byte)| *Byte);
+    let Consistent = match Separators.next() {
+        Some(First) => Separators.all(|Other| Other == First),
+        None => true,
+    };
+
+    match Format {
+        PhoneFormat::International => (8..=15).contains(&Digits),
+        PhoneFormat::Nanp => Digits == 10 && Consistent,
+        PhoneFormat::Trunk => (9..=11).contains(&Digits) && Consistent,
+    }
+}
+
+// Where the candidate could end: after each digit group, longest first
+fn group_ends(Text: &str) -> impl Iterator<Item = usize> + '_ {
+    let Bytes = Text.as_bytes();
+    (1..=Bytes.len()).rev().filter(move |End| {
+        (Bytes[End - 1].is_ascii_digit() || Bytes[End - 1] == b')') && Bytes.get(*End).is_none_or(|Byte| matches!(Byte, b' ' | b'.' | b'-'))
+    })
+}
+
+impl PhoneDetector {
+    pub fn new() -> Self {
+        PhoneDetector {
+            _Patterns: PHONE_PATTERNS.iter().map(|(Format, Pattern)| (*Format, Regex::new(Pattern).unwrap())).collect(),
+            _Token: String::from(DEFAULT_MASK),
+        }
+    }
+
+    // A candidate that runs on into another number ("020 7946 0958 24h") is shortened group by group
+    pub fn find_numbers(&self, Content: &str) -> Vec<PhoneMatch> {
+        let mut Found: Vec<PhoneMatch> = Vec::new();
+        for (Format, Pattern) in self._Patterns.iter() {
+            for Candidate in Pattern.find_iter(Content) {
+                let Start = Candidate.start();
+                let Text = Candidate.as_str();
+                if let Some(End) = group_ends(Text).find(|End| is_plausible_number(*Format, &Text[..*End]) && is_whole_token(Content, Start, Start + End, &PHONE_BOUNDS)) {
+                    Found.push(PhoneMatch { Start, End: Start + End });
+                }
+            }
+        }
+
+        // "+1 415-555-2671" is found whole and again as a North American number
+        drop_overlapping(&mut Found, |Match| Match.Start..Match.End);
+        Found
+    }
+
+    pub fn redact_numbers(&self, Content: &mut String) {
+        let Found = self.find_numbers(Content);
+        replace_spans(Content, Found.iter().map(|Match| (Match.Start..Match.End, &self._Token)));
+    }
+}
+
+impl WafModule for PhoneDetector {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    // Grouped digits are the loosest shape of all, the stricter detectors get the first look
+    fn priority(&self) -> i32 {
+        PRIORITY_REDACT_LOOSE
+    }
+
+    fn content_types(&self) -> &[&str] {
+        TEXT_CONTENT_TYPES
+    }
+
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+
+    // PHONE_REDACT_TOKEN replaces each number, [REDACTED] unless set
+    fn init(&mut self) -> Result<(), String> {
+        self._Token = env_opt("PHONE_REDACT_TOKEN").unwrap_or_else(|| String::from(DEFAULT_MASK));
+        Ok(())
+    }
+
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(PhoneDetector::new()))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        &["PHONE_REDACT_TOKEN"]
+    }
+
+    fn on_body(&self, Content: &mut String) -> BodyAction {
+        self.redact_numbers(Content);
+        BodyAction::Continue
+    }
+}
+
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(PhoneDetector::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::corpus;
+
+    #[test]
+    fn phone_corpus() {
+        corpus::check("phone_detection.txt", &[&PhoneDetector::new()]);
+    }
+}
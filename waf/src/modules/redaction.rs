@@ -0,0 +1,322 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+use once_cell::sync::Lazy;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::{env_list, env_opt};
+
+pub const DEFAULT_MASK: &str = "[REDACTED]";
+
+// Hex digits of the HMAC kept in a pseudonym, 48 bits is plenty to tell addresses apart in logs
+const PSEUDONYM_DIGITS: usize = 12;
+const MIN_SECRET_LEN: usize = 16;
+
+pub fn continues_token(Byte: u8) -> bool {
+    Byte.is_ascii_alphanumeric() || Byte == b'_'
+}
+
+// Where a detector's candidate may start and end. A token character on either side makes it part of
+// something longer, and so does any of Leading right before it. One of Trailing right after it still
+// ends a sentence or a list, unless Continues holds for the byte after that.
+pub struct TokenBounds {
+    pub Leading: &'static [u8],
+    pub Trailing: &'static [u8],
+    pub Continues: fn(u8) -> bool,
+}
+
+pub fn is_whole_token(Content: &str, Start: usize, End: usize, Bounds: &TokenBounds) -> bool {
+    let Bytes = Content.as_bytes();
+    if Start > 0 && (continues_token(Bytes[Start - 1]) || Bounds.Leading.contains(&Bytes[Start - 1])) {
+        return false;
+    }
+    match Bytes.get(End) {
+        Some(Byte) if Bounds.Trailing.contains(Byte) => !Bytes.get(End + 1).is_some_and(|Next| (Bounds.Continues)(*Next)),
+        Some(Byte) => !continues_token(*Byte),
+        None => true,
+    }
+}
+
+// Of overlapping matches the one starting first, or the longer of two starting together, is kept
+pub fn drop_overlapping<M>(Found: &mut Vec<M>, Span: impl Fn(&M) -> Range<usize>) {
+    Found.sort_by_key(|Match| {
+        let Range { start, end } = Span(Match);
+        (start, std::cmp::Reverse(end))
+    });
+    let mut Last = 0;
+    Found.retain(|Match| {
+        let Range { start, end } = Span(Match);
+        let Keep = start >= Last;
+        if Keep {
+            Last = end;
+        }
+        Keep
+    });
+}
+
+// Swaps each span for its replacement, the spans in order and not overlapping
+pub fn replace_spans<R: AsRef<str>>(Content: &mut String, Spans: impl IntoIterator<Item = (Range<usize>, R)>) {
+    let mut Spans = Spans.into_iter().peekable();
+    if Spans.peek().is_none() {
+        return;
+    }
+
+    let mut Redacted = String::with_capacity(Content.len());
+    let mut Last = 0;
+    for (Span, Replacement) in Spans {
+        Redacted.push_str(&Content[Last..Span.start]);
+        Redacted.push_str(Replacement.as_ref());
+        Last = Span.end;
+    }
+    Redacted.push_str(&Content[Last..]);
+    *Content = Redacted;
+}
+
+#[derive(PartialEq)]
+pub struct RedactionKey {
+    pub Id: String,
+    Secret: Vec<u8>,
+}
+
+impl RedactionKey {
+    // ::ffff:c0a8:1 hashes like 192.168.0.1, so both detectors give an address the same pseudonym
+    fn digest(&self, Address: IpAddr) -> [u8; 32] {
+        let mut Signer = Hmac::<Sha256>::new_from_slice(&self.Secret).expect("HMAC takes keys of any length");
+        match Address.to_canonical() {
+            IpAddr::V4(Address) => Signer.update(&Address.octets()),
+            IpAddr::V6(Address) => Signer.update(&Address.octets()),
+        }
+        Signer.finalize().into_bytes().into()
+    }
+
+    pub fn pseudonym(&self, Address: IpAddr) -> String {
+        let Digest: String = self.digest(Address).iter().map(|Byte| format!("{:02x}", Byte)).collect();
+        format!("[ip:{}:{}]", self.Id, &Digest[..PSEUDONYM_DIGITS])
+    }
+
+    // A syntactically valid address that can never be a real host: 240.0.0.0/4 for IPv4 and the
+    // 2001:db8::/32 documentation range for IPv6, filled with bits of the HMAC
+    pub fn fake_address(&self, Address: IpAddr) -> IpAddr {
+        let Digest = self.digest(Address);
+        match Address {
+            IpAddr::V4(_) => {
+                let Bits = u32::from_be_bytes([Digest[0], Digest[1], Digest[2], Digest[3]]);
+                IpAddr::V4(Ipv4Addr::from(0xF000_0000 | (Bits >> 4)))
+            }
+            IpAddr::V6(_) => {
+                let mut Bytes = [0u8; 16];
+                Bytes.copy_from_slice(&Digest[..16]);
+                IpAddr::V6(Ipv6Addr::from((0x2001_0db8u128 << 96) | (u128::from_be_bytes(Bytes) >> 32)))
+            }
+        }
+    }
+}
+
+// REDACT_KEYS="<id>:<secret>,..." or REDACT_KEYS_FILE with one "<id>:<secret>" per line. The first key
+// signs; the ones after it are retired keys kept so support can still look up what an address was
+// called before a rotation. Rotating is putting a new key first and reloading the detectors.
+#[derive(PartialEq)]
+pub struct Keyring {
+    Keys: Vec<RedactionKey>,
+}
+
+// The keyring the detectors redact with, one instance for both of them and the admin lookup
+static _SHARED_KEYRING: Lazy<RwLock<Arc<Keyring>>> = Lazy::new(|| RwLock::new(Arc::new(Keyring { Keys: Vec::new() })));
+
+impl Keyring {
+    pub fn from_env() -> Result<Self, String> {
+        let (Source, Entries) = match env_opt("REDACT_KEYS_FILE") {
+            Some(Path) => {
+                let Content = fs::read_to_string(&Path).map_err(|e| format!("REDACT_KEYS_FILE {}: {}", Path, e))?;
+                let Entries = Content.lines()
+                    .map(|Line| Line.trim().to_string())
+                    .filter(|Line| !Line.is_empty() && !Line.starts_with('#'))
+                    .collect();
+                ("REDACT_KEYS_FILE", Entries)
+            }
+            None => ("REDACT_KEYS", env_list("REDACT_KEYS")),
+        };
+
+        let mut Keys: Vec<RedactionKey> = Vec::with_capacity(Entries.len());
+        for Entry in Entries.iter() {
+            let (Id, Secret) = Entry.split_once(':').ok_or_else(|| format!("{}: expected <id>:<secret>", Source))?;
+            let Id = Id.trim();
+            if Id.is_empty() || !Id.chars().all(|Char| Char.is_ascii_alphanumeric() || Char == '-' || Char == '_') {
+                return Err(format!("{}: key id '{}' must be letters, digits, '-' or '_'", Source, Id));
+            }
+            if Secret.len() < MIN_SECRET_LEN {
+                return Err(format!("{}: the secret of key '{}' is shorter than {} characters", Source, Id, MIN_SECRET_LEN));
+            }
+            if Keys.iter().any(|Key| Key.Id == Id) {
+                return Err(format!("{}: key id '{}' is used twice", Source, Id));
+            }
+            Keys.push(RedactionKey { Id: Id.to_string(), Secret: Secret.as_bytes().to_vec() });
+        }
+
+        Ok(Self { Keys })
+    }
+
+    pub fn keys(&self) -> &[RedactionKey] {
+        &self.Keys
+    }
+
+    fn active(&self) -> Option<&RedactionKey> {
+        self.Keys.first()
+    }
+
+    // Empty until a detector with a keyed strategy was initialised
+    pub fn shared() -> Arc<Self> {
+        _SHARED_KEYRING.read().unwrap().clone()
+    }
+
+    // Makes this the shared keyring, or hands back the shared one when it holds the same keys
+    fn share(Loaded: Arc<Self>) -> Arc<Self> {
+        let mut Shared = _SHARED_KEYRING.write().unwrap();
+        if **Shared != *Loaded {
+            *Shared = Loaded;
+        }
+        Shared.clone()
+    }
+}
+
+pub enum RedactionStrategy {
+    Mask(String),
+    // Stable keyed HMAC pseudonym, e.g. [ip:k1:3fa9c1d2e5f6]
+    Hash,
+    // Keeps the network part, 192.168.0.17 becomes 192.168.0.0/24
+    Truncate { V4Prefix: u8, V6Prefix: u8 },
+    FormatPreserving,
+}
+
+fn prefix_from_env(Key: &str, Default: u8, Max: u8) -> Result<u8, String> {
+    match env_opt(Key) {
+        Some(Value) => Value.parse::<u8>().ok()
+            .filter(|Prefix| *Prefix <= Max)
+            .ok_or_else(|| format!("{} must be a prefix length between 0 and {}, got '{}'", Key, Max, Value)),
+        None => Ok(Default),
+    }
+}
+
+fn truncate(Address: IpAddr, V4Prefix: u8, V6Prefix: u8) -> (IpAddr, u8) {
+    match Address {
+        IpAddr::V4(Address) => {
+            let Mask = u32::MAX.checked_shl(32 - V4Prefix as u32).unwrap_or(0);
+            (IpAddr::V4(Ipv4Addr::from(u32::from(Address) & Mask)), V4Prefix)
+        }
+        IpAddr::V6(Address) => {
+            let Mask = u128::MAX.checked_shl(128 - V6Prefix as u32).unwrap_or(0);
+            (IpAddr::V6(Ipv6Addr::from(u128::from(Address) & Mask)), V6Prefix)
+        }
+    }
+}
+
+// How one detector rewrites what it found. <FAMILY>_REDACT_STRATEGY is mask (default), hash, truncate
+// or format-preserving; mask writes <FAMILY>_REDACT_TOKEN, truncate keeps IPV4_REDACT_PREFIX (24) or
+// IPV6_REDACT_PREFIX (48) bits. The keyed strategies need a keyring.
+pub struct IpRedactor {
+    Strategy: RedactionStrategy,
+    Keyring: Arc<Keyring>,
+}
+
+impl Default for IpRedactor {
+    fn default() -> Self {
+        Self { Strategy: RedactionStrategy::Mask(String::from(DEFAULT_MASK)), Keyring: Arc::new(Keyring { Keys: Vec::new() }) }
+    }
+}
+
+impl IpRedactor {
+    pub fn from_env(Family: &str) -> Result<Self, String> {
+        let StrategyKey = format!("{}_REDACT_STRATEGY", Family);
+        let Strategy = match env_opt(&StrategyKey).map(|Value| Value.to_lowercase()).as_deref() {
+            None | Some("mask") => RedactionStrategy::Mask(env_opt(&format!("{}_REDACT_TOKEN", Family)).unwrap_or_else(|| String::from(DEFAULT_MASK))),
+            Some("hash") => RedactionStrategy::Hash,
+            Some("truncate") => RedactionStrategy::Truncate {
+                V4Prefix: prefix_from_env("IPV4_REDACT_PREFIX", 24, 32)?,
+                V6Prefix: prefix_from_env("IPV6_REDACT_PREFIX", 48, 128)?,
+            },
+            Some("format-preserving") => RedactionStrategy::FormatPreserving,
+            Some(Other) => return Err(format!("{} '{}' is not one of mask, hash, truncate, format-preserving", StrategyKey, Other)),
+        };
+
+        let Keyring = match Strategy {
+            RedactionStrategy::Hash | RedactionStrategy::FormatPreserving => {
+                let Keyring = Keyring::from_env()?;
+                if Keyring.active().is_none() {
+                    return Err(format!("{} needs REDACT_KEYS or REDACT_KEYS_FILE", StrategyKey));
+                }
+                Arc::new(Keyring)
+            }
+            _ => Arc::new(Keyring { Keys: Vec::new() }),
+        };
+
+        Ok(Self { Strategy, Keyring })
+    }
+
+    // For the detector that is going to use it: a keyed redactor takes the shared keyring, or makes its
+    // freshly loaded keys the shared ones, which is what the admin lookup reports
+    pub fn into_shared(mut self) -> Self {
+        if self.Keyring.active().is_some() {
+            self.Keyring = Keyring::share(self.Keyring);
+        }
+        self
+    }
+
+    // Prefix is the length of a matched CIDR, which stays attached to fake and truncated networks
+    pub fn replacement(&self, Address: IpAddr, Prefix: Option<u8>) -> String {
+        match (&self.Strategy, self.Keyring.active()) {
+            (RedactionStrategy::Mask(Token), _) => Token.clone(),
+            (RedactionStrategy::Truncate { V4Prefix, V6Prefix }, _) => {
+                let (Network, Kept) = truncate(Address, (*V4Prefix).min(Prefix.unwrap_or(32)), (*V6Prefix).min(Prefix.unwrap_or(128)));
+                format!("{}/{}", Network, Kept)
+            }
+            (RedactionStrategy::Hash, Some(Key)) => Key.pseudonym(Address),
+            (RedactionStrategy::FormatPreserving, Some(Key)) => match Prefix {
+                Some(Prefix) => format!("{}/{}", Key.fake_address(Address), Prefix),
+                None => Key.fake_address(Address).to_string(),
+            },
+            // from_env refuses keyed strategies without a key
+            _ => String::from(DEFAULT_MASK),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::set_override;
+
+    fn hash_redactor(Family: &str) -> IpRedactor {
+        let Key = format!("{}_REDACT_STRATEGY", Family);
+        set_override(&Key, Some(String::from("hash")));
+        let Redactor = IpRedactor::from_env(Family);
+        set_override(&Key, None);
+        Redactor.unwrap().into_shared()
+    }
+
+    #[test]
+    fn detectors_and_the_admin_lookup_share_one_keyring() {
+        set_override("REDACT_KEYS", Some(String::from("k2:0123456789abcdef0123,k1:fedcba9876543210fedc")));
+        let V4 = hash_redactor("IPV4");
+        let V6 = hash_redactor("IPV6");
+        assert!(Arc::ptr_eq(&V4.Keyring, &V6.Keyring));
+        assert!(Arc::ptr_eq(&V4.Keyring, &Keyring::shared()));
+
+        // ::ffff:192.168.0.1 is the same host, so both families agree on its pseudonym
+        let Address: IpAddr = "192.168.0.1".parse().unwrap();
+        let Mapped: IpAddr = "::ffff:192.168.0.1".parse().unwrap();
+        assert_eq!(V4.replacement(Address, None), V6.replacement(Mapped, None));
+        assert!(V4.replacement(Address, None).starts_with("[ip:k2:"));
+
+        // A rotation shows up in the shared keyring as soon as a detector loaded it
+        set_override("REDACT_KEYS", Some(String::from("k3:aaaabbbbccccddddeeee,k2:0123456789abcdef0123")));
+        let Rotated = hash_redactor("IPV4");
+        set_override("REDACT_KEYS", None);
+        assert!(Arc::ptr_eq(&Rotated.Keyring, &Keyring::shared()));
+        let Ids: Vec<&str> = Rotated.Keyring.keys().iter().map(|Key| Key.Id.as_str()).collect();
+        assert_eq!(Ids, ["k3", "k2"]);
+    }
+}
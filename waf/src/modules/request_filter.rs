@@ -0,0 +1,307 @@
+#![allow(non_snake_case)]
+
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{HeaderMap, Response, StatusCode};
+use regex::Regex;
+
+use crate::cidr::{parse_cidr_list, IpCidr};
+use crate::config::{env_list, env_opt};
+use crate::module::{register_module, FailurePolicy, RequestAction, RequestContext, WafModule, PRIORITY_FILTER};
+use crate::proxy::body::{full_body, ResponseBody};
+
+pub const MODULE_NAME: &str = "RequestFilter";
+pub const MODULE_VERSION: &str = "1.0.0";
+// Every key RequestFilterConfig::from_env reads
+const CONFIG_KEYS: &[&str] = &[
+    "REQUEST_FILTER_BLOCK_METHODS", "REQUEST_FILTER_DENY_CLIENTS", "REQUEST_FILTER_BLOCK_PATH",
+    "REQUEST_FILTER_CHALLENGE_PATH", "REQUEST_FILTER_BLOCK_BODY", "REQUEST_FILTER_STRIP_HEADERS",
+    "REQUEST_FILTER_STRIP_RESPONSE_HEADERS",
+];
+
+// ../ and ..\ in plain, percent-encoded and overlong forms, REQUEST_FILTER_BLOCK_PATH=traversal turns it on
+const TRAVERSAL_PATH_PATTERN: &str = r"(?i)(\.\.[/\\]|%2e%2e(%2f|%5c|/|\\)|\.\.%2f|\.\.%5c|%c0%ae)";
+
+// Every rule is off until configured: REQUEST_FILTER_BLOCK_METHODS (e.g. TRACE,TRACK),
+// REQUEST_FILTER_DENY_CLIENTS (CIDRs), REQUEST_FILTER_BLOCK_PATH (a pattern or "traversal"),
+// REQUEST_FILTER_CHALLENGE_PATH, REQUEST_FILTER_BLOCK_BODY (needs the body buffered),
+// REQUEST_FILTER_STRIP_HEADERS and REQUEST_FILTER_STRIP_RESPONSE_HEADERS (e.g. Server,X-Powered-By)
+#[derive(Default)]
+pub struct RequestFilterConfig {
+    pub BlockMethods: Vec<String>,
+    pub DenyClients: Vec<String>,
+    pub BlockPath: Option<String>,
+    pub ChallengePath: Option<String>,
+    pub BlockBody: Option<String>,
+    pub StripHeaders: Vec<String>,
+    pub StripResponseHeaders: Vec<String>,
+}
+
+fn pattern_from_env(Key: &str) -> Option<String> {
+    expand_pattern(env_opt(Key))
+}
+
+fn expand_pattern(Value: Option<String>) -> Option<String> {
+    match Value {
+        Some(Pattern) if Pattern.eq_ignore_ascii_case("off") => None,
+        Some(Pattern) if Pattern.eq_ignore_ascii_case("traversal") => Some(String::from(TRAVERSAL_PATH_PATTERN)),
+        Pattern => Pattern,
+    }
+}
+
+impl RequestFilterConfig {
+    pub fn from_env() -> Self {
+        Self {
+            BlockMethods: env_list("REQUEST_FILTER_BLOCK_METHODS").iter().map(|Method| Method.to_uppercase()).collect(),
+            DenyClients: env_list("REQUEST_FILTER_DENY_CLIENTS"),
+            BlockPath: pattern_from_env("REQUEST_FILTER_BLOCK_PATH"),
+            ChallengePath: pattern_from_env("REQUEST_FILTER_CHALLENGE_PATH"),
+            BlockBody: pattern_from_env("REQUEST_FILTER_BLOCK_BODY"),
+            StripHeaders: env_list("REQUEST_FILTER_STRIP_HEADERS"),
+            StripResponseHeaders: env_list("REQUEST_FILTER_STRIP_RESPONSE_HEADERS"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FilterRules {
+    BlockMethods: Vec<String>,
+    DenyClients: Vec<IpCidr>,
+    BlockPath: Option<Regex>,
+    ChallengePath: Option<Regex>,
+    BlockBody: Option<Regex>,
+    StripHeaders: Vec<HeaderName>,
+    StripResponseHeaders: Vec<HeaderName>,
+}
+
+fn compile(Key: &str, Pattern: Option<&String>) -> Result<Option<Regex>, String> {
+    match Pattern {
+        Some(Pattern) => Regex::new(Pattern).map(Some).map_err(|e| format!("{} is not a valid pattern: {}", Key, e)),
+        None => Ok(None),
+    }
+}
+
+fn header_names(Key: &str, Names: &[String]) -> Result<Vec<HeaderName>, String> {
+    Names.iter()
+        .map(|Name| HeaderName::from_bytes(Name.to_lowercase().as_bytes()).map_err(|_| format!("{} has an invalid header name '{}'", Key, Name)))
+        .collect()
+}
+
+impl FilterRules {
+    fn build(Config: &RequestFilterConfig) -> Result<Self, String> {
+        Ok(Self {
+            BlockMethods: Config.BlockMethods.clone(),
+            DenyClients: parse_cidr_list(&Config.DenyClients).map_err(|e| format!("REQUEST_FILTER_DENY_CLIENTS: {}", e))?,
+            BlockPath: compile("REQUEST_FILTER_BLOCK_PATH", Config.BlockPath.as_ref())?,
+            ChallengePath: compile("REQUEST_FILTER_CHALLENGE_PATH", Config.ChallengePath.as_ref())?,
+            BlockBody: compile("REQUEST_FILTER_BLOCK_BODY", Config.BlockBody.as_ref())?,
+            StripHeaders: header_names("REQUEST_FILTER_STRIP_HEADERS", &Config.StripHeaders)?,
+            StripResponseHeaders: header_names("REQUEST_FILTER_STRIP_RESPONSE_HEADERS", &Config.StripResponseHeaders)?,
+        })
+    }
+}
+
+pub struct RequestFilter {
+    _Config: RequestFilterConfig,
+    _Rules: FilterRules,
+}
+
+fn forbidden(Reason: &str) -> Response<ResponseBody> {
+    let mut _Response = Response::new(full_body(format!("Forbidden: {}", Reason)));
+    *_Response.status_mut() = StatusCode::FORBIDDEN;
+    _Response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    _Response
+}
+
+impl RequestFilter {
+    pub fn new(Config: RequestFilterConfig) -> Self {
+        Self {
+            _Config: Config,
+            _Rules: FilterRules::default(),
+        }
+    }
+
+    pub fn inspect(&self, Context: &mut RequestContext) -> RequestAction {
+        let Rules = &self._Rules;
+
+        if Rules.DenyClients.iter().any(|Range| Range.contains(Context.ClientAddr.ip())) {
+            return RequestAction::Block(forbidden("client address denied"));
+        }
+
+        if Rules.BlockMethods.iter().any(|Method| Method == Context.Method.as_str()) {
+            return RequestAction::Block(forbidden("request method not allowed"));
+        }
+
+        let PathAndQuery = Context.Uri.path_and_query().map(|Value| Value.as_str()).unwrap_or("/");
+
+        if let Some(Pattern) = Rules.BlockPath.as_ref() {
+            if Pattern.is_match(PathAndQuery) {
+                return RequestAction::Block(forbidden("request path rejected"));
+            }
+        }
+
+        if let (Some(Pattern), Some(Body)) = (Rules.BlockBody.as_ref(), Context.Body.as_deref()) {
+            if Pattern.is_match(&String::from_utf8_lossy(Body)) {
+                return RequestAction::Block(forbidden("request body rejected"));
+            }
+        }
+
+        let mut Outcome = RequestAction::Allow;
+
+        for Name in Rules.StripHeaders.iter() {
+            if Context.Headers.remove(Name).is_some() {
+                Outcome = RequestAction::Modify;
+            }
+        }
+
+        if let Some(Pattern) = Rules.ChallengePath.as_ref() {
+            if Pattern.is_match(PathAndQuery) {
+                Outcome = RequestAction::Challenge;
+            }
+        }
+
+        Outcome
+    }
+}
+
+impl WafModule for RequestFilter {
+    fn name(&self) -> &str {
+        MODULE_NAME
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        PRIORITY_FILTER
+    }
+
+    fn handles_requests(&self) -> bool {
+        true
+    }
+
+    fn handles_response_headers(&self) -> bool {
+        !self._Config.StripResponseHeaders.is_empty()
+    }
+
+    fn wants_request_body(&self) -> bool {
+        self._Config.BlockBody.is_some()
+    }
+
+    fn failure_policy(&self) -> FailurePolicy {
+        FailurePolicy::Closed
+    }
+
+    fn validate_config(&self) -> Result<(), String> {
+        FilterRules::build(&self._Config).map(|_| ())
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        self._Rules = FilterRules::build(&self._Config)?;
+        Ok(())
+    }
+
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(RequestFilter::new(RequestFilterConfig::from_env())))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        CONFIG_KEYS
+    }
+
+    fn on_request(&self, Context: &mut RequestContext) -> RequestAction {
+        self.inspect(Context)
+    }
+
+    fn on_response_headers(&self, _Status: StatusCode, Headers: &mut HeaderMap) {
+        for Name in self._Rules.StripResponseHeaders.iter() {
+            Headers.remove(Name);
+        }
+    }
+}
+
+pub fn register() -> Result<(), String> {
+    register_module(Box::new(RequestFilter::new(RequestFilterConfig::from_env())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+    use hyper::{Method, Uri};
+
+    #[test]
+    fn no_rules_apply_until_configured() {
+        let Rules = FilterRules::build(&RequestFilterConfig::from_env()).unwrap();
+        assert!(Rules.BlockMethods.is_empty() && Rules.DenyClients.is_empty() && Rules.StripHeaders.is_empty());
+        assert!(Rules.BlockPath.is_none() && Rules.ChallengePath.is_none() && Rules.BlockBody.is_none());
+    }
+
+    #[test]
+    fn traversal_keyword_enables_the_built_in_pattern() {
+        let Config = RequestFilterConfig { ChallengePath: expand_pattern(Some(String::from("Traversal"))), ..Default::default() };
+        assert_eq!(expand_pattern(Some(String::from("OFF"))), None);
+
+        let Pattern = FilterRules::build(&Config).unwrap().ChallengePath.unwrap();
+        for Path in ["/a/../b", "/a/%2E%2e%2Fb", "/a/..%5cb", "/%c0%ae%c0%ae/"] {
+            assert!(Pattern.is_match(Path), "{}", Path);
+        }
+        assert!(!Pattern.is_match("/a/b.c/d..e"));
+    }
+
+    fn filter(Config: RequestFilterConfig) -> RequestFilter {
+        let mut Filter = RequestFilter::new(Config);
+        Filter.init().unwrap();
+        Filter
+    }
+
+    fn inspect(Filter: &RequestFilter, Method: Method, Client: &str, Headers: &mut HeaderMap, Body: Option<&mut Bytes>) -> RequestAction {
+        let mut _Uri: Uri = "/account?id=1".parse().unwrap();
+        let mut Context = RequestContext { Method: &Method, Uri: &mut _Uri, Headers, ClientAddr: Client.parse().unwrap(), Body };
+        Filter.inspect(&mut Context)
+    }
+
+    fn blocked(Action: RequestAction) -> bool {
+        matches!(Action, RequestAction::Block(Response) if Response.status() == StatusCode::FORBIDDEN)
+    }
+
+    #[test]
+    fn denied_clients_are_blocked() {
+        let Filter = filter(RequestFilterConfig { DenyClients: vec![String::from("10.0.0.0/8"), String::from("2001:db8::/32")], ..Default::default() });
+        assert!(blocked(inspect(&Filter, Method::GET, "10.1.2.3:4000", &mut HeaderMap::new(), None)));
+        assert!(blocked(inspect(&Filter, Method::GET, "[2001:db8::7]:4000", &mut HeaderMap::new(), None)));
+        assert!(matches!(inspect(&Filter, Method::GET, "192.0.2.1:4000", &mut HeaderMap::new(), None), RequestAction::Allow));
+    }
+
+    #[test]
+    fn blocked_methods_are_refused() {
+        let Filter = filter(RequestFilterConfig { BlockMethods: vec![String::from("TRACE")], ..Default::default() });
+        assert!(blocked(inspect(&Filter, Method::TRACE, "192.0.2.1:4000", &mut HeaderMap::new(), None)));
+        assert!(matches!(inspect(&Filter, Method::GET, "192.0.2.1:4000", &mut HeaderMap::new(), None), RequestAction::Allow));
+    }
+
+    #[test]
+    fn stripping_a_header_modifies_the_request() {
+        let Filter = filter(RequestFilterConfig { StripHeaders: vec![String::from("X-Debug")], ..Default::default() });
+        let mut Headers = HeaderMap::new();
+        Headers.insert("x-debug", HeaderValue::from_static("1"));
+        Headers.insert("accept", HeaderValue::from_static("*/*"));
+
+        assert!(matches!(inspect(&Filter, Method::GET, "192.0.2.1:4000", &mut Headers, None), RequestAction::Modify));
+        assert!(!Headers.contains_key("x-debug") && Headers.contains_key("accept"));
+        assert!(matches!(inspect(&Filter, Method::GET, "192.0.2.1:4000", &mut Headers, None), RequestAction::Allow));
+    }
+
+    #[test]
+    fn body_patterns_block_buffered_bodies() {
+        let Filter = filter(RequestFilterConfig { BlockBody: Some(String::from("(?i)<script")), ..Default::default() });
+        assert!(Filter.wants_request_body());
+
+        let mut Body = Bytes::from("name=<SCRIPT>alert(1)</script>");
+        assert!(blocked(inspect(&Filter, Method::POST, "192.0.2.1:4000", &mut HeaderMap::new(), Some(&mut Body))));
+        let mut Body = Bytes::from("name=alice");
+        assert!(matches!(inspect(&Filter, Method::POST, "192.0.2.1:4000", &mut HeaderMap::new(), Some(&mut Body)), RequestAction::Allow));
+        // Nothing buffered, nothing to match
+        assert!(matches!(inspect(&Filter, Method::POST, "192.0.2.1:4000", &mut HeaderMap::new(), None), RequestAction::Allow));
+    }
+}
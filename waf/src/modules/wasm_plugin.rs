@@ -0,0 +1,462 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{HeaderMap, Response, StatusCode};
+use once_cell::sync::Lazy;
+use wasmtime::{Config, Engine, Instance, InstancePre, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc, WasmParams, WasmResults};
+
+use crate::config::{env_list, env_opt, env_or};
+use crate::module::{raise_module_fault, register_module, BodyAction, RequestAction, RequestContext, WafModule, PRIORITY_REDACT, TEXT_CONTENT_TYPES};
+use crate::proxy::body::{full_body, ResponseBody};
+
+pub const MODULE_VERSION: &str = "wasm/1";
+
+// Plugin ABI version 1. A plugin is a core wasm module (binary or text) without imports that exports
+//   memory                                          its linear memory
+//   waf_abi_version() -> i32                        must return 1
+//   waf_alloc(len: i32) -> i32                      room for the host to write an input of len bytes
+// and any of
+//   waf_priority() -> i32                           pipeline position, PRIORITY_REDACT when missing
+//   waf_on_request(ptr, len) -> i32                 input "METHOD URI CLIENT_IP\n" + "name: value\n"
+//                                                   per header + "\n" + the buffered body, if any;
+//                                                   0 allows, 1 challenges, 400-599 blocks with it
+//   waf_on_response_headers(status, ptr, len) -> i64  input one "name: value\n" per header
+//   waf_on_body(ptr, len) -> i64                    input the UTF-8 text body
+// An i64 result is ptr << 32 | len of the replacement (all headers, or the whole body), negative
+// keeps the input unchanged. Every call runs in a fresh instance, so plugins keep no state between
+// calls and never need to free anything.
+const ABI_VERSION: i32 = 1;
+
+// WASM_PLUGINS lists plugin files, WASM_PLUGIN_DIR adds every .wasm and .wat file in a directory.
+// WASM_PLUGIN_FUEL bounds the work of a single hook call, WASM_PLUGIN_MEMORY_MB its linear memory.
+const DEFAULT_FUEL: u64 = 10_000_000;
+const DEFAULT_MEMORY_MB: usize = 16;
+
+static _ENGINE: Lazy<Engine> = Lazy::new(|| {
+    Engine::new(Config::new().consume_fuel(true)).expect("wasm engine with fuel metering")
+});
+
+#[derive(Clone, Copy)]
+struct PluginLimits {
+    Fuel: u64,
+    MemoryBytes: usize,
+}
+
+impl PluginLimits {
+    fn from_env() -> Result<Self, String> {
+        let Fuel = env_or("WASM_PLUGIN_FUEL", DEFAULT_FUEL);
+        let MemoryMb = env_or("WASM_PLUGIN_MEMORY_MB", DEFAULT_MEMORY_MB);
+        if Fuel == 0 {
+            return Err(String::from("WASM_PLUGIN_FUEL must be greater than 0"));
+        }
+        if MemoryMb == 0 || MemoryMb > 4096 {
+            return Err(String::from("WASM_PLUGIN_MEMORY_MB must be between 1 and 4096"));
+        }
+        Ok(Self { Fuel, MemoryBytes: MemoryMb * 1024 * 1024 })
+    }
+}
+
+struct LoadedPlugin {
+    Instance: InstancePre<StoreLimits>,
+    Priority: i32,
+    OnRequest: bool,
+    OnResponseHeaders: bool,
+    OnBody: bool,
+}
+
+// One hook call: a fresh store with the fuel and memory budget and a fresh instance of the plugin
+struct PluginCall {
+    Store: Store<StoreLimits>,
+    Instance: Instance,
+    Memory: Memory,
+}
+
+impl PluginCall {
+    fn start(Instance: &InstancePre<StoreLimits>, Limits: PluginLimits) -> Result<Self, String> {
+        let Budget = StoreLimitsBuilder::new()
+            .memory_size(Limits.MemoryBytes)
+            .instances(1)
+            .memories(1)
+            .tables(1)
+            .build();
+        let mut _Store = Store::new(&_ENGINE, Budget);
+        _Store.limiter(|Budget| Budget);
+        _Store.set_fuel(Limits.Fuel).map_err(|e| e.to_string())?;
+
+        let _Instance = Instance.instantiate(&mut _Store).map_err(|e| format!("instantiation failed: {:#}", e))?;
+        let _Memory = _Instance.get_memory(&mut _Store, "memory").ok_or("does not export its memory")?;
+        Ok(Self { Store: _Store, Instance: _Instance, Memory: _Memory })
+    }
+
+    fn func<P: WasmParams, R: WasmResults>(&mut self, Name: &str) -> Result<TypedFunc<P, R>, String> {
+        self.Instance.get_typed_func(&mut self.Store, Name).map_err(|e| format!("{}: {:#}", Name, e))
+    }
+
+    fn call<P: WasmParams, R: WasmResults>(&mut self, Name: &str, Params: P) -> Result<R, String> {
+        let Function = self.func::<P, R>(Name)?;
+        Function.call(&mut self.Store, Params).map_err(|e| format!("{}: {:#}", Name, e))
+    }
+
+    fn write(&mut self, Input: &[u8]) -> Result<(i32, i32), String> {
+        let Length = i32::try_from(Input.len()).map_err(|_| String::from("input too large"))?;
+        let Pointer = self.call::<i32, i32>("waf_alloc", Length)?;
+        self.Memory.write(&mut self.Store, Pointer as u32 as usize, Input)
+            .map_err(|_| String::from("waf_alloc returned memory out of bounds"))?;
+        Ok((Pointer, Length))
+    }
+
+    // None when the plugin kept the input unchanged
+    fn read(&mut self, Packed: i64) -> Result<Option<Vec<u8>>, String> {
+        if Packed < 0 {
+            return Ok(None);
+        }
+        let Pointer = (Packed >> 32) as u32 as usize;
+        let Length = Packed as u32 as usize;
+        // Bounds first: the length is plugin-controlled and must not size an allocation on its own
+        self.Memory.data(&self.Store)
+            .get(Pointer..Pointer + Length)
+            .map(|Output| Some(Output.to_vec()))
+            .ok_or_else(|| String::from("returned a result out of bounds"))
+    }
+}
+
+fn has_export(Module: &Module, Name: &str) -> bool {
+    Module.exports().any(|Export| Export.name() == Name)
+}
+
+fn load_plugin(Path: &Path, Limits: PluginLimits) -> Result<LoadedPlugin, String> {
+    let _Module = Module::from_file(&_ENGINE, Path).map_err(|e| format!("{:#}", e))?;
+
+    if let Some(Import) = _Module.imports().next() {
+        return Err(format!("plugins cannot import anything, it imports {}::{}", Import.module(), Import.name()));
+    }
+
+    let Instance = Linker::new(&_ENGINE).instantiate_pre(&_Module).map_err(|e| format!("{:#}", e))?;
+    let mut Call = PluginCall::start(&Instance, Limits)?;
+
+    let Version = Call.call::<(), i32>("waf_abi_version", ())?;
+    if Version != ABI_VERSION {
+        return Err(format!("speaks plugin ABI {}, this build supports {}", Version, ABI_VERSION));
+    }
+    Call.func::<i32, i32>("waf_alloc")?;
+
+    let Priority = match has_export(&_Module, "waf_priority") {
+        true => Call.call::<(), i32>("waf_priority", ())?,
+        false => PRIORITY_REDACT,
+    };
+
+    // Check the hook signatures now rather than on the first request
+    let OnRequest = has_export(&_Module, "waf_on_request");
+    if OnRequest {
+        Call.func::<(i32, i32), i32>("waf_on_request")?;
+    }
+    let OnResponseHeaders = has_export(&_Module, "waf_on_response_headers");
+    if OnResponseHeaders {
+        Call.func::<(i32, i32, i32), i64>("waf_on_response_headers")?;
+    }
+    let OnBody = has_export(&_Module, "waf_on_body");
+    if OnBody {
+        Call.func::<(i32, i32), i64>("waf_on_body")?;
+    }
+
+    Ok(LoadedPlugin { Instance, Priority, OnRequest, OnResponseHeaders, OnBody })
+}
+
+fn serialize_headers(Headers: &HeaderMap, Output: &mut Vec<u8>) {
+    for (Name, Value) in Headers.iter() {
+        Output.extend_from_slice(Name.as_str().as_bytes());
+        Output.extend_from_slice(b": ");
+        Output.extend_from_slice(Value.as_bytes());
+        Output.push(b'\n');
+    }
+}
+
+fn parse_headers(Block: &[u8]) -> Result<HeaderMap, String> {
+    let mut Headers = HeaderMap::new();
+    for Line in Block.split(|Byte| *Byte == b'\n').filter(|Line| !Line.is_empty()) {
+        let Line = Line.strip_suffix(b"\r").unwrap_or(Line);
+        let Colon = Line.iter().position(|Byte| *Byte == b':')
+            .ok_or_else(|| format!("returned a header line without a colon: {}", String::from_utf8_lossy(Line)))?;
+        let Name = HeaderName::from_bytes(&Line[..Colon])
+            .map_err(|_| format!("returned an invalid header name: {}", String::from_utf8_lossy(&Line[..Colon])))?;
+        let Value = HeaderValue::from_bytes(Line[Colon + 1..].trim_ascii())
+            .map_err(|_| format!("returned an invalid value for header {}", Name))?;
+        Headers.append(Name, Value);
+    }
+    Ok(Headers)
+}
+
+fn blocked(Status: StatusCode, Plugin: &str) -> Response<ResponseBody> {
+    let Reason = Status.canonical_reason().unwrap_or("Blocked");
+    let mut _Response = Response::new(full_body(format!("{}: request rejected by {}", Reason, Plugin)));
+    *_Response.status_mut() = Status;
+    _Response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    _Response
+}
+
+pub struct WasmPlugin {
+    _Name: String,
+    _Path: PathBuf,
+    _Limits: Result<PluginLimits, String>,
+    _Loaded: Option<LoadedPlugin>,
+}
+
+impl WasmPlugin {
+    // The file stem names the module, so plugins/strip_tokens.wasm is configured as MODULE_STRIP_TOKENS_*
+    pub fn new(Path: PathBuf) -> Self {
+        Self {
+            _Name: Path.file_stem().map(|Stem| Stem.to_string_lossy().into_owned()).unwrap_or_default(),
+            _Path: Path,
+            _Limits: PluginLimits::from_env(),
+            _Loaded: None,
+        }
+    }
+
+    fn loaded(&self) -> &LoadedPlugin {
+        match self._Loaded.as_ref() {
+            Some(Loaded) => Loaded,
+            None => raise_module_fault(String::from("plugin was never loaded")),
+        }
+    }
+
+    // Any trap, exhausted fuel or malformed result is a fault of this module
+    fn session<R>(&self, Hook: impl FnOnce(&mut PluginCall) -> Result<R, String>) -> R {
+        let Limits = match self._Limits {
+            Ok(Limits) => Limits,
+            Err(ref e) => raise_module_fault(e.clone()),
+        };
+        match PluginCall::start(&self.loaded().Instance, Limits).and_then(|mut Call| Hook(&mut Call)) {
+            Ok(Result) => Result,
+            Err(e) => raise_module_fault(format!("plugin {}: {}", self._Name, e)),
+        }
+    }
+}
+
+impl WafModule for WasmPlugin {
+    fn name(&self) -> &str {
+        &self._Name
+    }
+
+    fn version(&self) -> &str {
+        MODULE_VERSION
+    }
+
+    fn priority(&self) -> i32 {
+        self._Loaded.as_ref().map(|Loaded| Loaded.Priority).unwrap_or(PRIORITY_REDACT)
+    }
+
+    fn content_types(&self) -> &[&str] {
+        match self._Loaded.as_ref() {
+            Some(Loaded) if Loaded.OnBody => TEXT_CONTENT_TYPES,
+            _ => &[],
+        }
+    }
+
+    fn handles_requests(&self) -> bool {
+        self._Loaded.as_ref().is_some_and(|Loaded| Loaded.OnRequest)
+    }
+
+    fn handles_response_headers(&self) -> bool {
+        self._Loaded.as_ref().is_some_and(|Loaded| Loaded.OnResponseHeaders)
+    }
+
+    fn wants_request_body(&self) -> bool {
+        self.handles_requests()
+    }
+
+    fn validate_config(&self) -> Result<(), String> {
+        if self._Name.is_empty() || !self._Name.chars().all(|Char| Char.is_ascii_alphanumeric() || Char == '_' || Char == '-') {
+            return Err(format!("plugin file name '{}' must be letters, digits, '_' or '-'", self._Path.display()));
+        }
+        self._Limits.as_ref().map(|_| ()).map_err(String::clone)
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        let Limits = self._Limits.clone()?;
+        self._Loaded = Some(load_plugin(&self._Path, Limits).map_err(|e| format!("{}: {}", self._Path.display(), e))?);
+        Ok(())
+    }
+
+    // Picks up a rebuilt plugin file and the current limits
+    fn reload(&self) -> Option<Box<dyn WafModule>> {
+        Some(Box::new(WasmPlugin::new(self._Path.clone())))
+    }
+
+    fn config_keys(&self) -> &[&str] {
+        &["WASM_PLUGIN_FUEL", "WASM_PLUGIN_MEMORY_MB"]
+    }
+
+    fn on_request(&self, Context: &mut RequestContext) -> RequestAction {
+        let mut Input = format!("{} {} {}\n", Context.Method, Context.Uri, Context.ClientAddr.ip()).into_bytes();
+        serialize_headers(Context.Headers, &mut Input);
+        Input.push(b'\n');
+        if let Some(Body) = Context.Body.as_deref() {
+            Input.extend_from_slice(Body);
+        }
+
+        let Verdict = self.session(|Call| {
+            let (Pointer, Length) = Call.write(&Input)?;
+            Call.call::<(i32, i32), i32>("waf_on_request", (Pointer, Length))
+        });
+
+        match Verdict {
+            0 => RequestAction::Allow,
+            1 => RequestAction::Challenge,
+            400..=599 => RequestAction::Block(blocked(StatusCode::from_u16(Verdict as u16).unwrap(), &self._Name)),
+            _ => raise_module_fault(format!("plugin {}: waf_on_request returned unknown verdict {}", self._Name, Verdict)),
+        }
+    }
+
+    fn on_response_headers(&self, Status: StatusCode, Headers: &mut HeaderMap) {
+        let mut Input = Vec::new();
+        serialize_headers(Headers, &mut Input);
+
+        let Replacement = self.session(|Call| {
+            let (Pointer, Length) = Call.write(&Input)?;
+            let Packed = Call.call::<(i32, i32, i32), i64>("waf_on_response_headers", (Status.as_u16() as i32, Pointer, Length))?;
+            Call.read(Packed)?.map(|Block| parse_headers(&Block)).transpose()
+        });
+
+        if let Some(Replacement) = Replacement {
+            *Headers = Replacement;
+        }
+    }
+
+    fn on_body(&self, Content: &mut String) -> BodyAction {
+        let Replacement = self.session(|Call| {
+            let (Pointer, Length) = Call.write(Content.as_bytes())?;
+            let Packed = Call.call::<(i32, i32), i64>("waf_on_body", (Pointer, Length))?;
+            Call.read(Packed)?
+                .map(|Body| String::from_utf8(Body).map_err(|_| String::from("waf_on_body returned invalid UTF-8")))
+                .transpose()
+        });
+
+        if let Some(Replacement) = Replacement {
+            *Content = Replacement;
+        }
+        BodyAction::Continue
+    }
+}
+
+fn plugin_paths() -> Result<Vec<PathBuf>, String> {
+    let mut Paths: Vec<PathBuf> = env_list("WASM_PLUGINS").into_iter().map(PathBuf::from).collect();
+
+    if let Some(Directory) = env_opt("WASM_PLUGIN_DIR") {
+        let Entries = fs::read_dir(&Directory).map_err(|e| format!("WASM_PLUGIN_DIR {}: {}", Directory, e))?;
+        let mut Found: Vec<PathBuf> = Entries
+            .filter_map(|Entry| Entry.ok().map(|Entry| Entry.path()))
+            .filter(|Path| Path.is_file() && Path.extension().is_some_and(|Extension| Extension == "wasm" || Extension == "wat"))
+            .collect();
+        Found.sort();
+        Paths.extend(Found);
+    }
+
+    Ok(Paths)
+}
+
+pub fn register_all() -> Result<(), String> {
+    for Path in plugin_paths()? {
+        register_module(Box::new(WasmPlugin::new(Path)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::{current_pipeline, register_with_policy, FailurePolicy};
+    use hyper::{Method, Uri};
+
+    const MASK_SECRET: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/plugins/mask_secret.wat");
+
+    // The file stem names the module, so every test gets its own copy of the sample plugin
+    fn sample_plugin(Name: &str, Limits: PluginLimits) -> WasmPlugin {
+        let Directory = std::env::temp_dir().join(format!("waf-wasm-{}", std::process::id()));
+        fs::create_dir_all(&Directory).unwrap();
+        let Path = Directory.join(format!("{}.wat", Name));
+        fs::copy(MASK_SECRET, &Path).unwrap();
+        WasmPlugin { _Limits: Ok(Limits), ..WasmPlugin::new(Path) }
+    }
+
+    fn default_limits() -> PluginLimits {
+        PluginLimits { Fuel: DEFAULT_FUEL, MemoryBytes: DEFAULT_MEMORY_MB * 1024 * 1024 }
+    }
+
+    #[test]
+    fn sample_plugin_masks_bodies_and_blocks_requests() {
+        let mut Plugin = sample_plugin("wasm_mask_sample", default_limits());
+        Plugin.validate_config().unwrap();
+        Plugin.init().unwrap();
+        assert_eq!(Plugin.priority(), 450);
+        assert!(Plugin.handles_requests());
+        assert!(!Plugin.handles_response_headers());
+
+        let mut Body = String::from("the Secret is out, secretly");
+        Plugin.on_body(&mut Body);
+        assert_eq!(Body, "the ****** is out, ******ly");
+
+        let mut Untouched = String::from("nothing to hide");
+        Plugin.on_body(&mut Untouched);
+        assert_eq!(Untouched, "nothing to hide");
+
+        let mut _Uri: Uri = "/account".parse().unwrap();
+        let mut Headers = HeaderMap::new();
+        let ClientAddr = "127.0.0.1:4000".parse().unwrap();
+        let mut Context = RequestContext { Method: &Method::GET, Uri: &mut _Uri, Headers: &mut Headers, ClientAddr, Body: None };
+        assert!(matches!(Plugin.on_request(&mut Context), RequestAction::Allow));
+
+        Headers.insert("x-wasm-block", HeaderValue::from_static("1"));
+        let mut Context = RequestContext { Method: &Method::GET, Uri: &mut _Uri, Headers: &mut Headers, ClientAddr, Body: None };
+        match Plugin.on_request(&mut Context) {
+            RequestAction::Block(Response) => assert_eq!(Response.status(), StatusCode::FORBIDDEN),
+            _ => panic!("X-Wasm-Block was not blocked"),
+        }
+    }
+
+    // Returns the body after the pipeline ran only the named plugin, or None when it faulted
+    fn run_registered(Plugin: WasmPlugin, Policy: FailurePolicy, Body: &str) -> Option<String> {
+        let Name = Plugin.name().to_string();
+        register_with_policy(Box::new(Plugin), Policy).unwrap();
+
+        let mut Content = String::from(Body);
+        let Selection = [Name];
+        current_pipeline().process_content(&mut Content, Some("text/plain"), Some(&Selection)).ok().map(|_| Content)
+    }
+
+    #[test]
+    fn exhausted_fuel_follows_the_failure_policy() {
+        let Limits = PluginLimits { Fuel: 50_000, ..default_limits() };
+        let Body = "secret ".repeat(20_000);
+
+        assert_eq!(run_registered(sample_plugin("wasm_fuel_open", Limits), FailurePolicy::Open, &Body), Some(Body.clone()));
+        assert_eq!(run_registered(sample_plugin("wasm_fuel_closed", Limits), FailurePolicy::Closed, &Body), None);
+    }
+
+    #[test]
+    fn memory_limit_breach_follows_the_failure_policy() {
+        // One page, so the plugin cannot grow its memory for an input of more than 63K
+        let Limits = PluginLimits { MemoryBytes: 64 * 1024, ..default_limits() };
+        let Body = "x".repeat(100 * 1024);
+
+        assert_eq!(run_registered(sample_plugin("wasm_memory_open", Limits), FailurePolicy::Open, &Body), Some(Body.clone()));
+        assert_eq!(run_registered(sample_plugin("wasm_memory_closed", Limits), FailurePolicy::Closed, &Body), None);
+
+        // Small enough bodies still go through the plugin
+        let Masked = run_registered(sample_plugin("wasm_memory_small", Limits), FailurePolicy::Closed, "a secret");
+        assert_eq!(Masked.as_deref(), Some("a ******"));
+    }
+
+    #[test]
+    fn results_outside_plugin_memory_are_refused_before_copying() {
+        let Loaded = load_plugin(Path::new(MASK_SECRET), default_limits()).unwrap();
+        let mut Call = PluginCall::start(&Loaded.Instance, default_limits()).unwrap();
+
+        assert_eq!(Call.read(6).unwrap().as_deref(), Some(&b"secret"[..]));
+        assert_eq!(Call.read(-1).unwrap(), None);
+        // A 4G length from one page of memory
+        assert!(Call.read(0xFFFF_FFFF).is_err());
+        assert!(Call.read((65_530 << 32) | 16).is_err());
+    }
+}
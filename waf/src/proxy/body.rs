@@ -0,0 +1,19 @@
+#![allow(non_snake_case)]
+
+use http_body_util::{BodyExt, Full};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+
+pub type BodyError = Box<dyn std::error::Error + Send + Sync>;
+pub type ResponseBody = BoxBody<Bytes, BodyError>;
+pub type RequestBody = BoxBody<Bytes, BodyError>;
+
+pub fn full_body<T: Into<Bytes>>(Content: T) -> ResponseBody {
+    Full::new(Content.into())
+        .map_err(|Never| match Never {})
+        .boxed()
+}
+
+pub fn empty_body() -> ResponseBody {
+    full_body(Bytes::new())
+}
@@ -0,0 +1,409 @@
+#![allow(non_snake_case)]
+
+use hyper::{Response, StatusCode};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::{env_flag, env_or};
+use crate::proxy::body::{full_body, ResponseBody};
+
+const MAX_TRANSITION_HISTORY: usize = 32;
+
+static _TRANSITIONS: Lazy<Mutex<VecDeque<BreakerTransition>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "CLOSED",
+            BreakerState::Open => "OPEN",
+            BreakerState::HalfOpen => "HALF-OPEN",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BreakerTransition {
+    pub Pool: String,
+    pub From: BreakerState,
+    pub To: BreakerState,
+    pub At: SystemTime,
+}
+
+pub struct BreakerSettings {
+    pub Enabled: bool,
+    pub ErrorRatePercent: f64,
+    pub SlowRatePercent: f64,
+    pub SlowCall: Duration,
+    pub MinRequests: u64,
+    pub Window: Duration,
+    pub OpenFor: Duration,
+    pub HalfOpenRequests: u32,
+    pub FallbackStatus: StatusCode,
+    pub FallbackBody: String,
+    pub FallbackContentType: String,
+}
+
+impl BreakerSettings {
+    // UPSTREAM_<NAME>_BREAKER_ENABLED, _BREAKER_ERROR_RATE, _BREAKER_SLOW_RATE, _BREAKER_SLOW_MS,
+    // _BREAKER_MIN_REQUESTS, _BREAKER_WINDOW_SECS, _BREAKER_OPEN_SECS, _BREAKER_HALF_OPEN_REQUESTS
+    // and the response served while open: _FALLBACK_STATUS, _FALLBACK_BODY, _FALLBACK_CONTENT_TYPE
+    pub fn from_env(Prefix: &str) -> Self {
+        let FallbackStatus: u16 = env_or(&format!("{}_FALLBACK_STATUS", Prefix), 503);
+
+        Self {
+            Enabled: env_flag(&format!("{}_BREAKER_ENABLED", Prefix), true),
+            ErrorRatePercent: env_or(&format!("{}_BREAKER_ERROR_RATE", Prefix), 50.0),
+            SlowRatePercent: env_or(&format!("{}_BREAKER_SLOW_RATE", Prefix), 100.0),
+            SlowCall: Duration::from_millis(env_or(&format!("{}_BREAKER_SLOW_MS", Prefix), 5000)),
+            MinRequests: env_or(&format!("{}_BREAKER_MIN_REQUESTS", Prefix), 20u64).max(1),
+            Window: Duration::from_secs(env_or(&format!("{}_BREAKER_WINDOW_SECS", Prefix), 10u64).max(1)),
+            OpenFor: Duration::from_secs(env_or(&format!("{}_BREAKER_OPEN_SECS", Prefix), 30)),
+            HalfOpenRequests: env_or(&format!("{}_BREAKER_HALF_OPEN_REQUESTS", Prefix), 3u32).max(1),
+            FallbackStatus: StatusCode::from_u16(FallbackStatus).unwrap_or(StatusCode::SERVICE_UNAVAILABLE),
+            FallbackBody: env_or(&format!("{}_FALLBACK_BODY", Prefix), String::from("Service temporarily unavailable")),
+            FallbackContentType: env_or(&format!("{}_FALLBACK_CONTENT_TYPE", Prefix), String::from("text/plain; charset=utf-8")),
+        }
+    }
+}
+
+pub enum Admission<'a> {
+    Allowed(BreakerPermit<'a>),
+    Rejected { RetryAfter: Duration },
+}
+
+// Held for the lifetime of one forwarded request. The outcome is recorded against the state the
+// request was admitted in; a permit dropped without an outcome (client gone, future cancelled)
+// gives its half-open slot back instead of holding it forever.
+pub struct BreakerPermit<'a> {
+    Breaker: &'a CircuitBreaker,
+    Generation: u64,
+    State: BreakerState,
+    Settled: bool,
+}
+
+impl BreakerPermit<'_> {
+    pub fn record(mut self, Success: bool, Latency: Duration) {
+        self.Settled = true;
+        self.Breaker.record(self.Generation, self.State, Success, Latency);
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if !self.Settled && self.State == BreakerState::HalfOpen {
+            self.Breaker.release(self.Generation);
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bucket {
+    Second: u64,
+    Total: u64,
+    Errors: u64,
+    Slow: u64,
+}
+
+struct BreakerInner {
+    State: BreakerState,
+    OpenedAt: Instant,
+    Buckets: VecDeque<Bucket>,
+    HalfOpenInFlight: u32,
+    HalfOpenSuccesses: u32,
+    Generation: u64,
+}
+
+pub struct CircuitBreaker {
+    Pool: String,
+    Settings: BreakerSettings,
+    Started: Instant,
+    Inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(Pool: String, Settings: BreakerSettings) -> Self {
+        Self {
+            Pool,
+            Settings,
+            Started: Instant::now(),
+            Inner: Mutex::new(BreakerInner {
+                State: BreakerState::Closed,
+                OpenedAt: Instant::now(),
+                Buckets: VecDeque::new(),
+                HalfOpenInFlight: 0,
+                HalfOpenSuccesses: 0,
+                Generation: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match self.Inner.lock() {
+            Ok(Inner) => Inner.State,
+            Err(_) => BreakerState::Closed,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Admission<'_> {
+        if !self.Settings.Enabled {
+            return Admission::Allowed(self.permit(0, BreakerState::Closed));
+        }
+
+        let mut Inner = match self.Inner.lock() {
+            Ok(Inner) => Inner,
+            Err(_) => return Admission::Allowed(self.permit(0, BreakerState::Closed)),
+        };
+
+        if Inner.State == BreakerState::Open {
+            let Elapsed = Inner.OpenedAt.elapsed();
+            if Elapsed < self.Settings.OpenFor {
+                return Admission::Rejected { RetryAfter: self.Settings.OpenFor - Elapsed };
+            }
+            self.transition(&mut Inner, BreakerState::HalfOpen);
+        }
+
+        if Inner.State == BreakerState::HalfOpen {
+            if Inner.HalfOpenInFlight >= self.Settings.HalfOpenRequests {
+                return Admission::Rejected { RetryAfter: Duration::from_secs(1) };
+            }
+            Inner.HalfOpenInFlight += 1;
+        }
+
+        Admission::Allowed(self.permit(Inner.Generation, Inner.State))
+    }
+
+    fn permit(&self, Generation: u64, State: BreakerState) -> BreakerPermit<'_> {
+        BreakerPermit { Breaker: self, Generation, State, Settled: false }
+    }
+
+    fn release(&self, Generation: u64) {
+        if let Ok(mut Inner) = self.Inner.lock() {
+            if Inner.Generation == Generation {
+                Inner.HalfOpenInFlight = Inner.HalfOpenInFlight.saturating_sub(1);
+            }
+        }
+    }
+
+    fn record(&self, Generation: u64, Admitted: BreakerState, Success: bool, Latency: Duration) {
+        if !self.Settings.Enabled {
+            return;
+        }
+
+        let mut Inner = match self.Inner.lock() {
+            Ok(Inner) => Inner,
+            Err(_) => return,
+        };
+
+        // The breaker has moved on since this request was let through; its outcome belongs to a
+        // state that no longer exists
+        if Inner.Generation != Generation {
+            return;
+        }
+
+        match Admitted {
+            BreakerState::Open => {}
+            BreakerState::HalfOpen => {
+                Inner.HalfOpenInFlight = Inner.HalfOpenInFlight.saturating_sub(1);
+                if !Success || (Latency >= self.Settings.SlowCall && self.Settings.SlowRatePercent < 100.0) {
+                    self.transition(&mut Inner, BreakerState::Open);
+                } else {
+                    Inner.HalfOpenSuccesses += 1;
+                    if Inner.HalfOpenSuccesses >= self.Settings.HalfOpenRequests {
+                        self.transition(&mut Inner, BreakerState::Closed);
+                    }
+                }
+            }
+            BreakerState::Closed => {
+                let Now = self.Started.elapsed().as_secs();
+                let WindowSecs = self.Settings.Window.as_secs();
+                while Inner.Buckets.front().map(|Bucket| Bucket.Second + WindowSecs <= Now).unwrap_or(false) {
+                    Inner.Buckets.pop_front();
+                }
+                if Inner.Buckets.back().map(|Bucket| Bucket.Second != Now).unwrap_or(true) {
+                    Inner.Buckets.push_back(Bucket { Second: Now, ..Bucket::default() });
+                }
+                if let Some(Current) = Inner.Buckets.back_mut() {
+                    Current.Total += 1;
+                    Current.Errors += (!Success) as u64;
+                    Current.Slow += (Latency >= self.Settings.SlowCall) as u64;
+                }
+
+                let (Total, Errors, Slow) = Inner.Buckets.iter()
+                    .fold((0, 0, 0), |(T, E, S), Bucket| (T + Bucket.Total, E + Bucket.Errors, S + Bucket.Slow));
+
+                if Total >= self.Settings.MinRequests {
+                    let ErrorRate = Errors as f64 * 100.0 / Total as f64;
+                    let SlowRate = Slow as f64 * 100.0 / Total as f64;
+                    let SlowTripped = self.Settings.SlowRatePercent < 100.0 && SlowRate >= self.Settings.SlowRatePercent;
+
+                    if ErrorRate >= self.Settings.ErrorRatePercent || SlowTripped {
+                        self.transition(&mut Inner, BreakerState::Open);
+                    }
+                }
+            }
+        }
+    }
+
+    fn transition(&self, Inner: &mut BreakerInner, To: BreakerState) {
+        let From = Inner.State;
+        Inner.State = To;
+        Inner.HalfOpenInFlight = 0;
+        Inner.HalfOpenSuccesses = 0;
+        Inner.Generation += 1;
+
+        match To {
+            BreakerState::Open => Inner.OpenedAt = Instant::now(),
+            BreakerState::Closed => Inner.Buckets.clear(),
+            BreakerState::HalfOpen => {}
+        }
+
+        if let Ok(mut Transitions) = _TRANSITIONS.lock() {
+            if Transitions.len() >= MAX_TRANSITION_HISTORY {
+                Transitions.pop_front();
+            }
+            Transitions.push_back(BreakerTransition {
+                Pool: self.Pool.clone(),
+                From,
+                To,
+                At: SystemTime::now(),
+            });
+        }
+    }
+
+    pub fn fallback_response(&self, RetryAfter: Duration) -> Response<ResponseBody> {
+        Response::builder()
+            .status(self.Settings.FallbackStatus)
+            .header(hyper::header::CONTENT_TYPE, self.Settings.FallbackContentType.as_str())
+            .header(hyper::header::RETRY_AFTER, RetryAfter.as_secs().max(1).to_string())
+            .body(full_body(self.Settings.FallbackBody.clone()))
+            .unwrap_or_else(|_| {
+                let mut Fallback = Response::new(full_body(self.Settings.FallbackBody.clone()));
+                *Fallback.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                Fallback
+            })
+    }
+}
+
+pub fn get_breaker_transitions() -> Vec<BreakerTransition> {
+    match _TRANSITIONS.lock() {
+        Ok(Transitions) => Transitions.iter().rev().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(OpenFor: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(String::from("test"), BreakerSettings {
+            Enabled: true,
+            ErrorRatePercent: 50.0,
+            SlowRatePercent: 100.0,
+            SlowCall: Duration::from_secs(5),
+            MinRequests: 4,
+            Window: Duration::from_secs(10),
+            OpenFor,
+            HalfOpenRequests: 2,
+            FallbackStatus: StatusCode::SERVICE_UNAVAILABLE,
+            FallbackBody: String::new(),
+            FallbackContentType: String::from("text/plain"),
+        })
+    }
+
+    fn admit(Breaker: &CircuitBreaker) -> BreakerPermit<'_> {
+        match Breaker.try_acquire() {
+            Admission::Allowed(Permit) => Permit,
+            Admission::Rejected { .. } => panic!("request rejected in state {:?}", Breaker.state()),
+        }
+    }
+
+    fn trip(Breaker: &CircuitBreaker) {
+        for _ in 0..4 {
+            admit(Breaker).record(false, Duration::ZERO);
+        }
+        assert_eq!(Breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn closes_again_after_enough_half_open_successes() {
+        let Breaker = breaker(Duration::ZERO);
+        for _ in 0..4 {
+            admit(&Breaker).record(true, Duration::ZERO);
+        }
+        assert_eq!(Breaker.state(), BreakerState::Closed);
+
+        trip(&Breaker);
+
+        let First = admit(&Breaker);
+        assert_eq!(Breaker.state(), BreakerState::HalfOpen);
+        let Second = admit(&Breaker);
+        assert!(matches!(Breaker.try_acquire(), Admission::Rejected { .. }));
+
+        First.record(true, Duration::ZERO);
+        Second.record(true, Duration::ZERO);
+        assert_eq!(Breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn a_failed_probe_reopens() {
+        let Breaker = breaker(Duration::ZERO);
+        trip(&Breaker);
+
+        admit(&Breaker).record(false, Duration::ZERO);
+        assert_eq!(Breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn stays_open_until_the_open_period_ends() {
+        let Breaker = breaker(Duration::from_secs(60));
+        trip(&Breaker);
+
+        match Breaker.try_acquire() {
+            Admission::Rejected { RetryAfter } => assert!(RetryAfter > Duration::from_secs(59)),
+            Admission::Allowed(_) => panic!("admitted while open"),
+        };
+    }
+
+    #[test]
+    fn a_dropped_probe_gives_its_slot_back() {
+        let Breaker = breaker(Duration::ZERO);
+        trip(&Breaker);
+
+        for _ in 0..5 {
+            drop(admit(&Breaker));
+        }
+        assert_eq!(Breaker.state(), BreakerState::HalfOpen);
+
+        admit(&Breaker).record(true, Duration::ZERO);
+        admit(&Breaker).record(true, Duration::ZERO);
+        assert_eq!(Breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn outcomes_from_an_earlier_state_are_not_counted_as_probes() {
+        let Breaker = breaker(Duration::ZERO);
+        let Straggler = admit(&Breaker);
+        trip(&Breaker);
+
+        let Probe = admit(&Breaker);
+        assert_eq!(Breaker.state(), BreakerState::HalfOpen);
+
+        // Admitted while closed, finishes during half-open: neither a success nor a failure probe
+        Straggler.record(false, Duration::ZERO);
+        assert_eq!(Breaker.state(), BreakerState::HalfOpen);
+
+        Probe.record(true, Duration::ZERO);
+        admit(&Breaker).record(true, Duration::ZERO);
+        assert_eq!(Breaker.state(), BreakerState::Closed);
+    }
+}
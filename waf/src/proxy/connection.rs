@@ -0,0 +1,17 @@
+#![allow(non_snake_case)]
+
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    pub ClientAddr: SocketAddr,
+    pub IsTls: bool,
+}
+
+// Plain and TLS client streams behind one type so a single serve path handles both
+pub trait ClientIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientIo for T {}
+
+pub type ClientStream = Box<dyn ClientIo>;
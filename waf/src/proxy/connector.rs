@@ -0,0 +1,210 @@
+#![allow(non_snake_case)]
+
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tower_service::Service;
+
+use crate::proxy::body::BodyError;
+#[cfg(unix)]
+use crate::proxy::timeout::within;
+
+const UNIX_PREFIX: &str = "unix:";
+const UNIX_SCHEME: &str = "unix";
+
+// Backends are "host:port" for TCP or "unix:/path/to.sock" for a Unix domain socket
+pub fn is_unix_backend(Address: &str) -> bool {
+    Address.starts_with(UNIX_PREFIX)
+}
+
+// The socket path travels hex-encoded in the authority so the client pools connections per socket
+pub fn backend_uri(Address: &str, PathAndQuery: &str) -> String {
+    match Address.strip_prefix(UNIX_PREFIX) {
+        Some(SocketPath) => {
+            let Encoded: String = SocketPath.bytes().map(|Byte| format!("{:02x}", Byte)).collect();
+            format!("{}://{}{}", UNIX_SCHEME, Encoded, PathAndQuery)
+        }
+        None => format!("http://{}{}", Address, PathAndQuery),
+    }
+}
+
+#[cfg(unix)]
+fn decode_socket_path(Authority: &str) -> Option<String> {
+    if !Authority.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let Bytes: Option<Vec<u8>> = (0..Authority.len())
+        .step_by(2)
+        .map(|Index| u8::from_str_radix(&Authority[Index..Index + 2], 16).ok())
+        .collect();
+
+    String::from_utf8(Bytes?).ok()
+}
+
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    Http: HttpConnector,
+    ConnectTimeout: Option<Duration>,
+}
+
+impl UpstreamConnector {
+    pub fn new(ConnectTimeout: Option<Duration>) -> Self {
+        let mut Http = HttpConnector::new();
+        Http.set_connect_timeout(ConnectTimeout);
+
+        Self { Http, ConnectTimeout }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = BodyError;
+    type Future = Pin<Box<dyn Future<Output = Result<UpstreamStream, BodyError>> + Send>>;
+
+    fn poll_ready(&mut self, Cx: &mut Context<'_>) -> Poll<Result<(), BodyError>> {
+        self.Http.poll_ready(Cx).map_err(|e| e.into())
+    }
+
+    fn call(&mut self, Destination: Uri) -> Self::Future {
+        if Destination.scheme_str() == Some(UNIX_SCHEME) {
+            let ConnectTimeout = self.ConnectTimeout;
+            return Box::pin(async move { connect_unix(Destination, ConnectTimeout).await });
+        }
+
+        let Connecting = self.Http.call(Destination);
+        Box::pin(async move {
+            Connecting.await
+                .map(UpstreamStream::Tcp)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(Destination: Uri, ConnectTimeout: Option<Duration>) -> Result<UpstreamStream, BodyError> {
+    let SocketPath = Destination.host()
+        .and_then(decode_socket_path)
+        .ok_or_else(|| format!("invalid unix socket address in {}", Destination))?;
+
+    match within(ConnectTimeout, "connect", UnixStream::connect(&SocketPath)).await {
+        Ok(Ok(Stream)) => Ok(UpstreamStream::Unix(TokioIo::new(Stream))),
+        Ok(Err(e)) => Err(format!("unix socket {}: {}", SocketPath, e).into()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("unix socket {}: connect timed out", SocketPath)).into()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_Destination: Uri, _ConnectTimeout: Option<Duration>) -> Result<UpstreamStream, BodyError> {
+    Err("unix socket upstreams are not supported on this platform".into())
+}
+
+pub enum UpstreamStream {
+    Tcp(TokioIo<TcpStream>),
+    #[cfg(unix)]
+    Unix(TokioIo<UnixStream>),
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            UpstreamStream::Tcp(Stream) => Stream.connected(),
+            #[cfg(unix)]
+            UpstreamStream::Unix(Stream) => Stream.connected(),
+        }
+    }
+}
+
+impl Read for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, Cx: &mut Context<'_>, Buffer: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(Stream) => Pin::new(Stream).poll_read(Cx, Buffer),
+            #[cfg(unix)]
+            UpstreamStream::Unix(Stream) => Pin::new(Stream).poll_read(Cx, Buffer),
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, Cx: &mut Context<'_>, Data: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(Stream) => Pin::new(Stream).poll_write(Cx, Data),
+            #[cfg(unix)]
+            UpstreamStream::Unix(Stream) => Pin::new(Stream).poll_write(Cx, Data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(Stream) => Pin::new(Stream).poll_flush(Cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(Stream) => Pin::new(Stream).poll_flush(Cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(Stream) => Pin::new(Stream).poll_shutdown(Cx),
+            #[cfg(unix)]
+            UpstreamStream::Unix(Stream) => Pin::new(Stream).poll_shutdown(Cx),
+        }
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::convert::Infallible;
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn unix_addresses_round_trip_through_the_authority() {
+        let Uri: Uri = backend_uri("unix:/run/app.sock", "/x?y=1").parse().unwrap();
+        assert_eq!(Uri.scheme_str(), Some(UNIX_SCHEME));
+        assert_eq!(Uri.path_and_query().unwrap().as_str(), "/x?y=1");
+        assert_eq!(decode_socket_path(Uri.host().unwrap()).as_deref(), Some("/run/app.sock"));
+        assert_eq!(backend_uri("127.0.0.1:8080", "/"), "http://127.0.0.1:8080/");
+        assert_eq!(decode_socket_path("2f7"), None);
+    }
+
+    #[tokio::test]
+    async fn requests_reach_a_unix_socket_backend() {
+        let SocketPath = std::env::temp_dir().join(format!("waf-connector-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&SocketPath);
+        let Listener = UnixListener::bind(&SocketPath).unwrap();
+        tokio::spawn(async move {
+            let (Stream, _) = Listener.accept().await.unwrap();
+            let Service = service_fn(|Request: Request<Incoming>| async move {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(Request.uri().to_string()))))
+            });
+            let _ = hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(Stream), Service).await;
+        });
+
+        let Client: Client<UpstreamConnector, Empty<Bytes>> = Client::builder(TokioExecutor::new()).build(UpstreamConnector::new(Some(Duration::from_secs(1))));
+        let Address = format!("unix:{}", SocketPath.display());
+        let Forward = Request::get(backend_uri(&Address, "/status?full=1")).header(hyper::header::HOST, "localhost").body(Empty::new()).unwrap();
+        let Response = Client.request(Forward).await.unwrap();
+        let Body = Response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(Body, "/status?full=1");
+
+        let _ = std::fs::remove_file(&SocketPath);
+        let Missing = Request::get(backend_uri("unix:/nonexistent/waf.sock", "/")).body(Empty::new()).unwrap();
+        assert!(Client.request(Missing).await.is_err());
+    }
+}
@@ -0,0 +1,261 @@
+#![allow(non_snake_case)]
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, UPGRADE};
+use std::net::IpAddr;
+
+use crate::cidr::{parse_cidr_list, IpCidr};
+use crate::config::env_list;
+use crate::proxy::connection::ConnectionInfo;
+
+// RFC 7230 section 6.1, plus the non-standard Proxy-Connection some clients still send
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+// Also drops every header the Connection header names. With KeepUpgrade the Upgrade handshake
+// survives so it can be replayed to the backend, or back to the client on a 101.
+pub fn strip_hop_by_hop(Headers: &mut HeaderMap, KeepUpgrade: bool) {
+    let Nominated: Vec<HeaderName> = Headers.get_all(CONNECTION)
+        .iter()
+        .filter_map(|Value| Value.to_str().ok())
+        .flat_map(|Value| Value.split(','))
+        .filter_map(|Name| HeaderName::from_bytes(Name.trim().as_bytes()).ok())
+        .collect();
+
+    let UpgradeValue = if KeepUpgrade { Headers.get(UPGRADE).cloned() } else { None };
+
+    for Name in HOP_BY_HOP_HEADERS.iter() {
+        Headers.remove(*Name);
+    }
+    for Name in Nominated.iter() {
+        Headers.remove(Name);
+    }
+
+    if let Some(UpgradeValue) = UpgradeValue {
+        Headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        Headers.insert(UPGRADE, UpgradeValue);
+    }
+}
+
+pub struct ForwardedSettings {
+    pub XForwardedFor: bool,
+    pub XForwardedProto: bool,
+    pub XForwardedHost: bool,
+    pub Forwarded: bool,
+    pub TrustedProxies: Vec<IpCidr>,
+}
+
+impl ForwardedSettings {
+    // FORWARDED_HEADERS=x-forwarded-for,x-forwarded-proto,x-forwarded-host,forwarded
+    // TRUSTED_PROXIES=10.0.0.0/8,192.168.1.10
+    pub fn from_env() -> Result<Self, String> {
+        let mut Enabled = env_list("FORWARDED_HEADERS");
+        if Enabled.is_empty() {
+            Enabled = vec![X_FORWARDED_FOR.to_string(), X_FORWARDED_PROTO.to_string()];
+        }
+        let Enabled: Vec<String> = Enabled.iter().map(|Name| Name.to_lowercase()).collect();
+        let IsEnabled = |Name: &str| Enabled.iter().any(|Item| Item == Name);
+
+        Ok(Self {
+            XForwardedFor: IsEnabled(X_FORWARDED_FOR),
+            XForwardedProto: IsEnabled(X_FORWARDED_PROTO),
+            XForwardedHost: IsEnabled(X_FORWARDED_HOST),
+            Forwarded: IsEnabled("forwarded"),
+            TrustedProxies: parse_cidr_list(&env_list("TRUSTED_PROXIES"))?,
+        })
+    }
+
+    pub fn is_trusted(&self, Address: IpAddr) -> bool {
+        self.TrustedProxies.iter().any(|Range| Range.contains(Address))
+    }
+
+    // A trusted peer already speaks for the client, so its chain is extended.
+    // Anything else could be spoofed and is replaced outright.
+    pub fn apply(&self, Headers: &mut HeaderMap, Connection: &ConnectionInfo, Host: Option<&str>) {
+        let ClientIp = Connection.ClientAddr.ip().to_canonical();
+        let Trusted = self.is_trusted(ClientIp);
+        let Proto = if Connection.IsTls { "https" } else { "http" };
+
+        if self.XForwardedFor {
+            append_or_replace(Headers, X_FORWARDED_FOR, &ClientIp.to_string(), Trusted);
+        }
+
+        if self.XForwardedProto && !(Trusted && Headers.contains_key(X_FORWARDED_PROTO)) {
+            set_header(Headers, X_FORWARDED_PROTO, Proto);
+        }
+
+        if self.XForwardedHost && !(Trusted && Headers.contains_key(X_FORWARDED_HOST)) {
+            match Host {
+                Some(Host) => set_header(Headers, X_FORWARDED_HOST, Host),
+                None => {
+                    Headers.remove(X_FORWARDED_HOST);
+                }
+            }
+        }
+
+        if self.Forwarded {
+            let mut Element = format!("for={};proto={}", forwarded_node(ClientIp), Proto);
+            if let Some(Host) = Host {
+                Element.push_str(&format!(";host={}", forwarded_value(Host)));
+            }
+            append_or_replace(Headers, "forwarded", &Element, Trusted);
+        }
+    }
+}
+
+// RFC 7239 section 6: IPv6 nodes are bracketed and therefore quoted
+fn forwarded_node(Address: IpAddr) -> String {
+    match Address {
+        IpAddr::V4(Address) => Address.to_string(),
+        IpAddr::V6(Address) => format!("\"[{}]\"", Address),
+    }
+}
+
+fn forwarded_value(Value: &str) -> String {
+    let IsToken = !Value.is_empty() && Value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if IsToken {
+        Value.to_string()
+    } else {
+        format!("\"{}\"", Value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn append_or_replace(Headers: &mut HeaderMap, Name: &'static str, Value: &str, Append: bool) {
+    let Existing: Vec<String> = if Append {
+        Headers.get_all(Name)
+            .iter()
+            .filter_map(|Value| Value.to_str().ok())
+            .map(|Value| Value.to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut Chain = Existing.join(", ");
+    if !Chain.is_empty() {
+        Chain.push_str(", ");
+    }
+    Chain.push_str(Value);
+
+    set_header(Headers, Name, &Chain);
+}
+
+fn set_header(Headers: &mut HeaderMap, Name: &'static str, Value: &str) {
+    match HeaderValue::from_str(Value) {
+        Ok(Value) => {
+            Headers.insert(HeaderName::from_static(Name), Value);
+        }
+        Err(_) => {
+            Headers.remove(Name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(Trusted: &[&str]) -> ForwardedSettings {
+        ForwardedSettings {
+            XForwardedFor: true,
+            XForwardedProto: true,
+            XForwardedHost: true,
+            Forwarded: true,
+            TrustedProxies: Trusted.iter().map(|Range| IpCidr::parse(Range).unwrap()).collect(),
+        }
+    }
+
+    fn connection(ClientAddr: &str, IsTls: bool) -> ConnectionInfo {
+        ConnectionInfo { ClientAddr: ClientAddr.parse().unwrap(), IsTls }
+    }
+
+    fn headers(Pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut Headers = HeaderMap::new();
+        for (Name, Value) in Pairs {
+            Headers.append(*Name, HeaderValue::from_static(Value));
+        }
+        Headers
+    }
+
+    #[test]
+    fn hop_by_hop_headers_and_the_ones_connection_names_are_stripped() {
+        let mut Headers = headers(&[
+            ("connection", "keep-alive, x-session-hint"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-connection", "keep-alive"),
+            ("te", "trailers"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-session-hint", "abc"),
+            ("x-kept", "1"),
+        ]);
+        strip_hop_by_hop(&mut Headers, false);
+        assert_eq!(Headers.len(), 1);
+        assert_eq!(Headers["x-kept"], "1");
+    }
+
+    #[test]
+    fn an_upgrade_survives_stripping_when_asked_to() {
+        let mut Headers = headers(&[("connection", "Upgrade, x-drop"), ("upgrade", "websocket"), ("x-drop", "1")]);
+        strip_hop_by_hop(&mut Headers, true);
+        assert_eq!(Headers["connection"], "upgrade");
+        assert_eq!(Headers["upgrade"], "websocket");
+        assert!(!Headers.contains_key("x-drop"));
+    }
+
+    #[test]
+    fn untrusted_peers_get_their_forwarding_headers_replaced() {
+        let mut Headers = headers(&[
+            ("x-forwarded-for", "6.6.6.6"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "spoofed.example"),
+            ("forwarded", "for=6.6.6.6"),
+        ]);
+        settings(&["10.0.0.0/8"]).apply(&mut Headers, &connection("203.0.113.9:5000", false), Some("shop.example"));
+        assert_eq!(Headers["x-forwarded-for"], "203.0.113.9");
+        assert_eq!(Headers["x-forwarded-proto"], "http");
+        assert_eq!(Headers["x-forwarded-host"], "shop.example");
+        assert_eq!(Headers["forwarded"], "for=203.0.113.9;proto=http;host=shop.example");
+    }
+
+    #[test]
+    fn trusted_peers_have_their_chain_extended() {
+        let mut Headers = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-forwarded-for", "198.51.100.2"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=198.51.100.1"),
+        ]);
+        settings(&["10.0.0.0/8"]).apply(&mut Headers, &connection("10.1.2.3:5000", false), None);
+        assert_eq!(Headers["x-forwarded-for"], "198.51.100.1, 198.51.100.2, 10.1.2.3");
+        assert_eq!(Headers["x-forwarded-proto"], "https");
+        assert!(!Headers.contains_key("x-forwarded-host"));
+        assert_eq!(Headers["forwarded"], "for=198.51.100.1, for=10.1.2.3;proto=http");
+    }
+
+    #[test]
+    fn ipv6_clients_and_odd_hosts_are_quoted_in_forwarded() {
+        let mut Headers = HeaderMap::new();
+        let Settings = ForwardedSettings { XForwardedFor: false, XForwardedProto: false, XForwardedHost: false, ..settings(&[]) };
+        Settings.apply(&mut Headers, &connection("[2001:db8::7]:443", true), Some("shop.example:8443"));
+        assert_eq!(Headers["forwarded"], "for=\"[2001:db8::7]\";proto=https;host=\"shop.example:8443\"");
+        assert_eq!(Headers.len(), 1);
+
+        // Mapped IPv4 clients are reported as plain IPv4
+        let mut Headers = HeaderMap::new();
+        settings(&[]).apply(&mut Headers, &connection("[::ffff:192.0.2.4]:80", false), None);
+        assert_eq!(Headers["x-forwarded-for"], "192.0.2.4");
+    }
+}
@@ -0,0 +1,98 @@
+#![allow(non_snake_case)]
+
+use http_body_util::Empty;
+use hyper::body::Bytes;
+use hyper::Request;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, RwLock};
+
+use crate::proxy::breaker::BreakerState;
+use crate::proxy::connector::{backend_uri, is_unix_backend, UpstreamConnector};
+use crate::proxy::upstream::{UpstreamPool, UpstreamRegistry};
+
+static _MONITORED_POOLS: Lazy<RwLock<Vec<Arc<UpstreamPool>>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+pub struct BackendStatus {
+    pub Pool: String,
+    pub Address: String,
+    pub Healthy: bool,
+    pub ActiveConnections: usize,
+    pub ConsecutiveFailures: u32,
+    pub ActiveProbe: bool,
+    pub Breaker: BreakerState,
+}
+
+pub fn start_health_checks(Registry: &UpstreamRegistry) {
+    if let Ok(mut Pools) = _MONITORED_POOLS.write() {
+        *Pools = Registry.pools().to_vec();
+    }
+
+    for Pool in Registry.pools().iter() {
+        if Pool.Policy.Probe.is_none() {
+            continue;
+        }
+
+        let Pool = Pool.clone();
+
+        tokio::spawn(async move {
+            let Probe = match Pool.Policy.Probe.as_ref() {
+                Some(Probe) => Probe,
+                None => return,
+            };
+            let ProbeClient: Client<UpstreamConnector, Empty<Bytes>> =
+                Client::builder(TokioExecutor::new()).build(UpstreamConnector::new(Some(Probe.Timeout)));
+            let mut _ProbeInterval = tokio::time::interval(Probe.Interval);
+
+            loop {
+                _ProbeInterval.tick().await;
+
+                for Backend in Pool.backends().iter() {
+                    let mut ProbeRequest = Request::get(backend_uri(&Backend.Address, &Probe.Path))
+                        .header(hyper::header::USER_AGENT, "cebulka-waf-health");
+                    // The socket path is no use as a Host header
+                    if is_unix_backend(&Backend.Address) {
+                        ProbeRequest = ProbeRequest.header(hyper::header::HOST, "localhost");
+                    }
+                    let ProbeRequest = match ProbeRequest.body(Empty::<Bytes>::new())
+                    {
+                        Ok(ProbeRequest) => ProbeRequest,
+                        Err(_) => continue,
+                    };
+
+                    let Passed = match tokio::time::timeout(Probe.Timeout, ProbeClient.request(ProbeRequest)).await {
+                        Ok(Ok(Response)) => Response.status().as_u16() == Probe.ExpectedStatus,
+                        _ => false,
+                    };
+
+                    Backend.report_probe(Passed);
+                }
+            }
+        });
+    }
+}
+
+pub fn get_upstream_status() -> Vec<BackendStatus> {
+    let Pools = match _MONITORED_POOLS.read() {
+        Ok(Pools) => Pools.clone(),
+        Err(_) => return Vec::new(),
+    };
+
+    let mut Status = Vec::new();
+    for Pool in Pools.iter() {
+        for Backend in Pool.backends().iter() {
+            Status.push(BackendStatus {
+                Pool: Pool.Name.clone(),
+                Address: Backend.Address.clone(),
+                Healthy: Backend.is_healthy(),
+                ActiveConnections: Backend.active_connections(),
+                ConsecutiveFailures: Backend.consecutive_failures(),
+                ActiveProbe: Pool.Policy.Probe.is_some(),
+                Breaker: Pool.Breaker.state(),
+            });
+        }
+    }
+
+    Status
+}
@@ -0,0 +1,123 @@
+#![allow(non_snake_case)]
+
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpListener;
+
+use crate::config::{env_flag, env_list, env_or};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ListenerSpec {
+    pub Address: SocketAddr,
+    pub Tls: bool,
+    pub ProxyProtocol: bool,
+}
+
+impl std::fmt::Display for ListenerSpec {
+    fn fmt(&self, Formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            Formatter,
+            "{}{}://{}",
+            if self.ProxyProtocol { "proxy+" } else { "" },
+            if self.Tls { "https" } else { "http" },
+            self.Address
+        )
+    }
+}
+
+// LISTENERS=http://0.0.0.0:8080,https://[::]:8443 runs several listeners at once, a proxy+ prefix
+// (proxy+https://...) expects a PROXY protocol header first. Without it a single listener binds
+// BIND_ADDRESS:SOURCE_PORT, serves TLS whenever a certificate directory is configured and reads
+// PROXY protocol headers when PROXY_PROTOCOL is set.
+pub fn listeners_from_env(TlsConfigured: bool) -> Result<Vec<ListenerSpec>, String> {
+    let Entries = env_list("LISTENERS");
+
+    if Entries.is_empty() {
+        let BindAddress: String = env_or("BIND_ADDRESS", String::from("127.0.0.1"));
+        let BindIp: IpAddr = strip_brackets(&BindAddress)
+            .parse()
+            .map_err(|_| format!("BIND_ADDRESS '{}' is not an IPv4 or IPv6 address", BindAddress))?;
+        let SourcePort: u16 = env_or("SOURCE_PORT", 2025);
+
+        return Ok(vec![ListenerSpec {
+            Address: SocketAddr::new(BindIp, SourcePort),
+            Tls: TlsConfigured,
+            ProxyProtocol: env_flag("PROXY_PROTOCOL", false),
+        }]);
+    }
+
+    let mut Specs = Vec::new();
+    for Entry in Entries.iter() {
+        let Spec = parse_listener(Entry)?;
+        if Spec.Tls && !TlsConfigured {
+            return Err(format!("listener '{}' uses https but TLS_CERT_DIR is not set", Entry));
+        }
+        if Specs.iter().any(|Existing: &ListenerSpec| Existing.Address == Spec.Address) {
+            return Err(format!("listener address {} is configured twice", Spec.Address));
+        }
+        Specs.push(Spec);
+    }
+
+    Ok(Specs)
+}
+
+// "https://[::1]:8443", "proxy+http://0.0.0.0:80" or a bare "127.0.0.1:8080" for plain HTTP
+fn parse_listener(Entry: &str) -> Result<ListenerSpec, String> {
+    let (ProxyProtocol, Scheme) = match Entry.strip_prefix("proxy+") {
+        Some(Rest) => (true, Rest),
+        None => (false, Entry),
+    };
+
+    let (Tls, Address) = if let Some(Address) = Scheme.strip_prefix("https://") {
+        (true, Address)
+    } else if let Some(Address) = Scheme.strip_prefix("http://") {
+        (false, Address)
+    } else if Scheme.contains("://") {
+        return Err(format!("listener '{}' must use http://, https:// or a proxy+ variant", Entry));
+    } else {
+        (false, Scheme)
+    };
+
+    let Address: SocketAddr = Address.trim_end_matches('/')
+        .parse()
+        .map_err(|_| format!("listener '{}' is not a valid address, expected ip:port or [ipv6]:port", Entry))?;
+
+    Ok(ListenerSpec { Address, Tls, ProxyProtocol })
+}
+
+fn strip_brackets(Value: &str) -> &str {
+    Value.trim().trim_start_matches('[').trim_end_matches(']')
+}
+
+pub async fn bind_listener(Spec: &ListenerSpec) -> Result<TcpListener, String> {
+    TcpListener::bind(Spec.Address).await.map_err(|e| {
+        let Hint = match e.kind() {
+            ErrorKind::AddrInUse => " (another process is already listening on this port)",
+            ErrorKind::PermissionDenied if Spec.Address.port() < 1024 => " (ports below 1024 need elevated privileges)",
+            ErrorKind::PermissionDenied => " (permission denied)",
+            ErrorKind::AddrNotAvailable => " (the address does not belong to any local interface)",
+            _ => "",
+        };
+        format!("cannot bind {}: {}{}", Spec, e, Hint)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_entries_are_parsed() {
+        let Spec = parse_listener("proxy+https://[::1]:8443/").unwrap();
+        assert_eq!((Spec.Address, Spec.Tls, Spec.ProxyProtocol), ("[::1]:8443".parse().unwrap(), true, true));
+        assert_eq!(Spec.to_string(), "proxy+https://[::1]:8443");
+
+        let Spec = parse_listener("127.0.0.1:8080").unwrap();
+        assert_eq!((Spec.Tls, Spec.ProxyProtocol), (false, false));
+        assert_eq!(parse_listener("http://0.0.0.0:80").unwrap().Address.port(), 80);
+
+        assert!(parse_listener("ftp://0.0.0.0:21").is_err());
+        assert!(parse_listener("http://localhost:80").is_err());
+        assert!(parse_listener("::1:8080").is_err());
+    }
+}
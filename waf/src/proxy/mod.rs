@@ -0,0 +1,19 @@
+#![allow(non_snake_case)]
+
+pub mod body;
+pub mod breaker;
+pub mod connection;
+pub mod connector;
+pub mod headers;
+pub mod health;
+pub mod listener;
+pub mod policy;
+pub mod proxy_protocol;
+pub mod retry;
+pub mod router;
+pub mod server;
+pub mod stream;
+pub mod timeout;
+pub mod tls;
+pub mod tunnel;
+pub mod upstream;
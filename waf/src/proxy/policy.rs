@@ -0,0 +1,20 @@
+#![allow(non_snake_case)]
+
+use crate::proxy::retry::{RetryBudget, RetrySettings};
+use crate::proxy::timeout::TimeoutSettings;
+
+pub struct UpstreamPolicy {
+    pub Timeouts: TimeoutSettings,
+    pub Retry: RetrySettings,
+    pub Budget: RetryBudget,
+}
+
+impl UpstreamPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            Timeouts: TimeoutSettings::from_env(),
+            Retry: RetrySettings::from_env(),
+            Budget: RetryBudget::from_env(),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+#![allow(non_snake_case)]
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::cidr::{parse_cidr_list, IpCidr};
+use crate::config::{env_list, env_or};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+// "PROXY TCP6 " plus two full IPv6 addresses, two ports and CRLF
+const V1_MAX_LENGTH: usize = 107;
+// The shortest valid header of either version ("PROXY UNKNOWN\r\n" is 15 bytes), safe to read up front
+const SIGNATURE_PROBE_LENGTH: usize = 12;
+
+pub struct ProxyProtocolSettings {
+    pub Timeout: Duration,
+    pub TrustedBalancers: Vec<IpCidr>,
+}
+
+impl ProxyProtocolSettings {
+    // PROXY_PROTOCOL_TIMEOUT_MS bounds the wait for the header, PROXY_PROTOCOL_TRUSTED lists the
+    // balancers allowed to send it. Anyone else could claim any client address, so a PROXY protocol
+    // listener without trusted balancers is a configuration error rather than an open door.
+    pub fn from_env(Required: bool) -> Result<Self, String> {
+        let TrustedBalancers = parse_cidr_list(&env_list("PROXY_PROTOCOL_TRUSTED"))?;
+        if Required && TrustedBalancers.is_empty() {
+            return Err(String::from("PROXY_PROTOCOL_TRUSTED must list the balancers allowed to send PROXY protocol headers"));
+        }
+
+        Ok(Self {
+            Timeout: Duration::from_millis(env_or("PROXY_PROTOCOL_TIMEOUT_MS", 3000)),
+            TrustedBalancers,
+        })
+    }
+
+    pub fn is_trusted(&self, Peer: IpAddr) -> bool {
+        self.TrustedBalancers.iter().any(|Range| Range.contains(Peer))
+    }
+
+    // Consumes the header from the front of the stream and returns the address it carries.
+    // LOCAL (v2) and UNKNOWN (v1) connections, such as balancer health checks, keep the peer address.
+    pub async fn accept<S: AsyncRead + Unpin>(&self, Stream: &mut S, Peer: SocketAddr) -> Result<SocketAddr, String> {
+        if !self.is_trusted(Peer.ip()) {
+            return Err(format!("{} is not a trusted PROXY protocol peer", Peer));
+        }
+
+        match tokio::time::timeout(self.Timeout, read_header(Stream)).await {
+            Ok(Ok(Some(Source))) => Ok(Source),
+            Ok(Ok(None)) => Ok(Peer),
+            Ok(Err(e)) => Err(format!("{}: {}", Peer, e)),
+            Err(_) => Err(format!("{}: timed out waiting for PROXY protocol header", Peer)),
+        }
+    }
+}
+
+async fn read_header<S: AsyncRead + Unpin>(Stream: &mut S) -> Result<Option<SocketAddr>, String> {
+    let mut Header = vec![0u8; SIGNATURE_PROBE_LENGTH];
+    Stream.read_exact(&mut Header).await.map_err(|e| e.to_string())?;
+
+    if Header.as_slice() == V2_SIGNATURE {
+        return read_v2(Stream).await;
+    }
+
+    if Header.starts_with(V1_PREFIX) {
+        return read_v1(Stream, Header).await;
+    }
+
+    Err(String::from("connection did not start with a PROXY protocol header"))
+}
+
+// Read byte by byte so nothing past the CRLF is taken from the client's own data
+async fn read_v1<S: AsyncRead + Unpin>(Stream: &mut S, mut Line: Vec<u8>) -> Result<Option<SocketAddr>, String> {
+    while !Line.ends_with(b"\r\n") {
+        if Line.len() >= V1_MAX_LENGTH {
+            return Err(String::from("PROXY v1 header exceeds 107 bytes"));
+        }
+        Line.push(Stream.read_u8().await.map_err(|e| e.to_string())?);
+    }
+
+    let Line = std::str::from_utf8(&Line[..Line.len() - 2]).map_err(|_| String::from("PROXY v1 header is not ASCII"))?;
+    let Fields: Vec<&str> = Line.split(' ').collect();
+
+    match Fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", Family, Source, _Destination, SourcePort, _DestinationPort] => {
+            let Address: IpAddr = Source.parse().map_err(|_| format!("invalid PROXY v1 source address '{}'", Source))?;
+            let Port: u16 = SourcePort.parse().map_err(|_| format!("invalid PROXY v1 source port '{}'", SourcePort))?;
+
+            match (*Family, Address) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(Some(SocketAddr::new(Address, Port))),
+                _ => Err(format!("PROXY v1 family {} does not match address {}", Family, Address)),
+            }
+        }
+        _ => Err(String::from("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(Stream: &mut S) -> Result<Option<SocketAddr>, String> {
+    let mut Fixed = [0u8; 4];
+    Stream.read_exact(&mut Fixed).await.map_err(|e| e.to_string())?;
+
+    let VersionCommand = Fixed[0];
+    let Family = Fixed[1];
+    let Length = u16::from_be_bytes([Fixed[2], Fixed[3]]) as usize;
+
+    if VersionCommand >> 4 != 2 {
+        return Err(format!("unsupported PROXY protocol version {}", VersionCommand >> 4));
+    }
+
+    // The address block is followed by TLVs, which are read to keep the stream aligned and then ignored
+    let mut Payload = vec![0u8; Length];
+    Stream.read_exact(&mut Payload).await.map_err(|e| e.to_string())?;
+
+    match VersionCommand & 0x0F {
+        0x0 => return Ok(None),
+        0x1 => {}
+        Command => return Err(format!("unsupported PROXY v2 command {}", Command)),
+    }
+
+    // High nibble is the address family, low nibble the transport (stream or datagram)
+    match Family >> 4 {
+        0x1 if Payload.len() >= 12 => {
+            let Address = Ipv4Addr::new(Payload[0], Payload[1], Payload[2], Payload[3]);
+            let Port = u16::from_be_bytes([Payload[8], Payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(Address), Port)))
+        }
+        0x2 if Payload.len() >= 36 => {
+            let mut Octets = [0u8; 16];
+            Octets.copy_from_slice(&Payload[0..16]);
+            let Port = u16::from_be_bytes([Payload[32], Payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(Octets)), Port)))
+        }
+        0x1 | 0x2 => Err(String::from("PROXY v2 address block is truncated")),
+        // AF_UNSPEC and AF_UNIX carry nothing usable as a client address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(Header: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let mut Stream = Header;
+        let Result = read_header(&mut Stream).await;
+        (Result, Stream.to_vec())
+    }
+
+    fn v2(Command: u8, Family: u8, Payload: &[u8]) -> Vec<u8> {
+        let mut Header = V2_SIGNATURE.to_vec();
+        Header.extend_from_slice(&[0x20 | Command, Family]);
+        Header.extend_from_slice(&(Payload.len() as u16).to_be_bytes());
+        Header.extend_from_slice(Payload);
+        Header
+    }
+
+    #[tokio::test]
+    async fn v1_headers_carry_the_source_and_leave_the_request_alone() {
+        let (Source, Rest) = parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(Source.unwrap(), Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(Rest, b"GET / HTTP/1.1\r\n");
+
+        let (Source, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 443\r\n").await;
+        assert_eq!(Source.unwrap(), Some("[2001:db8::1]:51000".parse().unwrap()));
+
+        let (Source, Rest) = parse(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(Source.unwrap(), None);
+        assert_eq!(Rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn malformed_v1_headers_are_refused() {
+        for Header in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 51000"[..],
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443",
+            b"PROXY TCP6 203.0.113.7 10.0.0.1 51000 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 99999 443\r\n",
+            b"PROXY TCP4 not-an-address 10.0.0.1 51000 443\r\n",
+        ] {
+            assert!(parse(Header).await.0.is_err(), "{}", String::from_utf8_lossy(Header));
+        }
+
+        let Overlong = format!("PROXY TCP4 {}\r\n", "1".repeat(120));
+        assert!(parse(Overlong.as_bytes()).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn anything_but_a_signature_is_refused() {
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.0.is_err());
+        assert!(parse(b"\r\n\r\n\0\r\nQUIX\n\x21\x11\0\0").await.0.is_err());
+        assert!(parse(b"PROXY").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_headers_carry_the_source() {
+        let mut Payload = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        Payload.extend_from_slice(&51000u16.to_be_bytes());
+        Payload.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV after the addresses is consumed and ignored
+        Payload.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let mut Header = v2(0x1, 0x11, &Payload);
+        Header.extend_from_slice(b"GET");
+
+        let (Source, Rest) = parse(&Header).await;
+        assert_eq!(Source.unwrap(), Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(Rest, b"GET");
+
+        let mut Payload = vec![0u8; 36];
+        Payload[15] = 1;
+        Payload[32..34].copy_from_slice(&8080u16.to_be_bytes());
+        assert_eq!(parse(&v2(0x1, 0x21, &Payload)).await.0.unwrap(), Some("[::1]:8080".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_unknown_and_unix_keep_the_peer_address() {
+        let (Source, Rest) = parse(&[v2(0x0, 0x11, &[0u8; 12]), b"GET".to_vec()].concat()).await;
+        assert_eq!(Source.unwrap(), None);
+        assert_eq!(Rest, b"GET");
+
+        assert_eq!(parse(&v2(0x1, 0x00, &[])).await.0.unwrap(), None);
+        assert_eq!(parse(&v2(0x1, 0x31, &[0u8; 216])).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_v2_headers_are_refused() {
+        // The address block is shorter than its family needs
+        assert!(parse(&v2(0x1, 0x11, &[203, 0, 113, 7])).await.0.is_err());
+        assert!(parse(&v2(0x1, 0x21, &[0u8; 20])).await.0.is_err());
+
+        // The stream ends before the announced length
+        let mut Header = v2(0x1, 0x11, &[0u8; 12]);
+        Header.truncate(Header.len() - 5);
+        assert!(parse(&Header).await.0.is_err());
+        assert!(parse(&V2_SIGNATURE[..]).await.0.is_err());
+
+        // Unknown version or command
+        let mut Header = v2(0x1, 0x11, &[0u8; 12]);
+        Header[12] = 0x11;
+        assert!(parse(&Header).await.0.is_err());
+        assert!(parse(&v2(0x2, 0x11, &[0u8; 12])).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_trusted_balancers_are_believed() {
+        let Settings = ProxyProtocolSettings { Timeout: Duration::from_secs(1), TrustedBalancers: Vec::new() };
+        let mut Stream = &b"PROXY UNKNOWN\r\n"[..];
+        assert!(Settings.accept(&mut Stream, "10.0.0.9:4000".parse().unwrap()).await.is_err());
+
+        let Settings = ProxyProtocolSettings { TrustedBalancers: parse_cidr_list(&[String::from("10.0.0.0/24")]).unwrap(), ..Settings };
+        let mut Stream = &b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\n"[..];
+        assert_eq!(Settings.accept(&mut Stream, "10.0.0.9:4000".parse().unwrap()).await.unwrap(), "203.0.113.7:51000".parse().unwrap());
+        let mut Stream = &b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\n"[..];
+        assert!(Settings.accept(&mut Stream, "10.0.1.9:4000".parse().unwrap()).await.is_err());
+    }
+}
@@ -0,0 +1,87 @@
+#![allow(non_snake_case)]
+
+use hyper::Method;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::env_or;
+
+pub struct RetrySettings {
+    pub MaxRetries: u32,
+    pub BackoffBase: Duration,
+    pub BackoffMax: Duration,
+}
+
+impl RetrySettings {
+    // UPSTREAM_MAX_RETRIES, UPSTREAM_RETRY_BACKOFF_MS, UPSTREAM_RETRY_BACKOFF_MAX_MS
+    pub fn from_env() -> Self {
+        Self {
+            MaxRetries: env_or("UPSTREAM_MAX_RETRIES", 2),
+            BackoffBase: Duration::from_millis(env_or("UPSTREAM_RETRY_BACKOFF_MS", 50)),
+            BackoffMax: Duration::from_millis(env_or("UPSTREAM_RETRY_BACKOFF_MAX_MS", 1000)),
+        }
+    }
+
+    // Exponential with full jitter, so retrying clients don't hit a recovering backend in lockstep
+    pub fn backoff(&self, Attempt: u32) -> Duration {
+        let Ceiling = self.BackoffBase
+            .saturating_mul(2u32.saturating_pow(Attempt))
+            .min(self.BackoffMax);
+        Ceiling.mul_f64(rand::random::<f64>())
+    }
+}
+
+// Every request earns Ratio of a retry and every retry spends one, with MinPerSecond trickling in so
+// low traffic can still retry. An outage therefore can't multiply the load on the backends.
+pub struct RetryBudget {
+    Ratio: f64,
+    MinPerSecond: f64,
+    MaxTokens: f64,
+    State: Mutex<(f64, Instant)>,
+}
+
+impl RetryBudget {
+    // UPSTREAM_RETRY_BUDGET_RATIO, UPSTREAM_RETRY_BUDGET_MIN_PER_SEC
+    pub fn from_env() -> Self {
+        let Ratio: f64 = env_or("UPSTREAM_RETRY_BUDGET_RATIO", 0.2);
+        let MinPerSecond: f64 = env_or("UPSTREAM_RETRY_BUDGET_MIN_PER_SEC", 5.0);
+        let MaxTokens = (MinPerSecond * 10.0).max(10.0);
+
+        Self {
+            Ratio,
+            MinPerSecond,
+            MaxTokens,
+            State: Mutex::new((MaxTokens, Instant::now())),
+        }
+    }
+
+    fn refill(&self, State: &mut (f64, Instant), Deposit: f64) {
+        let Elapsed = State.1.elapsed().as_secs_f64();
+        State.0 = (State.0 + Elapsed * self.MinPerSecond + Deposit).min(self.MaxTokens);
+        State.1 = Instant::now();
+    }
+
+    pub fn deposit(&self) {
+        if let Ok(mut State) = self.State.lock() {
+            self.refill(&mut State, self.Ratio);
+        }
+    }
+
+    pub fn try_withdraw(&self) -> bool {
+        if let Ok(mut State) = self.State.lock() {
+            self.refill(&mut State, 0.0);
+            if State.0 >= 1.0 {
+                State.0 -= 1.0;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+pub fn is_idempotent(RequestMethod: &Method) -> bool {
+    matches!(
+        *RequestMethod,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
@@ -0,0 +1,231 @@
+#![allow(non_snake_case)]
+
+use hyper::Method;
+use regex::Regex;
+use std::sync::Arc;
+
+use crate::config::{env_flag, env_list, env_opt};
+use crate::module::is_module_registered;
+use crate::proxy::upstream::{host_matches, strip_port, UpstreamPool, UpstreamRegistry};
+
+pub struct Route {
+    Hosts: Vec<String>,
+    PathPrefix: Option<String>,
+    PathRegex: Option<Regex>,
+    Methods: Vec<Method>,
+    pub Pool: Option<Arc<UpstreamPool>>,
+    pub Modules: Option<Arc<[String]>>,
+    pub Captcha: bool,
+}
+
+impl Route {
+    // Requests no route claims keep the old behaviour: pool by Host, every module, captcha on
+    fn fallback() -> Self {
+        Self {
+            Hosts: Vec::new(),
+            PathPrefix: None,
+            PathRegex: None,
+            Methods: Vec::new(),
+            Pool: None,
+            Modules: None,
+            Captcha: true,
+        }
+    }
+
+    fn matches(&self, Method: &Method, Host: Option<&str>, Path: &str) -> bool {
+        if !self.Methods.is_empty() && !self.Methods.contains(Method) {
+            return false;
+        }
+
+        if !self.Hosts.is_empty() {
+            let Host = match Host {
+                Some(Host) => strip_port(Host).to_lowercase(),
+                None => return false,
+            };
+            if !self.Hosts.iter().any(|Pattern| host_matches(Pattern, &Host)) {
+                return false;
+            }
+        }
+
+        if let Some(Prefix) = self.PathPrefix.as_ref() {
+            if !path_has_prefix(Path, Prefix) {
+                return false;
+            }
+        }
+
+        if let Some(Pattern) = self.PathRegex.as_ref() {
+            if !Pattern.is_match(Path) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// "/api" covers "/api" and "/api/users" but not "/apiary"
+fn path_has_prefix(Path: &str, Prefix: &str) -> bool {
+    match Path.strip_prefix(Prefix) {
+        Some(Rest) => Prefix.ends_with('/') || Rest.is_empty() || Rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn decode_percent(Path: &str) -> String {
+    let Bytes = Path.as_bytes();
+    let mut Decoded = Vec::with_capacity(Bytes.len());
+    let mut Index = 0;
+    while Index < Bytes.len() {
+        let Escaped = Bytes.get(Index + 1..Index + 3)
+            .and_then(|Hex| std::str::from_utf8(Hex).ok())
+            .and_then(|Hex| u8::from_str_radix(Hex, 16).ok());
+        match (Bytes[Index], Escaped) {
+            (b'%', Some(Byte)) => {
+                Decoded.push(Byte);
+                Index += 3;
+            }
+            (Byte, _) => {
+                Decoded.push(Byte);
+                Index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&Decoded).into_owned()
+}
+
+// Routes are matched against the path the backend will end up serving, so "/public/../admin" or
+// "/public/%2e%2e/admin" cannot borrow the settings of a "/public" route. Escapes are decoded, backslashes
+// and repeated slashes count as one "/" and "." / ".." segments are applied. The request is forwarded as sent.
+pub fn normalize_path(Path: &str) -> String {
+    let Decoded = decode_percent(Path).replace('\\', "/");
+    let mut Segments: Vec<&str> = Vec::new();
+    for Segment in Decoded.split('/').filter(|Segment| !Segment.is_empty()) {
+        match Segment {
+            "." => {}
+            ".." => {
+                Segments.pop();
+            }
+            Segment => Segments.push(Segment),
+        }
+    }
+
+    let TrailingSlash = Decoded.ends_with('/') || Decoded.ends_with("/.") || Decoded.ends_with("/..");
+    let mut Normalized = format!("/{}", Segments.join("/"));
+    if TrailingSlash && !Segments.is_empty() {
+        Normalized.push('/');
+    }
+    Normalized
+}
+
+pub struct RoutingTable {
+    Routes: Vec<Route>,
+    Fallback: Route,
+}
+
+impl RoutingTable {
+    // ROUTES=api,static lists routes in match order, the first one that matches wins
+    // ROUTE_API_HOSTS=api.example.com,*.example.org
+    // ROUTE_API_PATH_PREFIX=/v1 or ROUTE_API_PATH_REGEX=^/v[0-9]+/, both see the normalized path
+    // ROUTE_API_METHODS=GET,POST
+    // ROUTE_API_UPSTREAM=api (a pool from UPSTREAM_POOLS, otherwise the pool is picked by Host)
+    // ROUTE_API_MODULES=IPv4Detector,IPv6Detector (unset runs every module, "none" runs none)
+    // ROUTE_API_CAPTCHA=false
+    pub fn from_env(Upstreams: &UpstreamRegistry) -> Result<Self, String> {
+        let mut Routes = Vec::new();
+
+        for RouteName in env_list("ROUTES") {
+            let Prefix = format!("ROUTE_{}", RouteName.to_uppercase().replace('-', "_"));
+
+            let PathPrefix = env_opt(&format!("{}_PATH_PREFIX", Prefix));
+            if let Some(PathPrefix) = PathPrefix.as_ref() {
+                if !PathPrefix.starts_with('/') {
+                    return Err(format!("route '{}' path prefix '{}' must start with '/'", RouteName, PathPrefix));
+                }
+            }
+
+            let PathRegex = match env_opt(&format!("{}_PATH_REGEX", Prefix)) {
+                Some(Pattern) => Some(Regex::new(&Pattern).map_err(|e| format!("route '{}' has an invalid path regex: {}", RouteName, e))?),
+                None => None,
+            };
+
+            let Methods = env_list(&format!("{}_METHODS", Prefix))
+                .iter()
+                .map(|Name| {
+                    Method::from_bytes(Name.to_uppercase().as_bytes())
+                        .map_err(|_| format!("route '{}' has an invalid method '{}'", RouteName, Name))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let Pool = match env_opt(&format!("{}_UPSTREAM", Prefix)) {
+                Some(PoolName) => Some(Upstreams.pool_named(&PoolName)
+                    .ok_or_else(|| format!("route '{}' refers to unknown upstream pool '{}'", RouteName, PoolName))?),
+                None => None,
+            };
+
+            let ModuleNames = env_list(&format!("{}_MODULES", Prefix));
+            let Modules: Option<Arc<[String]>> = if ModuleNames.is_empty() {
+                None
+            } else if ModuleNames.len() == 1 && ModuleNames[0].eq_ignore_ascii_case("none") {
+                Some(Arc::from(Vec::new()))
+            } else {
+                if let Some(Unknown) = ModuleNames.iter().find(|Name| !is_module_registered(Name)) {
+                    return Err(format!("route '{}' refers to unknown module '{}'", RouteName, Unknown));
+                }
+                Some(Arc::from(ModuleNames))
+            };
+
+            Routes.push(Route {
+                Hosts: env_list(&format!("{}_HOSTS", Prefix)).iter().map(|Host| Host.to_lowercase()).collect(),
+                PathPrefix,
+                PathRegex,
+                Methods,
+                Pool,
+                Modules,
+                Captcha: env_flag(&format!("{}_CAPTCHA", Prefix), true),
+            });
+        }
+
+        Ok(Self { Routes, Fallback: Route::fallback() })
+    }
+
+    pub fn route(&self, Method: &Method, Host: Option<&str>, Path: &str) -> &Route {
+        let Path = normalize_path(Path);
+        self.Routes.iter()
+            .find(|Route| Route.matches(Method, Host, &Path))
+            .unwrap_or(&self.Fallback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_segments_and_escapes_are_resolved_before_matching() {
+        assert_eq!(normalize_path("/public/../admin"), "/admin");
+        assert_eq!(normalize_path("/public/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize_path("/public/%2E%2E%2Fadmin"), "/admin");
+        assert_eq!(normalize_path("/public/..\\admin"), "/admin");
+        assert_eq!(normalize_path("/public\\..\\admin"), "/admin");
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("//public/./css//site.css"), "/public/css/site.css");
+        assert_eq!(normalize_path("/public/"), "/public/");
+        assert_eq!(normalize_path("/public/docs/.."), "/public/");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/100%/done%zz"), "/100%/done%zz");
+    }
+
+    #[test]
+    fn traversal_out_of_a_relaxed_route_falls_back() {
+        let Table = RoutingTable {
+            Routes: vec![Route { PathPrefix: Some(String::from("/public")), Captcha: false, ..Route::fallback() }],
+            Fallback: Route::fallback(),
+        };
+
+        assert!(!Table.route(&Method::GET, None, "/public/index.html").Captcha);
+        assert!(!Table.route(&Method::GET, None, "/public/a/../b").Captcha);
+        for Path in ["/public/../admin", "/public/%2e%2e/admin", "/public/%2E%2e%2fadmin", "/public/..%5cadmin", "/publicity"] {
+            assert!(Table.route(&Method::GET, None, Path).Captcha, "{} matched the public route", Path);
+        }
+    }
+}
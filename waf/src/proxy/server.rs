@@ -0,0 +1,123 @@
+#![allow(non_snake_case)]
+
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::time::Duration;
+
+use crate::config::{env_flag, env_or};
+
+const MIN_HTTP1_BUF_SIZE: usize = 8192;
+const HTTP2_FRAME_SIZES: std::ops::RangeInclusive<u32> = 16_384..=16_777_215;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum HttpProtocols {
+    Auto,
+    Http1Only,
+    Http2Only,
+}
+
+#[derive(Clone)]
+pub struct ServerSettings {
+    pub Protocols: HttpProtocols,
+    pub Http1KeepAlive: bool,
+    pub Http1MaxBufSize: usize,
+    pub Http2MaxConcurrentStreams: u32,
+    pub Http2InitialStreamWindowSize: u32,
+    pub Http2InitialConnectionWindowSize: u32,
+    pub Http2AdaptiveWindow: bool,
+    pub Http2MaxFrameSize: u32,
+    pub Http2KeepAliveInterval: Option<Duration>,
+}
+
+impl ServerSettings {
+    pub fn from_env() -> Result<Self, String> {
+        let Protocols = match env_or("HTTP_PROTOCOLS", String::from("auto")).to_lowercase().as_str() {
+            "auto" => HttpProtocols::Auto,
+            "http1" | "h1" => HttpProtocols::Http1Only,
+            "http2" | "h2" => HttpProtocols::Http2Only,
+            Other => return Err(format!("HTTP_PROTOCOLS must be auto, http1 or http2, not '{}'", Other)),
+        };
+
+        let KeepAliveSecs: u64 = env_or("HTTP2_KEEP_ALIVE_INTERVAL_SECS", 0);
+
+        let Settings = Self {
+            Protocols,
+            Http1KeepAlive: env_flag("HTTP1_KEEP_ALIVE", true),
+            Http1MaxBufSize: env_or("HTTP1_MAX_BUF_SIZE", 400 * 1024),
+            Http2MaxConcurrentStreams: env_or("HTTP2_MAX_CONCURRENT_STREAMS", 200),
+            Http2InitialStreamWindowSize: env_or("HTTP2_INITIAL_STREAM_WINDOW_SIZE", 1024 * 1024),
+            Http2InitialConnectionWindowSize: env_or("HTTP2_INITIAL_CONNECTION_WINDOW_SIZE", 2 * 1024 * 1024),
+            Http2AdaptiveWindow: env_flag("HTTP2_ADAPTIVE_WINDOW", false),
+            Http2MaxFrameSize: env_or("HTTP2_MAX_FRAME_SIZE", 16 * 1024),
+            Http2KeepAliveInterval: if KeepAliveSecs > 0 { Some(Duration::from_secs(KeepAliveSecs)) } else { None },
+        };
+        Settings.validate()?;
+        Ok(Settings)
+    }
+
+    // hyper and h2 assert on these rather than returning an error, so catch them before the first connection
+    fn validate(&self) -> Result<(), String> {
+        if self.Http1MaxBufSize < MIN_HTTP1_BUF_SIZE {
+            return Err(format!("HTTP1_MAX_BUF_SIZE must be at least {}, got {}", MIN_HTTP1_BUF_SIZE, self.Http1MaxBufSize));
+        }
+        if !HTTP2_FRAME_SIZES.contains(&self.Http2MaxFrameSize) {
+            return Err(format!(
+                "HTTP2_MAX_FRAME_SIZE must be between {} and {}, got {}",
+                HTTP2_FRAME_SIZES.start(), HTTP2_FRAME_SIZES.end(), self.Http2MaxFrameSize,
+            ));
+        }
+        Ok(())
+    }
+
+    // Without TLS the protocol is picked from the connection preface, so h2c prior knowledge works too
+    pub fn build(&self) -> auto::Builder<TokioExecutor> {
+        let mut Builder = auto::Builder::new(TokioExecutor::new());
+
+        Builder.http1()
+            .keep_alive(self.Http1KeepAlive)
+            .max_buf_size(self.Http1MaxBufSize);
+
+        Builder.http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.Http2MaxConcurrentStreams)
+            .initial_stream_window_size(self.Http2InitialStreamWindowSize)
+            .initial_connection_window_size(self.Http2InitialConnectionWindowSize)
+            .adaptive_window(self.Http2AdaptiveWindow)
+            .max_frame_size(self.Http2MaxFrameSize)
+            .keep_alive_interval(self.Http2KeepAliveInterval);
+
+        match self.Protocols {
+            HttpProtocols::Auto => Builder,
+            HttpProtocols::Http1Only => Builder.http1_only(),
+            HttpProtocols::Http2Only => Builder.http2_only(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ServerSettings {
+        ServerSettings {
+            Protocols: HttpProtocols::Auto,
+            Http1KeepAlive: true,
+            Http1MaxBufSize: 400 * 1024,
+            Http2MaxConcurrentStreams: 200,
+            Http2InitialStreamWindowSize: 1024 * 1024,
+            Http2InitialConnectionWindowSize: 2 * 1024 * 1024,
+            Http2AdaptiveWindow: false,
+            Http2MaxFrameSize: 16 * 1024,
+            Http2KeepAliveInterval: None,
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_hyper_would_assert_on() {
+        assert!(settings().validate().is_ok());
+        assert!(ServerSettings { Http1MaxBufSize: 8191, ..settings() }.validate().is_err());
+        assert!(ServerSettings { Http2MaxFrameSize: 16_383, ..settings() }.validate().is_err());
+        assert!(ServerSettings { Http2MaxFrameSize: 16_777_216, ..settings() }.validate().is_err());
+        assert!(ServerSettings { Http2MaxFrameSize: 16_777_215, ..settings() }.validate().is_ok());
+    }
+}
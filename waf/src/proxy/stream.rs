@@ -0,0 +1,255 @@
+#![allow(non_snake_case)]
+
+use hyper::body::{Body, Bytes, Frame};
+use hyper::HeaderMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use crate::module::Pipeline;
+use crate::modules::brotli_compressor::BrotliStream;
+use crate::proxy::body::BodyError;
+
+// Text is handed to the content modules in line-aligned pieces so a pattern is never cut in half.
+// A body without newlines is held back until this many bytes are pending, then cut at whitespace.
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+
+fn find_last(Haystack: &[u8], Needle: &[u8]) -> Option<usize> {
+    Haystack.windows(Needle.len()).rposition(|Window| Window == Needle)
+}
+
+// Start of the line the earliest unterminated block begins on. Text from there stays pending until the
+// block ended, or until MAX_PENDING_BYTES forces it out like any other text.
+fn open_block_start(Text: &[u8], Blocks: &[(String, String)]) -> Option<usize> {
+    Blocks.iter()
+        .filter_map(|(BlockBegin, BlockEnd)| {
+            let Begin = find_last(Text, BlockBegin.as_bytes())?;
+            match find_last(&Text[Begin..], BlockEnd.as_bytes()) {
+                Some(_) => None,
+                None => Some(Begin),
+            }
+        })
+        .min()
+        .map(|Begin| Text[..Begin].iter().rposition(|Byte| *Byte == b'\n').map(|Position| Position + 1).unwrap_or(0))
+}
+
+pub struct ContentStream<B> {
+    Inner: Pin<Box<B>>,
+    Pending: Vec<u8>,
+    Pipeline: Arc<Pipeline>,
+    ContentType: Option<String>,
+    Modules: Option<Arc<[String]>>,
+    HeldBlocks: Vec<(String, String)>,
+    ProcessText: bool,
+    Compressor: Option<BrotliStream>,
+    Trailers: Option<HeaderMap>,
+    Done: bool,
+}
+
+impl<B> ContentStream<B>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<BodyError>,
+{
+    pub fn new(Inner: B, Pipeline: Arc<Pipeline>, ContentType: Option<String>, Modules: Option<Arc<[String]>>, ProcessText: bool, Compress: bool) -> Self {
+        let HeldBlocks = Pipeline.held_blocks(ContentType.as_deref(), Modules.as_deref());
+        Self {
+            Inner: Box::pin(Inner),
+            Pending: Vec::new(),
+            Pipeline,
+            ContentType,
+            Modules,
+            HeldBlocks,
+            ProcessText,
+            Compressor: if Compress { Some(BrotliStream::new(4)) } else { None },
+            Trailers: None,
+            Done: false,
+        }
+    }
+
+    fn find_split_point(&self) -> usize {
+        if !self.ProcessText {
+            return self.Pending.len();
+        }
+
+        if let Some(Position) = self.Pending.iter().rposition(|Byte| *Byte == b'\n') {
+            if self.Pending.len() < MAX_PENDING_BYTES {
+                if let Some(Start) = open_block_start(&self.Pending[..Position + 1], &self.HeldBlocks) {
+                    return Start;
+                }
+            }
+            return Position + 1;
+        }
+
+        if self.Pending.len() < MAX_PENDING_BYTES {
+            return 0;
+        }
+
+        if let Some(Position) = self.Pending.iter().rposition(|Byte| Byte.is_ascii_whitespace()) {
+            return Position + 1;
+        }
+
+        match std::str::from_utf8(&self.Pending) {
+            Ok(_) => self.Pending.len(),
+            Err(E) if E.valid_up_to() > 0 => E.valid_up_to(),
+            Err(_) => self.Pending.len(),
+        }
+    }
+
+    // A fail-closed module fault ends the body with an error, the client sees a cut-off response
+    fn drain(&mut self, Final: bool) -> Result<Bytes, BodyError> {
+        let SplitAt = if Final { self.Pending.len() } else { self.find_split_point() };
+        let Chunk: Vec<u8> = self.Pending.drain(..SplitAt).collect();
+
+        let Processed = if self.ProcessText && !Chunk.is_empty() {
+            match String::from_utf8(Chunk) {
+                Ok(mut Text) => {
+                    if let Err(Fault) = self.Pipeline.process_content(&mut Text, self.ContentType.as_deref(), self.Modules.as_deref()) {
+                        self.Done = true;
+                        self.Trailers = None;
+                        return Err(Box::new(Fault));
+                    }
+                    Text.into_bytes()
+                }
+                Err(E) => {
+                    // Not text after all, the rest of the body is passed through untouched
+                    self.ProcessText = false;
+                    E.into_bytes()
+                }
+            }
+        } else {
+            Chunk
+        };
+
+        match self.Compressor.as_mut() {
+            Some(Compressor) => {
+                let mut Output = Compressor.push(&Processed);
+                if Final {
+                    if let Some(Compressor) = self.Compressor.take() {
+                        Output.extend_from_slice(&Compressor.finish());
+                    }
+                }
+                Ok(Bytes::from(Output))
+            }
+            None => Ok(Bytes::from(Processed)),
+        }
+    }
+}
+
+impl<B> Body for ContentStream<B>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<BodyError>,
+{
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        let This = self.get_mut();
+
+        loop {
+            if This.Done {
+                return Poll::Ready(This.Trailers.take().map(|Trailers| Ok(Frame::trailers(Trailers))));
+            }
+
+            match ready!(This.Inner.as_mut().poll_frame(Cx)) {
+                Some(Ok(Frame)) => match Frame.into_data() {
+                    Ok(Data) => {
+                        This.Pending.extend_from_slice(&Data);
+                        let Output = match This.drain(false) {
+                            Ok(Output) => Output,
+                            Err(E) => return Poll::Ready(Some(Err(E))),
+                        };
+                        if !Output.is_empty() {
+                            return Poll::Ready(Some(Ok(Frame::data(Output))));
+                        }
+                    }
+                    Err(Frame) => {
+                        if let Ok(Trailers) = Frame.into_trailers() {
+                            This.Trailers = Some(Trailers);
+                        }
+                    }
+                },
+                Some(Err(E)) => return Poll::Ready(Some(Err(E.into()))),
+                None => {
+                    This.Done = true;
+                    let Output = match This.drain(true) {
+                        Ok(Output) => Output,
+                        Err(E) => return Poll::Ready(Some(Err(E))),
+                    };
+                    if !Output.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(Output))));
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.Done && self.Trailers.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::Once;
+    use http_body_util::{BodyExt, StreamBody};
+    use crate::module::{current_pipeline, register_module, BodyAction, WafModule, TEXT_CONTENT_TYPES};
+
+    // Uppercases every "world", so a word cut in half by the stream would come out unchanged
+    struct Shout;
+
+    impl WafModule for Shout {
+        fn name(&self) -> &str {
+            "TestStreamShout"
+        }
+
+        fn version(&self) -> &str {
+            "0.0.0"
+        }
+
+        fn priority(&self) -> i32 {
+            0
+        }
+
+        fn content_types(&self) -> &[&str] {
+            TEXT_CONTENT_TYPES
+        }
+
+        fn on_body(&self, Content: &mut String) -> BodyAction {
+            *Content = Content.replace("world", "WORLD").replace("café", "CAFÉ");
+            BodyAction::Continue
+        }
+    }
+
+    // Each read of the upstream body gets one of the chunks
+    async fn stream(Chunks: &[&[u8]]) -> Vec<u8> {
+        static REGISTER: Once = Once::new();
+        REGISTER.call_once(|| register_module(Box::new(Shout)).unwrap());
+
+        let Frames: Vec<_> = Chunks.iter().map(|Chunk| Ok::<_, Infallible>(Frame::data(Bytes::copy_from_slice(Chunk)))).collect();
+        let Inner = StreamBody::new(futures_util::stream::iter(Frames));
+        let Selection: Arc<[String]> = Arc::from([String::from("TestStreamShout")]);
+        let Stream = ContentStream::new(Inner, current_pipeline(), Some(String::from("text/plain")), Some(Selection), true, false);
+        Stream.collect().await.unwrap().to_bytes().to_vec()
+    }
+
+    #[tokio::test]
+    async fn a_line_split_across_reads_is_processed_whole() {
+        assert_eq!(stream(&[b"hello wo", b"rld\nsecond ", b"world\n"]).await, b"hello WORLD\nsecond WORLD\n");
+    }
+
+    #[tokio::test]
+    async fn a_character_split_across_reads_stays_text() {
+        let Text = "un café, world\n".as_bytes();
+        let Cut = Text.iter().position(|Byte| *Byte == 0xC3).unwrap() + 1;
+        assert_eq!(stream(&[&Text[..Cut], &Text[Cut..]]).await, "un CAFÉ, WORLD\n".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn text_without_a_trailing_newline_is_processed_at_the_end() {
+        assert_eq!(stream(&[b"hello ", b"world"]).await, b"hello WORLD");
+    }
+}
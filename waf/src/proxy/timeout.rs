@@ -0,0 +1,225 @@
+#![allow(non_snake_case)]
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
+
+use crate::config::env_or;
+use crate::proxy::body::{BodyError, ResponseBody};
+
+#[derive(Clone, Copy)]
+pub struct TimeoutSettings {
+    pub Connect: Option<Duration>,
+    pub Read: Option<Duration>,
+    pub BodyIdle: Option<Duration>,
+    pub Total: Option<Duration>,
+}
+
+// Every timeout takes milliseconds and 0 turns it off
+fn timeout(Millis: u64) -> Option<Duration> {
+    match Millis {
+        0 => None,
+        Millis => Some(Duration::from_millis(Millis)),
+    }
+}
+
+impl TimeoutSettings {
+    // UPSTREAM_CONNECT_TIMEOUT_MS, UPSTREAM_READ_TIMEOUT_MS (reading the request body and waiting for the
+    // response head), UPSTREAM_BODY_IDLE_TIMEOUT_MS between response body chunks and UPSTREAM_TOTAL_TIMEOUT_MS
+    // for the whole exchange. The last two are off by default so SSE and long polls are left alone.
+    pub fn from_env() -> Self {
+        Self::from_millis(
+            env_or("UPSTREAM_CONNECT_TIMEOUT_MS", 5000),
+            env_or("UPSTREAM_READ_TIMEOUT_MS", 30000),
+            env_or("UPSTREAM_BODY_IDLE_TIMEOUT_MS", 0),
+            env_or("UPSTREAM_TOTAL_TIMEOUT_MS", 0),
+        )
+    }
+
+    pub fn from_millis(Connect: u64, Read: u64, BodyIdle: u64, Total: u64) -> Self {
+        Self { Connect: timeout(Connect), Read: timeout(Read), BodyIdle: timeout(BodyIdle), Total: timeout(Total) }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.Total.map(|Total| Instant::now() + Total)
+    }
+
+    // Time allowed for the backend to send its response head, bounded by the request deadline
+    pub fn header_timeout(&self, Deadline: Option<Instant>) -> Option<Duration> {
+        let Remaining = Deadline.map(|Deadline| Deadline.saturating_duration_since(Instant::now()));
+        match (self.Read, Remaining) {
+            (Some(Read), Some(Remaining)) => Some(Read.min(Remaining)),
+            (Read, Remaining) => Read.or(Remaining),
+        }
+    }
+}
+
+// tokio's timeout for limits that may be switched off, None waits as long as it takes
+pub async fn within<F: Future>(Limit: Option<Duration>, Phase: &'static str, Work: F) -> Result<F::Output, UpstreamTimeout> {
+    match Limit {
+        Some(Limit) => tokio::time::timeout(Limit, Work).await.map_err(|_| UpstreamTimeout(Phase)),
+        None => Ok(Work.await),
+    }
+}
+
+#[derive(Debug)]
+pub struct UpstreamTimeout(&'static str);
+
+impl std::fmt::Display for UpstreamTimeout {
+    fn fmt(&self, Formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(Formatter, "upstream {} timed out", self.0)
+    }
+}
+
+impl std::error::Error for UpstreamTimeout {}
+
+// Once the status line has gone out a 504 is no longer possible, so an expired body read aborts the stream
+pub struct DeadlineBody {
+    Inner: ResponseBody,
+    IdleTimeout: Option<Duration>,
+    Idle: Option<Pin<Box<Sleep>>>,
+    Deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl DeadlineBody {
+    pub fn new(Inner: ResponseBody, IdleTimeout: Option<Duration>, Deadline: Option<Instant>) -> Self {
+        Self {
+            Inner,
+            IdleTimeout,
+            Idle: IdleTimeout.map(|IdleTimeout| Box::pin(sleep(IdleTimeout))),
+            Deadline: Deadline.map(|Deadline| Box::pin(sleep_until(Deadline))),
+        }
+    }
+}
+
+impl Body for DeadlineBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        if let Poll::Ready(Frame) = Pin::new(&mut self.Inner).poll_frame(Cx) {
+            if let Some(IdleTimeout) = self.IdleTimeout {
+                if let Some(Idle) = self.Idle.as_mut() {
+                    Idle.as_mut().reset(Instant::now() + IdleTimeout);
+                }
+            }
+            return Poll::Ready(Frame);
+        }
+
+        if let Some(Deadline) = self.Deadline.as_mut() {
+            if Deadline.as_mut().poll(Cx).is_ready() {
+                return Poll::Ready(Some(Err(Box::new(UpstreamTimeout("request deadline")))));
+            }
+        }
+
+        if self.Idle.as_mut().is_some_and(|Idle| Idle.as_mut().poll(Cx).is_ready()) {
+            return Poll::Ready(Some(Err(Box::new(UpstreamTimeout("body read")))));
+        }
+
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.Inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.Inner.size_hint()
+    }
+}
+
+// The connector reports its connect timeout as a TimedOut io::Error somewhere down the source chain
+pub fn is_connect_timeout(Error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut Current = Some(Error);
+    while let Some(Cause) = Current {
+        if Cause.downcast_ref::<io::Error>().map(|e| e.kind() == io::ErrorKind::TimedOut).unwrap_or(false) {
+            return true;
+        }
+        Current = Cause.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::body::{empty_body, full_body, RequestBody};
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use tokio::net::{TcpSocket, TcpStream};
+    use http_body_util::{BodyExt, StreamBody};
+
+    #[test]
+    fn zero_disables_every_timeout() {
+        let Settings = TimeoutSettings::from_millis(0, 0, 0, 0);
+        assert!(Settings.Connect.is_none() && Settings.Read.is_none() && Settings.BodyIdle.is_none() && Settings.Total.is_none());
+        assert_eq!(Settings.header_timeout(None), None);
+    }
+
+    #[tokio::test]
+    async fn header_timeout_is_bounded_by_the_deadline() {
+        let Settings = TimeoutSettings { Connect: None, Read: None, BodyIdle: None, Total: Some(Duration::from_secs(2)) };
+        let Limit = Settings.header_timeout(Settings.deadline()).unwrap();
+        assert!(Limit <= Duration::from_secs(2) && Limit > Duration::from_secs(1));
+
+        let Settings = TimeoutSettings { Read: Some(Duration::from_millis(100)), ..Settings };
+        assert_eq!(Settings.header_timeout(None), Some(Duration::from_millis(100)));
+        assert!(within(None, "test", async { 1 }).await.is_ok());
+    }
+
+    // One chunk, then a pause longer than the idle timeout before the next one
+    fn slow_body(Pause: Duration) -> ResponseBody {
+        let Chunks = futures_util::stream::unfold(0, move |Sent| async move {
+            match Sent {
+                0 => Some((Ok::<_, BodyError>(Frame::data(Bytes::from("first"))), 1)),
+                1 => {
+                    tokio::time::sleep(Pause).await;
+                    Some((Ok(Frame::data(Bytes::from("second"))), 2))
+                }
+                _ => None,
+            }
+        });
+        StreamBody::new(Chunks).boxed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_only_applies_when_set() {
+        let Collected = DeadlineBody::new(slow_body(Duration::from_secs(600)), None, None).collect().await.unwrap();
+        assert_eq!(Collected.to_bytes(), Bytes::from("firstsecond"));
+
+        let Result = DeadlineBody::new(slow_body(Duration::from_secs(600)), Some(Duration::from_secs(30)), None).collect().await;
+        assert!(Result.is_err());
+
+        let Collected = DeadlineBody::new(full_body(Bytes::from("whole")), Some(Duration::from_secs(30)), None).collect().await.unwrap();
+        assert_eq!(Collected.to_bytes(), Bytes::from("whole"));
+    }
+
+    #[tokio::test]
+    async fn a_blackholed_backend_is_reported_as_a_connect_timeout() {
+        // A listener that never accepts: once its accept queue is full further SYNs are dropped
+        let Socket = TcpSocket::new_v4().unwrap();
+        Socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let Listener = Socket.listen(0).unwrap();
+        let Address = Listener.local_addr().unwrap();
+        let mut Held = Vec::new();
+        for _ in 0..4 {
+            if let Ok(Ok(Stream)) = tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(Address)).await {
+                Held.push(Stream);
+            }
+        }
+
+        let mut Connector = HttpConnector::new();
+        Connector.set_connect_timeout(Some(Duration::from_millis(200)));
+        let Client: Client<HttpConnector, RequestBody> = Client::builder(TokioExecutor::new()).build(Connector);
+        let Request = hyper::Request::get(format!("http://{}/", Address)).body(empty_body()).unwrap();
+        let Error = Client.request(Request).await.unwrap_err();
+
+        assert!(Error.is_connect());
+        assert!(is_connect_timeout(&Error));
+    }
+}
@@ -0,0 +1,274 @@
+#![allow(non_snake_case)]
+
+use hyper::header::{HeaderMap, CONNECTION, UPGRADE};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{Request, Version};
+use hyper_util::rt::TokioIo;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::upstream::BackendGuard;
+use crate::shutdown::DrainGuard;
+
+static _TUNNEL_ID: AtomicU64 = AtomicU64::new(0);
+static _ACTIVE_TUNNELS: Lazy<Mutex<HashMap<u64, Arc<TunnelStats>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub static TUNNEL_TOTAL: AtomicU64 = AtomicU64::new(0);
+pub static TUNNEL_BYTES_UP: AtomicU64 = AtomicU64::new(0);
+pub static TUNNEL_BYTES_DOWN: AtomicU64 = AtomicU64::new(0);
+
+pub struct TunnelStats {
+    pub ClientAddr: SocketAddr,
+    pub Protocol: String,
+    pub Path: String,
+    pub Started: Instant,
+    pub BytesUp: AtomicU64,
+    pub BytesDown: AtomicU64,
+}
+
+pub fn is_upgrade_request<B>(Request: &Request<B>) -> bool {
+    Request.version() == Version::HTTP_11
+        && Request.headers().contains_key(UPGRADE)
+        && connection_has_token(Request.headers(), "upgrade")
+}
+
+pub fn connection_has_token(Headers: &HeaderMap, Token: &str) -> bool {
+    Headers.get_all(CONNECTION)
+        .iter()
+        .filter_map(|Value| Value.to_str().ok())
+        .flat_map(|Value| Value.split(','))
+        .any(|Item| Item.trim().eq_ignore_ascii_case(Token))
+}
+
+// Splices the client and backend connections once both sides finished the 101 handshake
+pub fn spawn_tunnel(
+    ClientUpgrade: OnUpgrade,
+    BackendUpgrade: OnUpgrade,
+    Guard: BackendGuard,
+    ClientAddr: SocketAddr,
+    Protocol: String,
+    Path: String,
+) {
+    let Drain = DrainGuard::new();
+    tokio::spawn(async move {
+        let _Guard = Guard;
+        let _Drain = Drain;
+        let (ClientIo, BackendIo) = match tokio::try_join!(ClientUpgrade, BackendUpgrade) {
+            Ok(Pair) => Pair,
+            Err(_e) => return,
+        };
+
+        let Stats = Arc::new(TunnelStats {
+            ClientAddr,
+            Protocol,
+            Path,
+            Started: Instant::now(),
+            BytesUp: AtomicU64::new(0),
+            BytesDown: AtomicU64::new(0),
+        });
+
+        let TunnelId = _TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+        TUNNEL_TOTAL.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut Tunnels) = _ACTIVE_TUNNELS.lock() {
+            Tunnels.insert(TunnelId, Stats.clone());
+        }
+
+        let mut ClientSide = CountingIo {
+            Inner: TokioIo::new(ClientIo),
+            Stats: Stats.clone(),
+        };
+        let mut BackendSide = TokioIo::new(BackendIo);
+
+        let _ = tokio::io::copy_bidirectional(&mut ClientSide, &mut BackendSide).await;
+
+        if let Ok(mut Tunnels) = _ACTIVE_TUNNELS.lock() {
+            Tunnels.remove(&TunnelId);
+        }
+    });
+}
+
+pub fn get_active_tunnels() -> Vec<Arc<TunnelStats>> {
+    match _ACTIVE_TUNNELS.lock() {
+        Ok(Tunnels) => {
+            let mut Active: Vec<Arc<TunnelStats>> = Tunnels.values().cloned().collect();
+            Active.sort_by_key(|Stats| Stats.Started);
+            Active
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+// Bytes read from the client went up to the backend, bytes written to it came down
+struct CountingIo {
+    Inner: TokioIo<Upgraded>,
+    Stats: Arc<TunnelStats>,
+}
+
+impl AsyncRead for CountingIo {
+    fn poll_read(mut self: Pin<&mut Self>, Cx: &mut Context<'_>, Buffer: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let Before = Buffer.filled().len();
+        let Result = Pin::new(&mut self.Inner).poll_read(Cx, Buffer);
+
+        if let Poll::Ready(Ok(())) = Result {
+            let Read = (Buffer.filled().len() - Before) as u64;
+            self.Stats.BytesUp.fetch_add(Read, Ordering::Relaxed);
+            TUNNEL_BYTES_UP.fetch_add(Read, Ordering::Relaxed);
+        }
+
+        Result
+    }
+}
+
+impl AsyncWrite for CountingIo {
+    fn poll_write(mut self: Pin<&mut Self>, Cx: &mut Context<'_>, Data: &[u8]) -> Poll<io::Result<usize>> {
+        let Result = Pin::new(&mut self.Inner).poll_write(Cx, Data);
+
+        if let Poll::Ready(Ok(Written)) = Result {
+            self.Stats.BytesDown.fetch_add(Written as u64, Ordering::Relaxed);
+            TUNNEL_BYTES_DOWN.fetch_add(Written as u64, Ordering::Relaxed);
+        }
+
+        Result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.Inner).poll_flush(Cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.Inner).poll_shutdown(Cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use hyper::body::{Bytes, Incoming};
+    use hyper::header::{HeaderValue, HOST};
+    use hyper::service::service_fn;
+    use hyper::{Response, StatusCode};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::proxy::breaker::BreakerSettings;
+    use crate::proxy::upstream::{BalanceStrategy, HealthPolicy, UpstreamPool};
+
+    fn switching_protocols() -> Response<Empty<Bytes>> {
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "echo")
+            .body(Empty::new())
+            .unwrap()
+    }
+
+    // Answers the upgrade and echoes whatever comes through afterwards
+    async fn echo_backend() -> SocketAddr {
+        let Listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let Address = Listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (Stream, _) = Listener.accept().await.unwrap();
+            let Service = service_fn(|mut Request: Request<Incoming>| async move {
+                tokio::spawn(async move {
+                    let Upgraded = hyper::upgrade::on(&mut Request).await.unwrap();
+                    let (mut Reader, mut Writer) = tokio::io::split(TokioIo::new(Upgraded));
+                    let _ = tokio::io::copy(&mut Reader, &mut Writer).await;
+                });
+                Ok::<_, Infallible>(switching_protocols())
+            });
+            let _ = hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(Stream), Service).with_upgrades().await;
+        });
+        Address
+    }
+
+    // Forwards one upgrade to the backend and tunnels the two connections like the proxy does
+    async fn tunnelling_proxy(Backend: SocketAddr, Pool: Arc<UpstreamPool>) -> SocketAddr {
+        let Listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let Address = Listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (Stream, ClientAddr) = Listener.accept().await.unwrap();
+            let Service = service_fn(move |mut Request: Request<Incoming>| {
+                let Pool = Pool.clone();
+                async move {
+                    assert!(is_upgrade_request(&Request));
+                    let BackendStream = TcpStream::connect(Backend).await.unwrap();
+                    let (mut Sender, Connection) = hyper::client::conn::http1::handshake(TokioIo::new(BackendStream)).await.unwrap();
+                    tokio::spawn(Connection.with_upgrades());
+
+                    let mut Forward = Request::get("/chat").body(Empty::<Bytes>::new()).unwrap();
+                    Forward.headers_mut().insert(HOST, HeaderValue::from_static("backend"));
+                    Forward.headers_mut().insert(CONNECTION, HeaderValue::from_static("upgrade"));
+                    Forward.headers_mut().insert(UPGRADE, HeaderValue::from_static("echo"));
+                    let mut BackendResponse = Sender.send_request(Forward).await.unwrap();
+                    assert_eq!(BackendResponse.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+                    let Guard = Pool.select(ClientAddr.ip()).unwrap();
+                    spawn_tunnel(hyper::upgrade::on(&mut Request), hyper::upgrade::on(&mut BackendResponse), Guard, ClientAddr, String::from("echo"), String::from("/tunnel-test"));
+                    Ok::<_, Infallible>(switching_protocols())
+                }
+            });
+            let _ = hyper::server::conn::http1::Builder::new().serve_connection(TokioIo::new(Stream), Service).with_upgrades().await;
+        });
+        Address
+    }
+
+    #[test]
+    fn upgrades_need_http11_and_the_connection_token() {
+        let Upgrade = |Version: Version, Connection: &str| {
+            let Request = Request::get("/").version(Version).header(UPGRADE, "websocket").header(CONNECTION, Connection).body(()).unwrap();
+            is_upgrade_request(&Request)
+        };
+        assert!(Upgrade(Version::HTTP_11, "Upgrade"));
+        assert!(Upgrade(Version::HTTP_11, "keep-alive, upgrade"));
+        assert!(!Upgrade(Version::HTTP_11, "keep-alive"));
+        assert!(!Upgrade(Version::HTTP_10, "upgrade"));
+        assert!(!Upgrade(Version::HTTP_2, "upgrade"));
+        assert!(!is_upgrade_request(&Request::get("/").header(CONNECTION, "upgrade").body(()).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn bytes_flow_both_ways_and_the_backend_is_busy_until_the_tunnel_closes() {
+        let Policy = HealthPolicy { MaxFails: 1, FailTimeout: Duration::from_secs(600), HealthyThreshold: 1, Probe: None };
+        let Backend = echo_backend().await;
+        let Pool = Arc::new(UpstreamPool::new(String::from("tunnel"), BalanceStrategy::RoundRobin, Policy, BreakerSettings::from_env("UPSTREAM_TEST"), vec![(Backend.to_string(), 1)]));
+        let Proxy = tunnelling_proxy(Backend, Pool.clone()).await;
+
+        let mut Client = TcpStream::connect(Proxy).await.unwrap();
+        Client.write_all(b"GET /tunnel-test HTTP/1.1\r\nHost: proxy\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n").await.unwrap();
+        let mut Head = Vec::new();
+        while !Head.ends_with(b"\r\n\r\n") {
+            let mut Byte = [0u8; 1];
+            Client.read_exact(&mut Byte).await.unwrap();
+            Head.push(Byte[0]);
+        }
+        assert!(Head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&Head));
+
+        Client.write_all(b"ping").await.unwrap();
+        let mut Echo = [0u8; 4];
+        Client.read_exact(&mut Echo).await.unwrap();
+        assert_eq!(&Echo, b"ping");
+
+        let Tunnel = get_active_tunnels().into_iter().find(|Tunnel| Tunnel.Path == "/tunnel-test").unwrap();
+        assert_eq!((Tunnel.BytesUp.load(Ordering::Relaxed), Tunnel.BytesDown.load(Ordering::Relaxed)), (4, 4));
+        assert_eq!(Pool.backends()[0].active_connections(), 1);
+
+        drop(Client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Pool.backends()[0].active_connections() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(get_active_tunnels().iter().all(|Tunnel| Tunnel.Path != "/tunnel-test"));
+    }
+}
@@ -0,0 +1,615 @@
+#![allow(non_snake_case)]
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::config::{env_list, env_or};
+use crate::proxy::breaker::{BreakerSettings, CircuitBreaker};
+use crate::proxy::body::{BodyError, ResponseBody};
+
+pub const DEFAULT_POOL_NAME: &str = "default";
+
+// Virtual nodes per unit of weight on the consistent hash ring
+const HASH_RING_REPLICAS: u32 = 64;
+// Keeps the ring at most HASH_RING_REPLICAS * MAX_BACKEND_WEIGHT points per backend
+const MAX_BACKEND_WEIGHT: u32 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BalanceStrategy {
+    RoundRobin,
+    LeastConnections,
+    Weighted,
+    ConsistentHash,
+}
+
+impl BalanceStrategy {
+    pub fn parse(Value: &str) -> Option<Self> {
+        match Value.trim().to_lowercase().replace('-', "_").as_str() {
+            "round_robin" | "rr" => Some(Self::RoundRobin),
+            "least_connections" | "least_conn" => Some(Self::LeastConnections),
+            "weighted" | "weighted_round_robin" => Some(Self::Weighted),
+            "consistent_hash" | "ip_hash" => Some(Self::ConsistentHash),
+            _ => None,
+        }
+    }
+}
+
+pub struct ActiveProbe {
+    pub Path: String,
+    pub ExpectedStatus: u16,
+    pub Interval: Duration,
+    pub Timeout: Duration,
+}
+
+pub struct HealthPolicy {
+    pub MaxFails: u32,
+    pub FailTimeout: Duration,
+    pub HealthyThreshold: u32,
+    pub Probe: Option<ActiveProbe>,
+}
+
+impl HealthPolicy {
+    // UPSTREAM_<NAME>_MAX_FAILS, _FAIL_TIMEOUT_SECS, _HEALTHY_THRESHOLD and the optional active probe
+    // UPSTREAM_<NAME>_HEALTH_PATH, _HEALTH_STATUS, _HEALTH_INTERVAL_SECS, _HEALTH_TIMEOUT_MS
+    pub fn from_env(Prefix: &str) -> Self {
+        let ProbePath: String = env_or(&format!("{}_HEALTH_PATH", Prefix), String::new());
+
+        let Probe = if ProbePath.is_empty() {
+            None
+        } else {
+            Some(ActiveProbe {
+                Path: ProbePath,
+                ExpectedStatus: env_or(&format!("{}_HEALTH_STATUS", Prefix), 200),
+                Interval: Duration::from_secs(env_or(&format!("{}_HEALTH_INTERVAL_SECS", Prefix), 5).max(1)),
+                Timeout: Duration::from_millis(env_or(&format!("{}_HEALTH_TIMEOUT_MS", Prefix), 2000)),
+            })
+        };
+
+        Self {
+            MaxFails: env_or(&format!("{}_MAX_FAILS", Prefix), 3u32).max(1),
+            FailTimeout: Duration::from_secs(env_or(&format!("{}_FAIL_TIMEOUT_SECS", Prefix), 10)),
+            HealthyThreshold: env_or(&format!("{}_HEALTHY_THRESHOLD", Prefix), 2u32).max(1),
+            Probe,
+        }
+    }
+}
+
+pub struct Backend {
+    pub Address: String,
+    pub Weight: u32,
+    Policy: Arc<HealthPolicy>,
+    ActiveConnections: AtomicUsize,
+    Healthy: AtomicBool,
+    ConsecutiveFailures: AtomicU32,
+    ConsecutiveSuccesses: AtomicU32,
+    EjectedAt: Mutex<Option<Instant>>,
+}
+
+// Accepts "host:port" or "host:port=weight", the weight between 1 and MAX_BACKEND_WEIGHT
+pub fn parse_backend(Value: &str) -> Result<(String, u32), String> {
+    let (Address, Weight) = match Value.rsplit_once('=') {
+        Some((Address, Weight)) => {
+            let Weight: u32 = Weight.trim().parse().map_err(|_| format!("invalid weight in backend '{}'", Value))?;
+            (Address.trim(), Weight)
+        }
+        None => (Value.trim(), 1),
+    };
+
+    if Address.is_empty() {
+        return Err(format!("invalid backend '{}'", Value));
+    }
+    if Weight == 0 || Weight > MAX_BACKEND_WEIGHT {
+        return Err(format!("backend '{}' needs a weight between 1 and {}", Address, MAX_BACKEND_WEIGHT));
+    }
+
+    Ok((Address.to_string(), Weight))
+}
+
+impl Backend {
+    pub fn new(Address: String, Weight: u32, Policy: Arc<HealthPolicy>) -> Self {
+        Self {
+            Address,
+            Weight,
+            Policy,
+            ActiveConnections: AtomicUsize::new(0),
+            Healthy: AtomicBool::new(true),
+            ConsecutiveFailures: AtomicU32::new(0),
+            ConsecutiveSuccesses: AtomicU32::new(0),
+            EjectedAt: Mutex::new(None),
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.ActiveConnections.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.Healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.ConsecutiveFailures.load(Ordering::Relaxed)
+    }
+
+    // Without an active probe an ejected backend is given another chance once FailTimeout has passed.
+    // It comes back one failure away from being ejected again.
+    pub fn is_available(&self) -> bool {
+        if self.is_healthy() {
+            return true;
+        }
+
+        if self.Policy.Probe.is_some() {
+            return false;
+        }
+
+        let Expired = self.EjectedAt.lock()
+            .map(|EjectedAt| EjectedAt.map(|At| At.elapsed() >= self.Policy.FailTimeout).unwrap_or(true))
+            .unwrap_or(false);
+
+        if Expired {
+            self.ConsecutiveFailures.store(self.Policy.MaxFails - 1, Ordering::Relaxed);
+            self.readmit();
+        }
+
+        Expired
+    }
+
+    pub fn report_success(&self) {
+        self.ConsecutiveFailures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn report_failure(&self) {
+        self.ConsecutiveSuccesses.store(0, Ordering::Relaxed);
+        let Failures = self.ConsecutiveFailures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if Failures >= self.Policy.MaxFails && self.Healthy.swap(false, Ordering::Relaxed) {
+            if let Ok(mut EjectedAt) = self.EjectedAt.lock() {
+                *EjectedAt = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn report_probe(&self, Passed: bool) {
+        if !Passed {
+            self.report_failure();
+            return;
+        }
+
+        self.report_success();
+        let Successes = self.ConsecutiveSuccesses.fetch_add(1, Ordering::Relaxed) + 1;
+        if !self.is_healthy() && Successes >= self.Policy.HealthyThreshold {
+            self.readmit();
+        }
+    }
+
+    fn readmit(&self) {
+        self.Healthy.store(true, Ordering::Relaxed);
+        if let Ok(mut EjectedAt) = self.EjectedAt.lock() {
+            *EjectedAt = None;
+        }
+    }
+}
+
+// Counts the backend as busy for as long as the request, and the response body it produced, are alive
+pub struct BackendGuard {
+    pub Backend: Arc<Backend>,
+}
+
+impl BackendGuard {
+    fn new(Backend: Arc<Backend>) -> Self {
+        Backend.ActiveConnections.fetch_add(1, Ordering::Relaxed);
+        Self { Backend }
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.Backend.ActiveConnections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct UpstreamPool {
+    pub Name: String,
+    pub Strategy: BalanceStrategy,
+    pub Policy: Arc<HealthPolicy>,
+    pub Breaker: CircuitBreaker,
+    Backends: Vec<Arc<Backend>>,
+    Cursor: AtomicUsize,
+    Ring: Vec<(u64, usize)>,
+}
+
+fn hash_of<T: Hash>(Value: &T) -> u64 {
+    let mut Hasher = DefaultHasher::new();
+    Value.hash(&mut Hasher);
+    Hasher.finish()
+}
+
+impl UpstreamPool {
+    pub fn new(Name: String, Strategy: BalanceStrategy, Policy: HealthPolicy, Breaker: BreakerSettings, Backends: Vec<(String, u32)>) -> Self {
+        let Policy = Arc::new(Policy);
+        let Backends: Vec<Arc<Backend>> = Backends.into_iter()
+            .map(|(Address, Weight)| Arc::new(Backend::new(Address, Weight, Policy.clone())))
+            .collect();
+
+        let mut Ring = Vec::new();
+        if Strategy == BalanceStrategy::ConsistentHash {
+            for (Index, Backend) in Backends.iter().enumerate() {
+                let Replicas = HASH_RING_REPLICAS.checked_mul(Backend.Weight).unwrap_or(HASH_RING_REPLICAS * MAX_BACKEND_WEIGHT);
+                for Replica in 0..Replicas {
+                    Ring.push((hash_of(&(&Backend.Address, Replica)), Index));
+                }
+            }
+            Ring.sort_unstable();
+        }
+
+        Self {
+            Breaker: CircuitBreaker::new(Name.clone(), Breaker),
+            Name,
+            Strategy,
+            Policy,
+            Backends,
+            Cursor: AtomicUsize::new(0),
+            Ring,
+        }
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.Backends
+    }
+
+    // Ejected backends are skipped, None means nothing in the pool can take the request
+    pub fn select(&self, ClientIp: IpAddr) -> Option<BackendGuard> {
+        let Available: Vec<bool> = self.Backends.iter().map(|Backend| Backend.is_available()).collect();
+        if !Available.iter().any(|Up| *Up) {
+            return None;
+        }
+
+        let Index = match self.Strategy {
+            BalanceStrategy::RoundRobin => self.pick_round_robin(&Available),
+            BalanceStrategy::LeastConnections => self.pick_least_connections(&Available),
+            BalanceStrategy::Weighted => self.pick_weighted(&Available),
+            BalanceStrategy::ConsistentHash => self.pick_consistent_hash(ClientIp, &Available),
+        }?;
+
+        Some(BackendGuard::new(self.Backends[Index].clone()))
+    }
+
+    fn pick_round_robin(&self, Available: &[bool]) -> Option<usize> {
+        let Offset = self.Cursor.fetch_add(1, Ordering::Relaxed);
+        let Count = self.Backends.len();
+
+        (0..Count)
+            .map(|Step| (Offset + Step) % Count)
+            .find(|Index| Available[*Index])
+    }
+
+    fn pick_least_connections(&self, Available: &[bool]) -> Option<usize> {
+        // Ties rotate so an idle pool still spreads load
+        let Offset = self.Cursor.fetch_add(1, Ordering::Relaxed);
+        let Count = self.Backends.len();
+
+        (0..Count)
+            .map(|Step| (Offset + Step) % Count)
+            .filter(|Index| Available[*Index])
+            .min_by_key(|Index| {
+                let Backend = &self.Backends[*Index];
+                // Compare connections per unit of weight without floats
+                Backend.active_connections() * 1000 / Backend.Weight as usize
+            })
+    }
+
+    fn pick_weighted(&self, Available: &[bool]) -> Option<usize> {
+        let TotalWeight: usize = self.Backends.iter()
+            .zip(Available)
+            .filter(|(_, Up)| **Up)
+            .map(|(Backend, _)| Backend.Weight as usize)
+            .sum();
+        let mut Position = self.Cursor.fetch_add(1, Ordering::Relaxed) % TotalWeight;
+
+        for (Index, Backend) in self.Backends.iter().enumerate() {
+            if !Available[Index] {
+                continue;
+            }
+            if Position < Backend.Weight as usize {
+                return Some(Index);
+            }
+            Position -= Backend.Weight as usize;
+        }
+
+        None
+    }
+
+    // Walks clockwise past ejected backends so only their clients move elsewhere
+    fn pick_consistent_hash(&self, ClientIp: IpAddr, Available: &[bool]) -> Option<usize> {
+        let Key = hash_of(&ClientIp);
+        let Start = self.Ring.partition_point(|(Point, _)| *Point < Key);
+
+        (0..self.Ring.len())
+            .map(|Step| self.Ring[(Start + Step) % self.Ring.len()].1)
+            .find(|Index| Available[*Index])
+    }
+}
+
+pub struct UpstreamRegistry {
+    Pools: Vec<Arc<UpstreamPool>>,
+    HostRules: Vec<(String, Arc<UpstreamPool>)>,
+    Default: Arc<UpstreamPool>,
+}
+
+impl UpstreamRegistry {
+    // UPSTREAM_POOLS=default,api
+    // UPSTREAM_API_BACKENDS=10.0.0.5:8080=3,10.0.0.6:8080
+    // UPSTREAM_API_STRATEGY=weighted
+    // UPSTREAM_API_HOSTS=api.example.com,*.api.example.com
+    // Without UPSTREAM_POOLS a single pool points at 127.0.0.1:DESTINATION_PORT.
+    pub fn from_env() -> Result<Self, String> {
+        let mut PoolNames = env_list("UPSTREAM_POOLS");
+        if PoolNames.is_empty() {
+            PoolNames.push(DEFAULT_POOL_NAME.to_string());
+        }
+
+        let mut Pools = HashMap::new();
+        let mut HostRules = Vec::new();
+
+        for PoolName in PoolNames.iter() {
+            let Prefix = format!("UPSTREAM_{}", PoolName.to_uppercase().replace('-', "_"));
+
+            let mut BackendList = env_list(&format!("{}_BACKENDS", Prefix));
+            if BackendList.is_empty() && PoolName == DEFAULT_POOL_NAME {
+                let DestinationPort: u16 = env_or("DESTINATION_PORT", 1337);
+                BackendList.push(format!("127.0.0.1:{}", DestinationPort));
+            }
+
+            let Backends = BackendList.iter()
+                .map(|Value| parse_backend(Value).map_err(|e| format!("{} in pool '{}'", e, PoolName)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if Backends.is_empty() {
+                return Err(format!("pool '{}' has no backends, set {}_BACKENDS", PoolName, Prefix));
+            }
+
+            let StrategyName: String = env_or(&format!("{}_STRATEGY", Prefix), String::from("round_robin"));
+            let Strategy = BalanceStrategy::parse(&StrategyName)
+                .ok_or_else(|| format!("unknown strategy '{}' in pool '{}'", StrategyName, PoolName))?;
+
+            let Policy = HealthPolicy::from_env(&Prefix);
+            let Breaker = BreakerSettings::from_env(&Prefix);
+            let Pool = Arc::new(UpstreamPool::new(PoolName.clone(), Strategy, Policy, Breaker, Backends));
+
+            for HostPattern in env_list(&format!("{}_HOSTS", Prefix)) {
+                HostRules.push((HostPattern.to_lowercase(), Pool.clone()));
+            }
+
+            Pools.insert(PoolName.clone(), Pool);
+        }
+
+        let Default = Pools.get(DEFAULT_POOL_NAME)
+            .or_else(|| Pools.get(&PoolNames[0]))
+            .cloned()
+            .ok_or_else(|| String::from("no upstream pools configured"))?;
+
+        let mut PoolList: Vec<Arc<UpstreamPool>> = Vec::new();
+        for PoolName in PoolNames.iter() {
+            if let Some(Pool) = Pools.get(PoolName) {
+                if !PoolList.iter().any(|Known| Arc::ptr_eq(Known, Pool)) {
+                    PoolList.push(Pool.clone());
+                }
+            }
+        }
+
+        Ok(Self { Pools: PoolList, HostRules, Default })
+    }
+
+    pub fn pools(&self) -> &[Arc<UpstreamPool>] {
+        &self.Pools
+    }
+
+    pub fn pool_named(&self, Name: &str) -> Option<Arc<UpstreamPool>> {
+        self.Pools.iter().find(|Pool| Pool.Name.eq_ignore_ascii_case(Name)).cloned()
+    }
+
+    pub fn pool_for_host(&self, Host: Option<&str>) -> Arc<UpstreamPool> {
+        if let Some(Host) = Host {
+            let Host = strip_port(Host).to_lowercase();
+
+            for (Pattern, Pool) in self.HostRules.iter() {
+                if host_matches(Pattern, &Host) {
+                    return Pool.clone();
+                }
+            }
+        }
+
+        self.Default.clone()
+    }
+}
+
+pub fn strip_port(Host: &str) -> &str {
+    if Host.starts_with('[') {
+        return Host.split_once(']').map(|(Address, _)| &Address[1..]).unwrap_or(Host);
+    }
+
+    match Host.rsplit_once(':') {
+        Some((Name, Port)) if Port.chars().all(|c| c.is_ascii_digit()) => Name,
+        _ => Host,
+    }
+}
+
+pub fn host_matches(Pattern: &str, Host: &str) -> bool {
+    if Pattern == "*" {
+        return true;
+    }
+
+    match Pattern.strip_prefix("*.") {
+        Some(Suffix) => Host.len() > Suffix.len() && Host.ends_with(Suffix) && Host[..Host.len() - Suffix.len()].ends_with('.'),
+        None => Pattern == Host,
+    }
+}
+
+pub struct TrackedBody {
+    Inner: ResponseBody,
+    _Guard: BackendGuard,
+}
+
+impl TrackedBody {
+    pub fn new(Inner: ResponseBody, Guard: BackendGuard) -> Self {
+        Self { Inner, _Guard: Guard }
+    }
+}
+
+impl Body for TrackedBody {
+    type Data = Bytes;
+    type Error = BodyError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, Cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BodyError>>> {
+        Pin::new(&mut self.Inner).poll_frame(Cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.Inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.Inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn pool(Strategy: BalanceStrategy, Backends: &[(&str, u32)]) -> UpstreamPool {
+        let Policy = HealthPolicy { MaxFails: 1, FailTimeout: Duration::from_secs(600), HealthyThreshold: 1, Probe: None };
+        let Backends = Backends.iter().map(|(Address, Weight)| (Address.to_string(), *Weight)).collect();
+        UpstreamPool::new(String::from("test"), Strategy, Policy, BreakerSettings::from_env("UPSTREAM_TEST"), Backends)
+    }
+
+    fn client(Index: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + Index))
+    }
+
+    fn picks(Pool: &UpstreamPool, Count: u32) -> Vec<String> {
+        (0..Count).map(|Index| Pool.select(client(Index)).unwrap().Backend.Address.clone()).collect()
+    }
+
+    fn share(Picks: &[String], Address: &str) -> usize {
+        Picks.iter().filter(|Picked| *Picked == Address).count()
+    }
+
+    #[test]
+    fn weights_outside_the_supported_range_are_rejected() {
+        assert_eq!(parse_backend("10.0.0.1:80").unwrap(), (String::from("10.0.0.1:80"), 1));
+        assert_eq!(parse_backend("10.0.0.1:80=1000").unwrap().1, 1000);
+        assert!(parse_backend("10.0.0.1:80=0").is_err());
+        assert!(parse_backend("10.0.0.1:80=1001").is_err());
+        assert!(parse_backend("10.0.0.1:80=4294967295").is_err());
+        assert!(parse_backend("=3").is_err());
+    }
+
+    #[test]
+    fn round_robin_rotates_past_ejected_backends() {
+        let Pool = pool(BalanceStrategy::RoundRobin, &[("a:1", 1), ("b:1", 1), ("c:1", 1)]);
+        let Picked = picks(&Pool, 6);
+        assert_eq!(Picked[..3], Picked[3..]);
+        assert!(["a:1", "b:1", "c:1"].iter().all(|Address| share(&Picked, Address) == 2));
+
+        // An ejected backend's turn goes to the next one in line
+        Pool.backends()[1].report_failure();
+        let Picked = picks(&Pool, 6);
+        assert_eq!(share(&Picked, "b:1"), 0);
+        assert_eq!(share(&Picked, "a:1") + share(&Picked, "c:1"), 6);
+    }
+
+    #[test]
+    fn weighted_follows_the_weights() {
+        let Pool = pool(BalanceStrategy::Weighted, &[("a:1", 3), ("b:1", 1)]);
+        let Picked = picks(&Pool, 400);
+        assert_eq!((share(&Picked, "a:1"), share(&Picked, "b:1")), (300, 100));
+    }
+
+    #[test]
+    fn least_connections_weighs_open_connections() {
+        let Pool = pool(BalanceStrategy::LeastConnections, &[("a:1", 1), ("b:1", 2)]);
+        let Held: Vec<BackendGuard> = (0..3).map(|Index| Pool.select(client(Index)).unwrap()).collect();
+        assert_eq!(Pool.backends()[0].active_connections(), 1);
+        assert_eq!(Pool.backends()[1].active_connections(), 2);
+
+        drop(Held);
+        assert!(Pool.backends().iter().all(|Backend| Backend.active_connections() == 0));
+    }
+
+    #[test]
+    fn consistent_hash_spreads_by_weight() {
+        let Pool = pool(BalanceStrategy::ConsistentHash, &[("a:1", 1), ("b:1", 3)]);
+        let Picked = picks(&Pool, 4000);
+        let Heavy = share(&Picked, "b:1") as f64 / Picked.len() as f64;
+        assert!(Heavy > 0.65 && Heavy < 0.85, "weight 3 of 4 took {:.2} of the clients", Heavy);
+
+        // The same client always lands on the same backend
+        assert_eq!(Picked, picks(&Pool, 4000));
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_the_clients_of_a_removed_backend() {
+        let Full = picks(&pool(BalanceStrategy::ConsistentHash, &[("a:1", 1), ("b:1", 1), ("c:1", 1)]), 3000);
+        let Reduced = picks(&pool(BalanceStrategy::ConsistentHash, &[("a:1", 1), ("b:1", 1)]), 3000);
+
+        for (Before, After) in Full.iter().zip(Reduced.iter()) {
+            if Before != "c:1" {
+                assert_eq!(Before, After);
+            }
+        }
+
+        // Ejecting instead of removing moves the same clients the same way
+        let Ejected = pool(BalanceStrategy::ConsistentHash, &[("a:1", 1), ("b:1", 1), ("c:1", 1)]);
+        Ejected.backends()[2].report_failure();
+        assert_eq!(picks(&Ejected, 3000), Reduced);
+    }
+
+    fn backend(MaxFails: u32, FailTimeout: Duration, Probe: Option<ActiveProbe>) -> Backend {
+        let Policy = HealthPolicy { MaxFails, FailTimeout, HealthyThreshold: 2, Probe };
+        Backend::new(String::from("a:1"), 1, Arc::new(Policy))
+    }
+
+    #[test]
+    fn passive_failures_eject_until_the_fail_timeout_passed() {
+        let Backend = backend(2, Duration::from_millis(50), None);
+        Backend.report_failure();
+        Backend.report_success();
+        Backend.report_failure();
+        assert!(Backend.is_available());
+
+        Backend.report_failure();
+        assert!(!Backend.is_healthy() && !Backend.is_available());
+
+        // Readmitted on trial, one failure away from the next ejection
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(Backend.is_available() && Backend.is_healthy());
+        Backend.report_failure();
+        assert!(!Backend.is_available());
+    }
+
+    #[test]
+    fn probed_backends_come_back_after_enough_passing_probes() {
+        let Probe = ActiveProbe { Path: String::from("/health"), ExpectedStatus: 200, Interval: Duration::from_secs(1), Timeout: Duration::from_secs(1) };
+        let Backend = backend(1, Duration::ZERO, Some(Probe));
+        Backend.report_probe(false);
+        // The probe decides, the fail timeout does not readmit it
+        assert!(!Backend.is_available());
+
+        Backend.report_probe(true);
+        Backend.report_probe(false);
+        Backend.report_probe(true);
+        assert!(!Backend.is_available());
+        Backend.report_probe(true);
+        assert!(Backend.is_available() && Backend.consecutive_failures() == 0);
+    }
+}
@@ -0,0 +1,112 @@
+#![allow(non_snake_case)]
+
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+use crate::config::env_or;
+
+static _SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+static _ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static _DRAINED: Notify = Notify::const_new();
+static _REQUESTED: Notify = Notify::const_new();
+
+pub struct ShutdownSettings {
+    pub Grace: Duration,
+}
+
+impl ShutdownSettings {
+    // SHUTDOWN_GRACE_SECS bounds how long in-flight requests and tunnels get to finish
+    pub fn from_env() -> Self {
+        Self {
+            Grace: Duration::from_secs(env_or("SHUTDOWN_GRACE_SECS", 30)),
+        }
+    }
+}
+
+// Stands in for SIGINT where the terminal does not send one, e.g. Ctrl+C while the dashboard has it in raw mode.
+// Each request is counted, so a second one cuts the drain short just like a second signal would.
+pub fn request_shutdown() {
+    _REQUESTED.notify_one();
+}
+
+// Resolves on SIGTERM, SIGINT or request_shutdown
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut Terminate = match signal(SignalKind::terminate()) {
+            Ok(Terminate) => Terminate,
+            Err(_) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = _REQUESTED.notified() => {}
+                }
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = Terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+            _ = _REQUESTED.notified() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = _REQUESTED.notified() => {}
+        }
+    }
+}
+
+pub fn trigger() {
+    _SHUTDOWN.send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *_SHUTDOWN.borrow()
+}
+
+// Resolves once shutdown has been triggered, immediately if it already was
+pub async fn shutdown_requested() {
+    let mut Receiver = _SHUTDOWN.subscribe();
+    let _ = Receiver.wait_for(|Triggered| *Triggered).await;
+}
+
+pub fn active_connections() -> usize {
+    _ACTIVE.load(Ordering::Acquire)
+}
+
+// Resolves once every tracked connection and tunnel has been dropped
+pub async fn drained() {
+    loop {
+        let Notified = _DRAINED.notified();
+        if active_connections() == 0 {
+            return;
+        }
+        Notified.await;
+    }
+}
+
+// Held by each client connection and upgraded tunnel for as long as it is alive
+pub struct DrainGuard;
+
+impl DrainGuard {
+    pub fn new() -> Self {
+        _ACTIVE.fetch_add(1, Ordering::AcqRel);
+        Self
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if _ACTIVE.fetch_sub(1, Ordering::AcqRel) == 1 {
+            _DRAINED.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,84 @@
+;; Sample plugin for the WASM plugin ABI 1 (see src/modules/wasm_plugin.rs).
+;; Masks every "secret" in text bodies and answers 403 to requests carrying an X-Wasm-Block header.
+;;   WASM_PLUGINS=tests/plugins/mask_secret.wat
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 0) "secret")
+  (data (i32.const 16) "\nx-wasm-block:")
+  ;; Inputs are written above the needles; every call gets a fresh instance so nothing is ever freed
+  (global $heap (mut i32) (i32.const 1024))
+
+  (func (export "waf_abi_version") (result i32)
+    (i32.const 1))
+
+  ;; Ahead of the built-in detectors (480 and up)
+  (func (export "waf_priority") (result i32)
+    (i32.const 450))
+
+  (func (export "waf_alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local $end i32)
+    (local.set $ptr (global.get $heap))
+    (local.set $end (i32.add (local.get $ptr) (local.get $len)))
+    (block $fits
+      (loop $grow
+        (br_if $fits (i32.le_u (local.get $end) (i32.mul (memory.size) (i32.const 65536))))
+        ;; past the host's memory limit
+        (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1))
+          (then (unreachable)))
+        (br $grow)))
+    (global.set $heap (local.get $end))
+    (local.get $ptr))
+
+  ;; 1 when the $nlen bytes at $needle (lowercase) start at $at, ignoring ASCII case
+  (func $matches (param $at i32) (param $needle i32) (param $nlen i32) (result i32)
+    (local $i i32)
+    (local $c i32)
+    (block $differs
+      (loop $next
+        (if (i32.eq (local.get $i) (local.get $nlen))
+          (then (return (i32.const 1))))
+        (local.set $c (i32.load8_u (i32.add (local.get $at) (local.get $i))))
+        (if (i32.and (i32.ge_u (local.get $c) (i32.const 65)) (i32.le_u (local.get $c) (i32.const 90)))
+          (then (local.set $c (i32.or (local.get $c) (i32.const 32)))))
+        (br_if $differs (i32.ne (local.get $c) (i32.load8_u (i32.add (local.get $needle) (local.get $i)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 0))
+
+  ;; Offset of the first match of the needle in [$ptr, $ptr + $len), -1 when there is none
+  (func $find (param $ptr i32) (param $len i32) (param $from i32) (param $needle i32) (param $nlen i32) (result i32)
+    (local $i i32)
+    (local.set $i (local.get $from))
+    (block $missing
+      (loop $scan
+        (br_if $missing (i32.gt_s (i32.add (local.get $i) (local.get $nlen)) (local.get $len)))
+        (if (call $matches (i32.add (local.get $ptr) (local.get $i)) (local.get $needle) (local.get $nlen))
+          (then (return (local.get $i))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $scan)))
+    (i32.const -1))
+
+  (func (export "waf_on_request") (param $ptr i32) (param $len i32) (result i32)
+    (if (result i32) (i32.ge_s (call $find (local.get $ptr) (local.get $len) (i32.const 0) (i32.const 16) (i32.const 14)) (i32.const 0))
+      (then (i32.const 403))
+      (else (i32.const 0))))
+
+  ;; Masks in place, so the replacement is the input buffer itself
+  (func (export "waf_on_body") (param $ptr i32) (param $len i32) (result i64)
+    (local $at i32)
+    (local $masked i32)
+    (block $done
+      (loop $next
+        (local.set $at (call $find (local.get $ptr) (local.get $len) (local.get $at) (i32.const 0) (i32.const 6)))
+        (br_if $done (i32.lt_s (local.get $at) (i32.const 0)))
+        (memory.fill (i32.add (local.get $ptr) (local.get $at)) (i32.const 42) (i32.const 6))
+        (local.set $at (i32.add (local.get $at) (i32.const 6)))
+        (local.set $masked (i32.const 1))
+        (br $next)))
+    (if (result i64) (local.get $masked)
+      (then (i64.or
+        (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+        (i64.extend_i32_u (local.get $len))))
+      (else (i64.const -1))))
+)